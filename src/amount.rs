@@ -0,0 +1,200 @@
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use utoipa::ToSchema;
+
+/// A non-negative token amount, stored in base units. With `decimals = 2`,
+/// `Amount::from_base_units(1250)` is 12.50 tokens.
+#[derive(
+    Debug,
+    Clone,
+    Copy,
+    Default,
+    PartialEq,
+    Eq,
+    PartialOrd,
+    Ord,
+    Hash,
+    Serialize,
+    Deserialize,
+    ToSchema,
+)]
+#[serde(transparent)]
+pub struct Amount(u64);
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AmountError {
+    Invalid(String),
+    TooManyDecimals(u8),
+    WrongSymbol(String),
+    Overflow,
+}
+
+impl fmt::Display for AmountError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AmountError::Invalid(s) => write!(f, "'{}' is not a valid amount", s),
+            AmountError::TooManyDecimals(d) => write!(f, "at most {} decimal places allowed", d),
+            AmountError::WrongSymbol(s) => write!(f, "unexpected token symbol '{}'", s),
+            AmountError::Overflow => write!(f, "amount is too large"),
+        }
+    }
+}
+
+impl std::error::Error for AmountError {}
+
+impl Amount {
+    pub const ZERO: Amount = Amount(0);
+    pub const MAX: Amount = Amount(u64::MAX);
+
+    pub fn from_base_units(units: u64) -> Amount {
+        Amount(units)
+    }
+
+    pub fn base_units(self) -> u64 {
+        self.0
+    }
+
+    pub fn is_zero(self) -> bool {
+        self.0 == 0
+    }
+
+    pub fn checked_add(self, other: Amount) -> Option<Amount> {
+        self.0.checked_add(other.0).map(Amount)
+    }
+
+    pub fn checked_sub(self, other: Amount) -> Option<Amount> {
+        self.0.checked_sub(other.0).map(Amount)
+    }
+
+    /// Adds up `amounts`, returning `None` on overflow.
+    pub fn checked_sum<I: IntoIterator<Item = Amount>>(amounts: I) -> Option<Amount> {
+        amounts
+            .into_iter()
+            .try_fold(Amount::ZERO, |total, amount| total.checked_add(amount))
+    }
+
+    /// Formats the amount as a decimal number, e.g. `12.5` for 1250 base
+    /// units with two decimals. Trailing zeros are dropped.
+    pub fn format(self, decimals: u8) -> String {
+        let scale = 10u128.pow(decimals as u32);
+        let units = self.0 as u128;
+        let whole = units / scale;
+        let frac = units % scale;
+        if frac == 0 {
+            return whole.to_string();
+        }
+        let frac = format!("{:0width$}", frac, width = decimals as usize);
+        format!("{}.{}", whole, frac.trim_end_matches('0'))
+    }
+
+    /// Parses a decimal number such as `12.5` into base units.
+    pub fn parse(s: &str, decimals: u8) -> Result<Amount, AmountError> {
+        let invalid = || AmountError::Invalid(s.to_string());
+        let (whole, frac) = s.split_once('.').unwrap_or((s, ""));
+        if whole.is_empty() && frac.is_empty() {
+            return Err(invalid());
+        }
+        let all_digits = |part: &str| part.chars().all(|c| c.is_ascii_digit());
+        if !all_digits(whole) || !all_digits(frac) {
+            return Err(invalid());
+        }
+        if frac.len() > decimals as usize {
+            return Err(AmountError::TooManyDecimals(decimals));
+        }
+
+        let scale = 10u64
+            .checked_pow(decimals as u32)
+            .ok_or(AmountError::Overflow)?;
+        let whole: u64 = if whole.is_empty() {
+            0
+        } else {
+            whole.parse().map_err(|_| AmountError::Overflow)?
+        };
+        let frac: u64 = if frac.is_empty() {
+            0
+        } else {
+            let padded = format!("{:0<width$}", frac, width = decimals as usize);
+            padded.parse().map_err(|_| AmountError::Overflow)?
+        };
+        whole
+            .checked_mul(scale)
+            .and_then(|units| units.checked_add(frac))
+            .map(Amount)
+            .ok_or(AmountError::Overflow)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_decimal_amounts() {
+        let parse = |s| Amount::parse(s, 2).map(Amount::base_units);
+        assert_eq!(parse("12"), Ok(1200));
+        assert_eq!(parse("12.5"), Ok(1250));
+        assert_eq!(parse("12.05"), Ok(1205));
+        assert_eq!(parse(".5"), Ok(50));
+        assert_eq!(parse("7."), Ok(700));
+        assert_eq!(parse("0"), Ok(0));
+        assert_eq!(Amount::parse("42", 0), Ok(Amount(42)));
+    }
+
+    #[test]
+    fn rejects_malformed_amounts() {
+        for s in ["", ".", "-1", "1.2.3", "1,5", "abc", " 1", "+1", "1e3"] {
+            assert_eq!(
+                Amount::parse(s, 2),
+                Err(AmountError::Invalid(s.to_string())),
+                "{:?}",
+                s
+            );
+        }
+        assert_eq!(
+            Amount::parse("1.234", 2),
+            Err(AmountError::TooManyDecimals(2))
+        );
+        assert_eq!(
+            Amount::parse("1.5", 0),
+            Err(AmountError::TooManyDecimals(0))
+        );
+    }
+
+    #[test]
+    fn rejects_amounts_that_overflow() {
+        assert_eq!(Amount::parse("18446744073709551615", 0), Ok(Amount::MAX));
+        assert_eq!(
+            Amount::parse("18446744073709551616", 0),
+            Err(AmountError::Overflow)
+        );
+        assert_eq!(
+            Amount::parse("184467440737095517", 2),
+            Err(AmountError::Overflow)
+        );
+        assert_eq!(Amount::parse("1", 20), Err(AmountError::Overflow));
+    }
+
+    #[test]
+    fn formats_without_trailing_zeros() {
+        assert_eq!(Amount(1250).format(2), "12.5");
+        assert_eq!(Amount(1205).format(2), "12.05");
+        assert_eq!(Amount(1200).format(2), "12");
+        assert_eq!(Amount(5).format(3), "0.005");
+        assert_eq!(Amount(42).format(0), "42");
+        assert_eq!(Amount::MAX.format(19), "1.8446744073709551615");
+        for units in [0, 1, 10, 999, 123456789] {
+            let amount = Amount(units);
+            assert_eq!(Amount::parse(&amount.format(4), 4), Ok(amount));
+        }
+    }
+
+    #[test]
+    fn sums_without_overflowing() {
+        assert_eq!(
+            Amount::checked_sum([Amount(1), Amount(2), Amount(3)]),
+            Some(Amount(6))
+        );
+        assert_eq!(Amount::checked_sum([Amount::MAX, Amount(1)]), None);
+        assert_eq!(Amount(1).checked_sub(Amount(2)), None);
+    }
+}
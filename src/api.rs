@@ -0,0 +1,20 @@
+use crate::node::Node;
+use crate::{rest, rpc, ws};
+use log::info;
+use std::error::Error;
+use std::net::SocketAddr;
+
+/// Serves JSON-RPC at `/rpc`, subscriptions at `/ws` and the REST API
+/// beside them on one port.
+pub async fn serve(addr: SocketAddr, node: Node) -> Result<(), Box<dyn Error>> {
+    let listener = tokio::net::TcpListener::bind(addr).await?;
+    info!(
+        "API listening on http://{} (JSON-RPC at /rpc, WebSocket at /ws)",
+        addr
+    );
+    let app = rpc::router(node.clone())
+        .merge(ws::router(node.clone()))
+        .merge(rest::router(node));
+    axum::serve(listener, app).await?;
+    Ok(())
+}
@@ -1,123 +1,1183 @@
+use crate::amount::{Amount, AmountError};
+use crate::events::{self, ChainEvent, EventSender};
+use crate::htlc::{self, Htlc, HtlcAction};
+use crate::index::{ChainIndex, LedgerEntry, Page, TxLocation};
+use crate::mining::{self, HashMeter};
+use crate::multisig::{self, MultisigAccount, MultisigError, TxSignature};
+use crate::p2p;
+use crate::smt::AccountProof;
+use crate::spec::{ChainSpec, LedgerModel};
+use crate::state::{BlockUndo, State, TxReceipt};
+use crate::token::{Token, TokenInfo};
+use crate::utxo::{Lock, OutPoint, TxOutput};
+use crate::vm::{self, Contract, ContractAction, ContractReceipt, Op};
 use chrono::prelude::*;
 use log::info;
 use serde::{Deserialize, Serialize};
 use sha2::{Digest, Sha256};
-use crate::p2p;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
 use tokio::sync::mpsc;
+use utoipa::ToSchema;
+
+/// Highest difficulty a SHA-256 hex hash can meet.
+pub const MAX_DIFFICULTY: u32 = 64;
 
-const REWARD: i64 = 420;
 const GENESIS_HASH: &str = "0000000000000000000000000000000000000000000000000000000000000000";
+/// How far ahead of our clock a block's timestamp may be, in milliseconds.
+const MAX_FUTURE_DRIFT: i64 = 2 * 60 * 60 * 1000;
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+pub enum TxKind {
+    /// A transfer of existing funds from `sender` to `receiver`.
+    Transfer,
+    /// Mints the block reward to `receiver`. Must be the first and only one in a block.
+    Coinbase,
+    /// Mints an initial allocation to `receiver`. Only valid in the genesis block.
+    Allocation,
+    /// Locks, claims or refunds a hash time-locked contract; see `htlc`.
+    Htlc,
+    /// Deploys or calls a contract; see `vm`.
+    Contract,
+    /// Issues a new token with the transaction's `token` as symbol and its
+    /// `amount` as supply.
+    IssueToken,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
 pub struct Transaction {
+    pub kind: TxKind,
     pub sender: String,
     pub receiver: String,
-    pub amount: i64,
+    pub amount: Amount,
+    /// Block height for a coinbase and position for an allocation, so that
+    /// otherwise identical mints get distinct ids. For everything a sender
+    /// authorizes in account mode, and for token transfers, the count of
+    /// such transactions the sender made before.
+    #[serde(default)]
+    pub nonce: u64,
+    /// Outputs spent by a transfer in UTXO mode. Empty in account mode.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub inputs: Vec<OutPoint>,
+    /// Outputs created by a transfer in UTXO mode. Empty in account mode.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub outputs: Vec<TxOutput>,
+    /// Keys and threshold of the sender, when it is a multisig account.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub multisig: Option<MultisigAccount>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub signatures: Vec<TxSignature>,
+    /// Not valid in blocks below this height.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub lock_height: Option<u64>,
+    /// Not valid in blocks with an earlier timestamp (milliseconds).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub lock_time: Option<i64>,
+    /// What an `Htlc` transaction does.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub htlc: Option<HtlcAction>,
+    /// What a `Contract` transaction does.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub contract: Option<ContractAction>,
+    /// Symbol of the token `amount` is in. `None` for the native token.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub token: Option<String>,
+    /// Name and decimals of the token an `IssueToken` transaction issues.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub issue: Option<TokenInfo>,
+}
+
+impl Transaction {
+    pub fn transfer(sender: String, receiver: String, amount: Amount) -> Transaction {
+        Transaction {
+            kind: TxKind::Transfer,
+            sender,
+            receiver,
+            amount,
+            nonce: 0,
+            inputs: Vec::new(),
+            outputs: Vec::new(),
+            multisig: None,
+            signatures: Vec::new(),
+            lock_height: None,
+            lock_time: None,
+            htlc: None,
+            contract: None,
+            token: None,
+            issue: None,
+        }
+    }
+
+    /// A UTXO-mode transfer spending `inputs` owned by `sender`.
+    pub fn utxo_transfer(
+        sender: String,
+        receiver: String,
+        amount: Amount,
+        inputs: Vec<OutPoint>,
+        outputs: Vec<TxOutput>,
+    ) -> Transaction {
+        Transaction {
+            inputs,
+            outputs,
+            ..Transaction::transfer(sender, receiver, amount)
+        }
+    }
+
+    /// Pays an HTLC out to `receiver`: its receiver on a claim, its sender
+    /// on a refund. The funds come from the contract, so nothing is signed.
+    pub fn htlc_payout(receiver: String, amount: Amount, action: HtlcAction) -> Transaction {
+        Transaction {
+            kind: TxKind::Htlc,
+            htlc: Some(action),
+            ..Transaction::transfer(String::new(), receiver, amount)
+        }
+    }
+
+    pub fn coinbase(receiver: String, amount: Amount, height: u64) -> Transaction {
+        Transaction {
+            kind: TxKind::Coinbase,
+            nonce: height,
+            ..Transaction::transfer(String::new(), receiver, amount)
+        }
+    }
+
+    pub fn allocation(receiver: String, amount: Amount, index: u64) -> Transaction {
+        Transaction {
+            kind: TxKind::Allocation,
+            nonce: index,
+            ..Transaction::transfer(String::new(), receiver, amount)
+        }
+    }
+
+    /// Hash identifying the transaction.
+    pub fn id(&self) -> String {
+        Chain::hash(self).expect("Failed to hash transaction")
+    }
+
+    /// Hash of everything but the signatures; this is what signers sign.
+    pub fn signing_hash(&self) -> String {
+        let unsigned = Transaction {
+            signatures: Vec::new(),
+            ..self.clone()
+        };
+        unsigned.id()
+    }
+
+    /// Adds a signature made with the hex-encoded `secret_key`.
+    pub fn sign(&mut self, secret_key: &str) -> Result<(), MultisigError> {
+        let signature = multisig::sign(secret_key, self.signing_hash().as_bytes())?;
+        self.signatures.push(signature);
+        Ok(())
+    }
+
+    /// Whether the transaction may go into a block at `height` with the
+    /// given `timestamp`.
+    pub fn is_unlocked(&self, height: u64, timestamp: i64) -> bool {
+        self.lock_height.is_none_or(|lock| height >= lock)
+            && self.lock_time.is_none_or(|lock| timestamp >= lock)
+    }
+
+    /// Coinbase and allocation transactions create new supply instead of moving it.
+    pub fn is_mint(&self) -> bool {
+        matches!(self.kind, TxKind::Coinbase | TxKind::Allocation)
+    }
+
+    /// Whether `amount` comes out of the sender's funds: transfers, contract
+    /// transactions and HTLC locks. Everything else pays out of new supply
+    /// or an HTLC.
+    pub fn debits_sender(&self) -> bool {
+        match self.kind {
+            TxKind::Transfer | TxKind::Contract => true,
+            TxKind::Htlc => matches!(self.htlc, Some(HtlcAction::Lock { .. })),
+            TxKind::Coinbase | TxKind::Allocation | TxKind::IssueToken => false,
+        }
+    }
+
+    /// Most the sender can burn in gas fees at `vm::GAS_PRICE`: the gas
+    /// of a deployment, or a call's gas limit. Nothing else pays a fee.
+    pub fn max_fee(&self) -> Amount {
+        let gas = match &self.contract {
+            Some(ContractAction::Deploy { code }) => vm::deploy_gas(code),
+            Some(ContractAction::Call { gas_limit, .. }) => *gas_limit,
+            None => 0,
+        };
+        Amount::from_base_units(gas.saturating_mul(vm::GAS_PRICE))
+    }
+
+    /// Whether the sender has to authorize the transaction: everything
+    /// that spends its funds, and token issues.
+    pub fn needs_authorization(&self) -> bool {
+        self.debits_sender() || self.kind == TxKind::IssueToken
+    }
+
+    /// Whether `amount` ends up with the receiver. HTLC locks hold it back
+    /// until the contract is claimed or refunded, and deployments have no
+    /// receiver.
+    pub fn credits_receiver(&self) -> bool {
+        !matches!(self.htlc, Some(HtlcAction::Lock { .. }))
+            && !matches!(self.contract, Some(ContractAction::Deploy { .. }))
+    }
+}
+
+/// Where a transaction is. A transaction in the tip block has one
+/// confirmation.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+pub enum TxStatus {
+    Unknown,
+    Pending,
+    Confirmed {
+        block_height: u64,
+        index: u32,
+        confirmations: u64,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BlockError {
+    GenesisMismatch,
+    InvalidPreviousHash,
+    InvalidTimestamp,
+    InvalidDifficulty { minimum: u32, found: u32 },
+    InsufficientWork,
+    CountMismatch,
+    MerkleMismatch,
+    TransactionLocked,
+    MissingCoinbase,
+    MultipleCoinbase,
+    UnexpectedAllocation,
+    InvalidReward { expected: Amount, found: Amount },
+    CoinbaseHeightMismatch,
+    StateRootMismatch,
+    WrongLedgerModel,
+    UnknownInput(OutPoint),
+    DoubleSpend(OutPoint),
+    InputLocked(OutPoint),
+    ValueMismatch,
+    InvalidHtlc,
+    UnknownHtlc(String),
+    InvalidPreimage,
+    HtlcExpired,
+    HtlcNotExpired,
+    InvalidContract,
+    UnknownContract(String),
+    ContractSender,
+    InvalidToken,
+    UnknownToken(String),
+    MissingSignatures,
+    InvalidSignatures,
+    InvalidNonce { expected: u64, found: u64 },
+    UnknownAccount { account: String },
+    InsufficientFunds { account: String },
+    Overflow,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+impl fmt::Display for BlockError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BlockError::GenesisMismatch => write!(f, "genesis block does not match the chain spec"),
+            BlockError::InvalidPreviousHash => write!(f, "previous hash does not match parent"),
+            BlockError::InvalidTimestamp => {
+                write!(f, "timestamp is before its parent or too far in the future")
+            }
+            BlockError::InvalidDifficulty { minimum, found } => {
+                write!(
+                    f,
+                    "difficulty {} is not from {} to {}",
+                    found, minimum, MAX_DIFFICULTY
+                )
+            }
+            BlockError::InsufficientWork => write!(f, "hash does not meet the header difficulty"),
+            BlockError::CountMismatch => {
+                write!(f, "transaction count does not match the transactions")
+            }
+            BlockError::MerkleMismatch => {
+                write!(f, "merkle root does not match the transactions")
+            }
+            BlockError::TransactionLocked => {
+                write!(f, "transaction is locked until a later height or time")
+            }
+            BlockError::MissingCoinbase => write!(f, "first transaction is not a coinbase"),
+            BlockError::MultipleCoinbase => write!(f, "more than one coinbase transaction"),
+            BlockError::UnexpectedAllocation => {
+                write!(f, "allocation transaction outside the genesis block")
+            }
+            BlockError::InvalidReward { expected, found } => {
+                write!(
+                    f,
+                    "coinbase pays {} but the reward is {} base units",
+                    found.base_units(),
+                    expected.base_units()
+                )
+            }
+            BlockError::CoinbaseHeightMismatch => {
+                write!(f, "coinbase nonce does not match the block height")
+            }
+            BlockError::WrongLedgerModel => {
+                write!(f, "transaction does not match the chain's ledger model")
+            }
+            BlockError::UnknownInput(outpoint) => write!(f, "input {} does not exist", outpoint),
+            BlockError::DoubleSpend(outpoint) => write!(f, "input {} is already spent", outpoint),
+            BlockError::InputLocked(outpoint) => {
+                write!(f, "input {} is not spendable by the sender", outpoint)
+            }
+            BlockError::ValueMismatch => write!(f, "inputs and outputs do not balance"),
+            BlockError::InvalidHtlc => write!(f, "malformed HTLC transaction"),
+            BlockError::UnknownHtlc(id) => write!(f, "no open HTLC with id {}", id),
+            BlockError::InvalidPreimage => write!(f, "preimage does not match the hash lock"),
+            BlockError::HtlcExpired => write!(f, "HTLC has timed out and can only be refunded"),
+            BlockError::HtlcNotExpired => write!(f, "HTLC has not timed out yet"),
+            BlockError::InvalidContract => write!(f, "malformed contract transaction"),
+            BlockError::UnknownContract(address) => write!(f, "no contract at {}", address),
+            BlockError::ContractSender => {
+                write!(f, "contracts cannot send transactions")
+            }
+            BlockError::InvalidToken => write!(f, "malformed token transaction"),
+            BlockError::UnknownToken(symbol) => write!(f, "no token with symbol {}", symbol),
+            BlockError::MissingSignatures => {
+                write!(f, "multisig account spends without its keys and signatures")
+            }
+            BlockError::InvalidSignatures => {
+                write!(f, "signatures do not meet the multisig threshold")
+            }
+            BlockError::InvalidNonce { expected, found } => {
+                write!(
+                    f,
+                    "nonce {} does not match the expected {}",
+                    found, expected
+                )
+            }
+            BlockError::StateRootMismatch => {
+                write!(f, "state root does not match the resulting balances")
+            }
+            BlockError::UnknownAccount { account } => write!(f, "unknown account {}", account),
+            BlockError::InsufficientFunds { account } => {
+                write!(f, "insufficient funds in account {}", account)
+            }
+            BlockError::Overflow => write!(f, "balance overflow"),
+        }
+    }
+}
+
+impl std::error::Error for BlockError {}
+
+/// Cumulative proof of work, kept exactly as big-endian base-16 digits
+/// since `16^MAX_DIFFICULTY` does not fit in any integer type. Digits are
+/// compared most significant first, so the derived ordering is numeric.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Work([u8; Work::DIGITS]);
+
+impl Work {
+    /// Room for `u64::MAX` blocks of the highest difficulty.
+    const DIGITS: usize = MAX_DIFFICULTY as usize + 17;
+
+    /// Adds the work of one block of `difficulty`, i.e. `16^difficulty`.
+    pub fn add(&mut self, difficulty: u32) {
+        let mut digit = Work::DIGITS - 1 - difficulty.min(MAX_DIFFICULTY) as usize;
+        loop {
+            if self.0[digit] < 15 {
+                self.0[digit] += 1;
+                return;
+            }
+            self.0[digit] = 0;
+            if digit == 0 {
+                return;
+            }
+            digit -= 1;
+        }
+    }
+}
+
+impl Default for Work {
+    fn default() -> Work {
+        Work([0; Work::DIGITS])
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
 pub struct BlockHeader {
     timestamp: i64,
     nonce: u32,
     previous_hash: String,
     merkle: String,
+    /// Root of the account state after applying this block.
+    state_root: String,
     difficulty: u32,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+impl BlockHeader {
+    pub fn timestamp(&self) -> i64 {
+        self.timestamp
+    }
+
+    pub fn nonce(&self) -> u32 {
+        self.nonce
+    }
+
+    pub fn previous_hash(&self) -> &str {
+        &self.previous_hash
+    }
+
+    pub fn merkle(&self) -> &str {
+        &self.merkle
+    }
+
+    pub fn state_root(&self) -> &str {
+        &self.state_root
+    }
+
+    pub fn difficulty(&self) -> u32 {
+        self.difficulty
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
 pub struct Block {
     header: BlockHeader,
     count: u32,
     transactions: Vec<Transaction>,
 }
 
+impl Block {
+    pub fn header(&self) -> &BlockHeader {
+        &self.header
+    }
+
+    pub fn transactions(&self) -> &[Transaction] {
+        &self.transactions
+    }
+
+    /// Builds the genesis block described by `spec`. Only the spec goes into
+    /// it, so the result is the same on every node.
+    pub fn genesis(spec: &ChainSpec) -> Block {
+        let transactions: Vec<Transaction> = spec
+            .allocations
+            .iter()
+            .enumerate()
+            .map(|(i, a)| Transaction::allocation(a.address.clone(), a.amount, i as u64))
+            .collect();
+        let mut state = State::new(spec.ledger);
+        state
+            .apply_block(&transactions, 0)
+            .expect("Genesis allocations overflow");
+        let mut header = BlockHeader {
+            timestamp: spec.genesis_timestamp,
+            nonce: 0,
+            previous_hash: GENESIS_HASH.to_string(),
+            merkle: Chain::get_merkle(transactions.clone())
+                .expect("Failed to calculate Merkle root"),
+            state_root: state.state_root(),
+            difficulty: spec.difficulty,
+        };
+        Chain::proof_of_work(&mut header);
+        Block {
+            header,
+            count: transactions.len() as u32,
+            transactions,
+        }
+    }
+}
+
+/// A block still needing its proof of work, from `Chain::block_template`.
+#[derive(Debug, Clone)]
+pub struct BlockTemplate {
+    height: u64,
+    block: Block,
+}
+
+impl BlockTemplate {
+    pub fn height(&self) -> u64 {
+        self.height
+    }
+
+    pub fn block(&self) -> &Block {
+        &self.block
+    }
+
+    /// Searches for a nonce; see `Chain::proof_of_work_metered`.
+    pub fn search(&mut self, meter: &HashMeter, cancelled: impl FnMut() -> bool) -> bool {
+        Chain::proof_of_work_metered(&mut self.block.header, meter, cancelled)
+    }
+}
+
 pub struct Chain {
     chain: Vec<Block>,
     current_transaction: Vec<Transaction>,
     difficulty: u32,
     miner_address: String,
-    reward: i64,
-    token_name: String,
-    token_symbol: String,
-    balances: HashMap<String, i64>,
+    spec: ChainSpec,
+    state: State,
+    /// Undo data for each block in `chain`, at the same index.
+    undo: Vec<BlockUndo>,
+    /// Multisig accounts created on this node, by address.
+    multisig_accounts: HashMap<String, MultisigAccount>,
+    index: ChainIndex,
     p2p_tx: mpsc::Sender<p2p::P2pMessage>,
+    events: EventSender,
+    /// What our coinbase claims, if less than the scheduled reward.
+    reward: Option<Amount>,
+    hash_meter: HashMeter,
 }
 
 impl Chain {
+    /// Creates a chain starting from the genesis block described by `spec`.
     pub fn new(
+        spec: ChainSpec,
         miner_address: String,
-        difficulty: u32,
-        token_name: String,
-        token_symbol: String,
         p2p_tx: mpsc::Sender<p2p::P2pMessage>,
     ) -> Chain {
-        let mut balances = HashMap::new();
-        balances.insert(String::from("Root"), 1_000_000_000);
+        let genesis = Block::genesis(&spec);
+        info!("Genesis block: {:?}", genesis);
+        info!("Token Name: {}", spec.token_name);
+        info!("Token Symbol: {}", spec.token_symbol);
 
         let mut chain = Chain {
             chain: Vec::new(),
             current_transaction: Vec::new(),
-            difficulty,
-            miner_address: miner_address.clone(),
-            reward: REWARD,
-            token_name,
-            token_symbol,
-            balances,
+            difficulty: spec.difficulty,
+            miner_address,
+            state: State::new(spec.ledger),
+            spec,
+            undo: Vec::new(),
+            multisig_accounts: HashMap::new(),
+            index: ChainIndex::default(),
             p2p_tx,
+            events: events::channel(),
+            reward: None,
+            hash_meter: HashMeter::default(),
         };
-
-        chain.generate_new_block();
+        let (undo, receipts) = chain
+            .state
+            .apply_block(&genesis.transactions, 0)
+            .expect("Genesis allocations overflow");
+        chain.undo.push(undo);
+        chain.chain.push(genesis);
+        chain.index_tip(&receipts);
         chain
     }
 
     pub fn create_account(&mut self, account: String) -> bool {
-        if self.balances.contains_key(&account) {
-            return false;
+        self.state.create_account(account)
+    }
+
+    pub fn get_balance(&self, account: &str) -> Option<Amount> {
+        self.state.balance(account)
+    }
+
+    /// Every account with a balance entry, sorted.
+    pub fn accounts(&self) -> Vec<String> {
+        let mut accounts: Vec<String> = self.state.balances().keys().cloned().collect();
+        accounts.sort();
+        accounts
+    }
+
+    /// Proof of `account`'s balance against the state root of the tip block.
+    pub fn prove_balance(&self, account: &str) -> AccountProof {
+        self.state.prove(account)
+    }
+
+    /// Formats `amount` with the token's decimals and symbol, e.g. `12.5 RST`.
+    pub fn format_amount(&self, amount: Amount) -> String {
+        format!(
+            "{} {}",
+            amount.format(self.spec.decimals),
+            self.spec.token_symbol
+        )
+    }
+
+    /// Formats `amount` in the issued token `symbol`, or in the native token
+    /// if there is none. `None` if no such token was issued.
+    pub fn format_amount_in(&self, symbol: Option<&str>, amount: Amount) -> Option<String> {
+        match symbol {
+            Some(symbol) => self
+                .get_token(symbol)
+                .map(|token| token.format_amount(amount)),
+            None => Some(self.format_amount(amount)),
         }
-        self.balances.insert(account, 0);
-        true
     }
 
-    pub fn get_balance(&self, account: &String) -> Option<&i64> {
-        self.balances.get(account)
+    /// Parses an amount such as `12.5` or `12.5 RST` in the token's units.
+    pub fn parse_amount(&self, s: &str) -> Result<Amount, AmountError> {
+        let s = s.trim();
+        let number = match s.split_once(char::is_whitespace) {
+            Some((number, symbol)) if symbol.trim() == self.spec.token_symbol => number,
+            Some((_, symbol)) => return Err(AmountError::WrongSymbol(symbol.trim().to_string())),
+            None => s,
+        };
+        Amount::parse(number, self.spec.decimals)
     }
 
     pub fn get_chain(&self) -> &Vec<Block> {
         &self.chain
     }
 
-    pub fn new_transaction(&mut self, sender: String, receiver: String, amount: i64) -> bool {
-        if let Some(balance) = self.balances.get(&sender) {
-            if *balance < amount {
-                info!("Transaction failed: insufficient funds.");
-                return false;
-            }
-        } else {
-            info!("Transaction failed: sender not found.");
-            return false;
+    /// Creates an M-of-N account from hex-encoded ed25519 public keys and
+    /// returns its address. Transfers from it must be built with
+    /// `build_transaction`, signed, and passed to `submit_transaction`.
+    pub fn create_multisig_account(
+        &mut self,
+        public_keys: Vec<String>,
+        threshold: u8,
+    ) -> Result<String, MultisigError> {
+        let account = MultisigAccount::new(public_keys, threshold)?;
+        let address = account.address();
+        self.state.create_account(address.clone());
+        self.multisig_accounts.insert(address.clone(), account);
+        Ok(address)
+    }
+
+    /// Queues a transfer and returns its id, which `transaction_status`
+    /// and `get_receipt` take.
+    pub fn new_transaction(
+        &mut self,
+        sender: String,
+        receiver: String,
+        amount: Amount,
+    ) -> Option<String> {
+        let tx = self.build_transaction(sender, receiver, amount)?;
+        let id = tx.id();
+        self.submit_transaction(tx).then_some(id)
+    }
+
+    pub fn transaction_status(&self, tx_id: &str) -> TxStatus {
+        if let Some(receipt) = self.state.tx_receipt(tx_id) {
+            return TxStatus::Confirmed {
+                block_height: receipt.block_height,
+                index: receipt.index,
+                confirmations: self.chain.len() as u64 - receipt.block_height,
+            };
+        }
+        if self.current_transaction.iter().any(|tx| tx.id() == tx_id) {
+            return TxStatus::Pending;
         }
+        TxStatus::Unknown
+    }
 
-        let tx = Transaction {
-            sender,
-            receiver,
-            amount,
+    /// Receipt of a confirmed transaction.
+    pub fn get_receipt(&self, tx_id: &str) -> Option<&TxReceipt> {
+        self.state.tx_receipt(tx_id)
+    }
+
+    /// A confirmed or pending transaction by id.
+    pub fn get_transaction(&self, tx_id: &str) -> Option<&Transaction> {
+        match self.state.tx_receipt(tx_id) {
+            Some(receipt) => self
+                .chain
+                .get(receipt.block_height as usize)?
+                .transactions
+                .get(receipt.index as usize),
+            None => self.current_transaction.iter().find(|tx| tx.id() == tx_id),
+        }
+    }
+
+    /// Builds an unsigned transfer with the sender's next nonce. Transfers
+    /// from multisig accounts created on this node get the account's keys
+    /// filled in too.
+    pub fn build_transaction(
+        &self,
+        sender: String,
+        receiver: String,
+        amount: Amount,
+    ) -> Option<Transaction> {
+        self.build_locked_transaction(sender, receiver, amount, None, None)
+    }
+
+    /// Builds a transfer that only becomes valid from `lock_height` and/or
+    /// `lock_time` on; see `build_transaction`.
+    fn build_locked_transaction(
+        &self,
+        sender: String,
+        receiver: String,
+        amount: Amount,
+        lock_height: Option<u64>,
+        lock_time: Option<i64>,
+    ) -> Option<Transaction> {
+        let mut tx = match self.spec.ledger {
+            LedgerModel::Account => Transaction {
+                lock_height,
+                lock_time,
+                ..Transaction::transfer(sender, receiver, amount)
+            },
+            LedgerModel::Utxo => {
+                match self.build_utxo_transfer(sender, receiver, amount, lock_height, lock_time) {
+                    Some(tx) => tx,
+                    None => {
+                        info!("Transaction failed: insufficient unspent outputs.");
+                        return None;
+                    }
+                }
+            }
         };
+        self.fill_sender(&mut tx);
+        Some(tx)
+    }
+
+    /// Fills in the next nonce of the sender and the keys of multisig
+    /// accounts created on this node.
+    fn fill_sender(&self, tx: &mut Transaction) {
+        if self.uses_nonce(tx) {
+            tx.nonce = self.next_nonce(&tx.sender);
+        }
+        if let Some(account) = self.multisig_accounts.get(&tx.sender) {
+            tx.multisig = Some(account.clone());
+        }
+    }
+
+    /// Nonce the next transaction `account` authorizes has to carry, after
+    /// its pending ones.
+    pub fn next_nonce(&self, account: &str) -> u64 {
+        let pending = self
+            .current_transaction
+            .iter()
+            .filter(|tx| tx.sender == account && tx.needs_authorization() && self.uses_nonce(tx))
+            .count() as u64;
+        self.state.nonce(account) + pending
+    }
+
+    fn uses_nonce(&self, tx: &Transaction) -> bool {
+        self.spec.ledger == LedgerModel::Account || tx.token.is_some()
+    }
+
+    /// Adds a transfer to the pending pool if it applies on top of the
+    /// transactions already there, and announces it to peers.
+    pub fn submit_transaction(&mut self, tx: Transaction) -> bool {
+        if let Err(e) = self.queue_transaction(tx.clone()) {
+            info!("Transaction failed: {}.", e);
+            return false;
+        }
         info!("New transaction created: {:?}", tx);
-        self.current_transaction.push(tx.clone());
+        self.announce_transaction(tx);
+        true
+    }
+
+    fn queue_transaction(&mut self, tx: Transaction) -> Result<(), String> {
+        if tx.is_mint() {
+            return Err(String::from("only transfers can be submitted"));
+        }
+        if tx.amount.is_zero() && tx.kind != TxKind::Contract {
+            return Err(String::from("amount must be positive"));
+        }
+        let mut overlay = self.state.overlay(self.chain.len() as u64);
+        for pending in &self.current_transaction {
+            // Pending transactions that no longer apply are dropped at mining.
+            let _ = overlay.apply(pending);
+        }
+        overlay.apply(&tx).map_err(|e| e.to_string())?;
+        drop(overlay);
+        self.current_transaction.push(tx);
+        Ok(())
+    }
+
+    fn announce_transaction(&self, tx: Transaction) {
+        let id = tx.id();
+        for address in [&tx.sender, &tx.receiver] {
+            if !address.is_empty() {
+                self.emit(ChainEvent::AddressActivity {
+                    address: address.clone(),
+                    tx_id: id.clone(),
+                    block_height: None,
+                });
+            }
+        }
+        self.emit(ChainEvent::PendingTransaction {
+            id,
+            transaction: Box::new(tx.clone()),
+        });
         let p2p_tx = self.p2p_tx.clone();
         tokio::spawn(async move {
             p2p_tx
                 .send(p2p::P2pMessage {
                     sender: "0.0.0.0:0".parse().unwrap(), // dummy address
-                    message: p2p::Message::NewTransaction(tx),
+                    message: p2p::Message::NewTransaction(Box::new(tx)),
                 })
                 .await
                 .unwrap();
         });
+    }
+
+    /// Queues a transfer that only becomes valid from `lock_height` and/or
+    /// `lock_time` (milliseconds) on.
+    pub fn new_locked_transaction(
+        &mut self,
+        sender: String,
+        receiver: String,
+        amount: Amount,
+        lock_height: Option<u64>,
+        lock_time: Option<i64>,
+    ) -> bool {
+        let Some(tx) =
+            self.build_locked_transaction(sender, receiver, amount, lock_height, lock_time)
+        else {
+            return false;
+        };
+        self.submit_transaction(tx)
+    }
+
+    /// Pays `total` to `receiver` in `installments` equal parts, the first
+    /// unlocking at `start_height` and the next ones every `interval` blocks
+    /// after it. The last installment also carries any remainder. Either the
+    /// whole schedule is queued or none of it.
+    pub fn schedule_vesting(
+        &mut self,
+        sender: String,
+        receiver: String,
+        total: Amount,
+        start_height: u64,
+        interval: u64,
+        installments: u32,
+    ) -> bool {
+        if installments == 0 || total.base_units() < installments as u64 {
+            info!(
+                "Vesting failed: total is too small for {} installments.",
+                installments
+            );
+            return false;
+        }
+        let part = total.base_units() / installments as u64;
+        let remainder = total.base_units() % installments as u64;
+        let Some(unlocks) = (0..installments as u64)
+            .map(|i| i.checked_mul(interval)?.checked_add(start_height))
+            .collect::<Option<Vec<u64>>>()
+        else {
+            info!("Vesting failed: the schedule runs past the last height.");
+            return false;
+        };
+
+        let queued = self.current_transaction.len();
+        for (i, unlock) in unlocks.into_iter().enumerate() {
+            let amount = if i == installments as usize - 1 {
+                part + remainder
+            } else {
+                part
+            };
+            let tx = self
+                .build_locked_transaction(
+                    sender.clone(),
+                    receiver.clone(),
+                    Amount::from_base_units(amount),
+                    Some(unlock),
+                    None,
+                )
+                .ok_or_else(|| String::from("could not build installment"))
+                .and_then(|tx| self.queue_transaction(tx));
+            if let Err(e) = tx {
+                info!("Vesting failed: {}.", e);
+                self.current_transaction.truncate(queued);
+                return false;
+            }
+        }
+        let scheduled: Vec<Transaction> = self.current_transaction[queued..].to_vec();
+        for tx in scheduled {
+            self.announce_transaction(tx);
+        }
         true
     }
 
+    /// Pending time- or height-locked transfers sent or received by
+    /// `account`, in unlock order.
+    pub fn scheduled_transfers(&self, account: &str) -> Vec<&Transaction> {
+        let mut scheduled: Vec<&Transaction> = self
+            .current_transaction
+            .iter()
+            .filter(|tx| tx.sender == account || tx.receiver == account)
+            .filter(|tx| tx.lock_height.is_some() || tx.lock_time.is_some())
+            .collect();
+        scheduled.sort_by_key(|tx| (tx.lock_height, tx.lock_time));
+        scheduled
+    }
+
+    /// Locks `amount` from `sender` in an HTLC that `receiver` can claim
+    /// with the preimage of `hash_lock` until `timeout_height`. Returns the
+    /// HTLC id, which is the id of the lock transaction.
+    pub fn lock_htlc(
+        &mut self,
+        sender: String,
+        receiver: String,
+        amount: Amount,
+        hash_lock: String,
+        timeout_height: u64,
+    ) -> Option<String> {
+        let mut tx = self.build_transaction(sender, receiver, amount)?;
+        if self.spec.ledger == LedgerModel::Utxo {
+            // The escrowed amount leaves with the lock, not as an output.
+            tx.outputs.remove(0);
+        }
+        tx.kind = TxKind::Htlc;
+        tx.htlc = Some(HtlcAction::Lock {
+            hash_lock,
+            timeout_height,
+        });
+        let id = tx.id();
+        self.submit_transaction(tx).then_some(id)
+    }
+
+    /// Pays HTLC `htlc_id` out to its receiver, revealing `preimage`.
+    pub fn claim_htlc(&mut self, htlc_id: String, preimage: String) -> bool {
+        let Some(htlc) = self.get_htlc(&htlc_id) else {
+            info!("Claim failed: no open HTLC {}.", htlc_id);
+            return false;
+        };
+        let action = HtlcAction::Claim { htlc_id, preimage };
+        self.submit_transaction(Transaction::htlc_payout(htlc.receiver, htlc.amount, action))
+    }
+
+    /// Pays an expired HTLC back to its sender.
+    pub fn refund_htlc(&mut self, htlc_id: String) -> bool {
+        let Some(htlc) = self.get_htlc(&htlc_id) else {
+            info!("Refund failed: no open HTLC {}.", htlc_id);
+            return false;
+        };
+        let action = HtlcAction::Refund { htlc_id };
+        self.submit_transaction(Transaction::htlc_payout(htlc.sender, htlc.amount, action))
+    }
+
+    /// The open HTLC with id `htlc_id`, as of the current tip.
+    pub fn get_htlc(&self, htlc_id: &str) -> Option<Htlc> {
+        self.state.htlc(htlc_id).cloned()
+    }
+
+    /// The secret revealed by a confirmed claim of HTLC `htlc_id`. This is
+    /// how the other side of a swap learns the preimage it needs.
+    pub fn htlc_preimage(&self, htlc_id: &str) -> Option<String> {
+        self.chain
+            .iter()
+            .flat_map(|block| &block.transactions)
+            .find_map(|tx| match &tx.htlc {
+                Some(HtlcAction::Claim {
+                    htlc_id: id,
+                    preimage,
+                }) if id == htlc_id => Some(preimage.clone()),
+                _ => None,
+            })
+            .filter(|preimage| htlc::hash_secret(preimage).is_some())
+    }
+
+    /// Deploys `code` from `sender`. Returns the new contract's address.
+    /// Contracts are only supported by the account ledger model.
+    pub fn deploy_contract(&mut self, sender: String, code: Vec<Op>) -> Option<String> {
+        let mut tx = self.build_transaction(sender, String::new(), Amount::ZERO)?;
+        tx.kind = TxKind::Contract;
+        tx.contract = Some(ContractAction::Deploy { code });
+        let address = vm::contract_address(&tx.id());
+        self.submit_transaction(tx).then_some(address)
+    }
+
+    /// Calls the contract at `contract`, sending it `value`. Returns the id
+    /// of the call transaction, under which its receipt is kept once mined.
+    pub fn call_contract(
+        &mut self,
+        sender: String,
+        contract: String,
+        value: Amount,
+        args: Vec<u64>,
+        gas_limit: u64,
+    ) -> Option<String> {
+        let mut tx = self.build_transaction(sender, contract, value)?;
+        tx.kind = TxKind::Contract;
+        tx.contract = Some(ContractAction::Call { args, gas_limit });
+        let id = tx.id();
+        self.submit_transaction(tx).then_some(id)
+    }
+
+    pub fn get_contract(&self, address: &str) -> Option<&Contract> {
+        self.state.contract(address)
+    }
+
+    /// Receipt of a mined contract transaction.
+    pub fn contract_receipt(&self, tx_id: &str) -> Option<&ContractReceipt> {
+        self.state.receipt(tx_id)
+    }
+
+    /// Issues a new token whose whole `supply` goes to `issuer`.
+    pub fn issue_token(
+        &mut self,
+        issuer: String,
+        symbol: String,
+        name: String,
+        decimals: u8,
+        supply: Amount,
+    ) -> bool {
+        if symbol == self.spec.token_symbol {
+            info!("Token issue failed: {} is the native token.", symbol);
+            return false;
+        }
+        let mut tx = Transaction {
+            kind: TxKind::IssueToken,
+            token: Some(symbol),
+            issue: Some(TokenInfo { name, decimals }),
+            ..Transaction::transfer(issuer.clone(), issuer, supply)
+        };
+        self.fill_sender(&mut tx);
+        self.submit_transaction(tx)
+    }
+
+    /// Transfers `amount` of the issued token `symbol`.
+    pub fn new_token_transaction(
+        &mut self,
+        sender: String,
+        receiver: String,
+        symbol: String,
+        amount: Amount,
+    ) -> bool {
+        let mut tx = Transaction {
+            token: Some(symbol),
+            ..Transaction::transfer(sender, receiver, amount)
+        };
+        self.fill_sender(&mut tx);
+        self.submit_transaction(tx)
+    }
+
+    pub fn get_token(&self, symbol: &str) -> Option<&Token> {
+        self.state.token(symbol)
+    }
+
+    /// All issued tokens, by symbol.
+    pub fn list_tokens(&self) -> Vec<&Token> {
+        let mut tokens: Vec<&Token> = self.state.tokens().values().collect();
+        tokens.sort_by(|a, b| a.symbol.cmp(&b.symbol));
+        tokens
+    }
+
+    pub fn get_token_balance(&self, account: &str, symbol: &str) -> Option<Amount> {
+        self.state.token_balance(symbol, account)
+    }
+
+    /// Accounts holding `symbol`, largest balance first.
+    pub fn token_holders(&self, symbol: &str) -> Vec<(String, Amount)> {
+        let mut holders: Vec<(String, Amount)> = self
+            .state
+            .token_holders(symbol)
+            .into_iter()
+            .map(|(account, balance)| (account.clone(), balance))
+            .collect();
+        holders.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        holders
+    }
+
+    /// Selects enough of `sender`'s unspent outputs, skipping those already
+    /// spent by pending transactions, and sends any change back to `sender`.
+    /// Outputs of pending transactions count too, so transfers can be
+    /// chained before they are mined, unless they would be waiting for a
+    /// later lock than `lock_height` and `lock_time`.
+    fn build_utxo_transfer(
+        &self,
+        sender: String,
+        receiver: String,
+        amount: Amount,
+        lock_height: Option<u64>,
+        lock_time: Option<i64>,
+    ) -> Option<Transaction> {
+        let pending: HashSet<&OutPoint> = self
+            .current_transaction
+            .iter()
+            .flat_map(|tx| &tx.inputs)
+            .collect();
+        // A pending output may only be spent by a transaction that unlocks
+        // no earlier than the one creating it and everything that one
+        // spends. `None` sorts first, as no lock at all.
+        let mut unlocks: HashMap<String, (Option<u64>, Option<i64>)> = HashMap::new();
+        let mut candidates = self.state.utxos_of(&sender);
+        for tx in &self.current_transaction {
+            let tx_id = tx.id();
+            let mut unlock = (tx.lock_height, tx.lock_time);
+            for input in &tx.inputs {
+                if let Some(&(height, time)) = unlocks.get(&input.tx_id) {
+                    unlock = (unlock.0.max(height), unlock.1.max(time));
+                }
+            }
+            unlocks.insert(tx_id.clone(), unlock);
+            if unlock.0 > lock_height || unlock.1 > lock_time {
+                continue;
+            }
+            for (index, output) in tx.outputs.iter().enumerate() {
+                if output.lock.owner() == sender {
+                    let outpoint = OutPoint {
+                        tx_id: tx_id.clone(),
+                        index: index as u32,
+                    };
+                    candidates.push((outpoint, output.clone()));
+                }
+            }
+        }
+
+        let mut inputs = Vec::new();
+        let mut total = Amount::ZERO;
+        for (outpoint, output) in candidates {
+            if total >= amount {
+                break;
+            }
+            if pending.contains(&outpoint) {
+                continue;
+            }
+            total = total.checked_add(output.amount)?;
+            inputs.push(outpoint);
+        }
+        let change = total.checked_sub(amount)?;
+
+        let mut outputs = vec![TxOutput {
+            amount,
+            lock: Lock::Address(receiver.clone()),
+        }];
+        if !change.is_zero() {
+            outputs.push(TxOutput {
+                amount: change,
+                lock: Lock::Address(sender.clone()),
+            });
+        }
+        Some(Transaction {
+            lock_height,
+            lock_time,
+            ..Transaction::utxo_transfer(sender, receiver, amount, inputs, outputs)
+        })
+    }
+
+    pub fn get_block(&self, height: u64) -> Option<&Block> {
+        self.chain.get(height as usize)
+    }
+
+    pub fn get_block_by_hash(&self, hash: &str) -> Option<&Block> {
+        self.get_block(self.block_height(hash)?)
+    }
+
+    /// Height of the connected block with header hash `hash`.
+    pub fn block_height(&self, hash: &str) -> Option<u64> {
+        self.index.height_of(hash)
+    }
+
+    /// Confirmed transactions involving `address`, newest first, skipping
+    /// `offset` of them and returning at most `limit`.
+    pub fn address_history(&self, address: &str, offset: usize, limit: usize) -> Page<TxLocation> {
+        self.index.address_history(address, offset, limit)
+    }
+
+    /// Native balance of `account` after the block at `height`. Looked up
+    /// in the index, so nothing is replayed.
+    pub fn balance_at(&self, account: &str, height: u64) -> Option<Amount> {
+        if height >= self.chain.len() as u64 {
+            return None;
+        }
+        self.index.balance_at(account, None, height)
+    }
+
+    /// Balance of `account` in the issued token `symbol` after the block at
+    /// `height`.
+    pub fn token_balance_at(&self, account: &str, symbol: &str, height: u64) -> Option<Amount> {
+        if height >= self.chain.len() as u64 {
+            return None;
+        }
+        self.index.balance_at(account, Some(symbol), height)
+    }
+
+    /// Every confirmed credit and debit of `account`'s native balance, or of
+    /// its balance in `token`, oldest first and with the balance after each.
+    pub fn account_ledger(&self, account: &str, token: Option<&str>) -> &[LedgerEntry] {
+        self.index.ledger(account, token)
+    }
+
+    /// Unspent outputs locked to `address`. Empty in account mode.
+    pub fn get_utxos(&self, address: &str) -> Vec<(OutPoint, TxOutput)> {
+        self.state.utxos_of(address)
+    }
+
     pub fn last_hash(&self) -> String {
         self.chain
             .last()
@@ -125,93 +1185,450 @@ impl Chain {
             .unwrap_or_else(|| GENESIS_HASH.to_string())
     }
 
+    /// Sets the difficulty new blocks are mined at. Peers reject blocks
+    /// below the spec's difficulty, so it is the lowest allowed.
     pub fn update_difficulty(&mut self, difficulty: u32) -> bool {
+        if !(self.spec.difficulty..=MAX_DIFFICULTY).contains(&difficulty) {
+            info!(
+                "Difficulty {} is not from {} to {}",
+                difficulty, self.spec.difficulty, MAX_DIFFICULTY
+            );
+            return false;
+        }
         self.difficulty = difficulty;
         true
     }
 
-    pub fn update_reward(&mut self, reward: i64) -> bool {
-        self.reward = reward;
+    pub fn difficulty(&self) -> u32 {
+        self.difficulty
+    }
+
+    /// Transactions waiting to be mined, in the order they were queued.
+    pub fn pending_transactions(&self) -> &[Transaction] {
+        &self.current_transaction
+    }
+
+    pub fn get_spec(&self) -> &ChainSpec {
+        &self.spec
+    }
+
+    pub fn genesis_hash(&self) -> String {
+        Chain::hash(&self.chain[0].header).expect("Failed to hash block header")
+    }
+
+    /// Maximum coinbase amount for the block at `height`.
+    pub fn block_reward(&self, height: usize) -> Amount {
+        let allocated = self.spec.allocated().unwrap_or(Amount::MAX);
+        self.spec.reward.reward_at(height as u64, allocated)
+    }
+
+    /// Sets what the coinbase of our blocks claims. Miners may take less
+    /// than the schedule allows, never more; what they leave is not minted.
+    pub fn update_reward(&mut self, reward: Amount) -> bool {
+        let max = self.block_reward(self.chain.len());
+        if reward > max {
+            info!(
+                "Reward {} is above the scheduled {}",
+                self.format_amount(reward),
+                self.format_amount(max)
+            );
+            return false;
+        }
+        self.reward = Some(reward);
         true
     }
 
+    /// What the coinbase of the next block we mine claims.
+    pub fn mining_reward(&self) -> Amount {
+        let max = self.block_reward(self.chain.len());
+        self.reward.map_or(max, |reward| reward.min(max))
+    }
+
+    /// Native supply in circulation after the block at `height`: everything
+    /// minted up to and including it, less the gas fees contract
+    /// transactions burned. `None` if the chain is not that long yet.
+    pub fn circulating_supply(&self, height: usize) -> Option<Amount> {
+        let blocks = self.chain.get(..=height)?;
+        let transactions = || blocks.iter().flat_map(|block| &block.transactions);
+        let minted =
+            Amount::checked_sum(transactions().filter(|tx| tx.is_mint()).map(|tx| tx.amount))?;
+        let burned = Amount::checked_sum(
+            transactions()
+                .filter(|tx| tx.kind == TxKind::Contract)
+                .filter_map(|tx| self.contract_receipt(&tx.id()))
+                .map(|receipt| {
+                    Amount::from_base_units(receipt.gas_used.saturating_mul(vm::GAS_PRICE))
+                }),
+        )?;
+        minted.checked_sub(burned)
+    }
+
+    /// Mines a block on top of the tip, holding the chain the whole time.
+    /// `mining::Miner` does the search without the lock.
     pub fn generate_new_block(&mut self) -> bool {
+        let Some(mut template) = self.block_template() else {
+            return false;
+        };
+        template.search(&self.hash_meter, || false);
+        self.submit_template(template)
+    }
+
+    /// A block on top of the tip with the pending transactions that apply,
+    /// still needing its proof of work. Transactions stay pending until the
+    /// block is submitted.
+    pub fn block_template(&mut self) -> Option<BlockTemplate> {
+        let height = self.chain.len() as u64;
+        let timestamp = self
+            .chain
+            .last()
+            .map_or(0, |parent| parent.header.timestamp)
+            .max(Utc::now().timestamp_millis());
+        let coinbase =
+            Transaction::coinbase(self.miner_address.clone(), self.mining_reward(), height);
+        let mut overlay = self.state.overlay(height);
+        if let Err(e) = overlay.apply(&coinbase) {
+            info!("Block generation failed: {}", e);
+            return None;
+        }
+
+        // Pending transactions may have been valid on their own but not in
+        // combination; drop the ones that no longer apply. Locked ones wait
+        // in the pool, and so do later ones from the same sender waiting
+        // for their nonce to come up or spending outputs of pending ones.
+        let queued: HashSet<(String, u64)> = self
+            .current_transaction
+            .iter()
+            .map(|tx| (tx.sender.clone(), tx.nonce))
+            .collect();
+        let pooled: HashSet<String> = self
+            .current_transaction
+            .iter()
+            .map(Transaction::id)
+            .collect();
+        let mut transactions = vec![coinbase];
+        let mut pending = Vec::new();
+        for tx in self.current_transaction.drain(..) {
+            if !tx.is_unlocked(height, timestamp) {
+                pending.push(tx);
+                continue;
+            }
+            match overlay.apply(&tx) {
+                Ok(_) => {
+                    transactions.push(tx.clone());
+                    pending.push(tx);
+                }
+                Err(BlockError::InvalidNonce { expected, found })
+                    if found > expected && queued.contains(&(tx.sender.clone(), expected)) =>
+                {
+                    pending.push(tx)
+                }
+                Err(BlockError::UnknownInput(outpoint)) if pooled.contains(&outpoint.tx_id) => {
+                    pending.push(tx)
+                }
+                Err(e) => info!("Dropping transaction {:?}: {}", tx, e),
+            }
+        }
+        let state_root = overlay.state_root();
+        drop(overlay);
+        self.current_transaction = pending;
+
         let header = BlockHeader {
-            timestamp: Utc::now().timestamp_millis(),
+            timestamp,
             nonce: 0,
             previous_hash: self.last_hash(),
             difficulty: self.difficulty,
-            merkle: String::new(),
-        };
-
-        let reward_transaction = Transaction {
-            sender: String::from("Root"),
-            receiver: self.miner_address.clone(),
-            amount: self.reward,
-        };
-
-        let mut block = Block {
-            header,
-            count: 0,
-            transactions: vec![],
+            merkle: Chain::get_merkle(transactions.clone())
+                .expect("Failed to calculate Merkle root"),
+            state_root,
         };
+        Some(BlockTemplate {
+            height,
+            block: Block {
+                header,
+                count: transactions.len() as u32,
+                transactions,
+            },
+        })
+    }
 
-        block.transactions.push(reward_transaction);
-        block.transactions.append(&mut self.current_transaction);
-        block.count = block.transactions.len() as u32;
-        block.header.merkle =
-            Chain::get_merkle(block.transactions.clone()).expect("Failed to calculate Merkle root");
-        Chain::proof_of_work(&mut block.header);
+    /// Connects a template whose proof of work is done and tells peers and
+    /// subscribers. Fails if the tip moved since the template was made.
+    pub fn submit_template(&mut self, template: BlockTemplate) -> bool {
+        let BlockTemplate { height, block } = template;
+        if block.header.previous_hash != self.last_hash() {
+            info!("Mined block {} is stale: the tip moved", height);
+            return false;
+        }
         info!("New block mined: {:?}", block);
-        if self.chain.is_empty() {
-            info!("Token Name: {}", self.token_name);
-            info!("Token Symbol: {}", self.token_symbol);
+        if let Err(e) = self.add_block(block) {
+            info!("Mined block was rejected: {}", e);
+            return false;
         }
-        self.process_transactions(&block);
+        true
+    }
+
+    /// Connects `block` on top of the tip, takes its transactions out of
+    /// the pending pool and tells subscribers and peers about it.
+    pub fn add_block(&mut self, block: Block) -> Result<(), BlockError> {
+        self.connect_block(block.clone())?;
+        let included: HashSet<String> = block.transactions.iter().map(Transaction::id).collect();
+        self.current_transaction
+            .retain(|tx| !included.contains(&tx.id()));
+        self.announce_block(self.chain.len() as u64 - 1);
         let p2p_tx = self.p2p_tx.clone();
-        let new_block = block.clone();
         tokio::spawn(async move {
             p2p_tx
                 .send(p2p::P2pMessage {
                     sender: "0.0.0.0:0".parse().unwrap(), // dummy address
-                    message: p2p::Message::NewBlock(new_block),
+                    message: p2p::Message::NewBlock(block),
                 })
                 .await
                 .unwrap();
         });
+        Ok(())
+    }
+
+    /// Validates `block` on top of the current tip and applies it. Either
+    /// every transaction applies and the block becomes the new tip, or
+    /// nothing changes.
+    pub fn connect_block(&mut self, block: Block) -> Result<(), BlockError> {
+        self.validate_block(&block, self.chain.len(), self.chain.last())?;
+        let (undo, receipts) = self
+            .state
+            .apply_block(&block.transactions, self.chain.len() as u64)?;
+        if self.state.state_root() != block.header.state_root {
+            self.state.undo(undo);
+            return Err(BlockError::StateRootMismatch);
+        }
+        self.undo.push(undo);
         self.chain.push(block);
-        true
+        self.index_tip(&receipts);
+        Ok(())
+    }
+
+    /// Indexes the tip block with the `receipts` of its transactions, in
+    /// block order.
+    fn index_tip(&mut self, receipts: &[TxReceipt]) {
+        let block = self.chain.last().expect("Chain has a genesis block");
+        let hash = Chain::hash(&block.header).expect("Failed to hash block header");
+        self.index.connect(hash, block, receipts);
+    }
+
+    /// Handle for subscribing to chain events.
+    pub fn events(&self) -> EventSender {
+        self.events.clone()
+    }
+
+    /// Counts the hashes tried while mining our blocks.
+    pub fn hash_meter(&self) -> HashMeter {
+        self.hash_meter.clone()
     }
 
-    fn process_transactions(&mut self, block: &Block) {
+    fn emit(&self, event: ChainEvent) {
+        // Nobody listening is fine.
+        let _ = self.events.send(event);
+    }
+
+    /// Tells subscribers about the connected block at `height` and every
+    /// address it touched.
+    fn announce_block(&self, height: u64) {
+        let block = &self.chain[height as usize];
+        let mut ids = Vec::new();
         for tx in &block.transactions {
-            if let Some(balance) = self.balances.get_mut(&tx.sender) {
-                *balance -= tx.amount;
+            let id = tx.id();
+            let mut addresses = vec![tx.sender.clone(), tx.receiver.clone()];
+            if let Some(receipt) = self.state.tx_receipt(&id) {
+                addresses.extend(receipt.balance_changes.iter().map(|c| c.account.clone()));
+            }
+            addresses.retain(|address| !address.is_empty());
+            addresses.sort();
+            addresses.dedup();
+            for address in addresses {
+                self.emit(ChainEvent::AddressActivity {
+                    address,
+                    tx_id: id.clone(),
+                    block_height: Some(height),
+                });
             }
-            let receiver_balance = self.balances.entry(tx.receiver.clone()).or_insert(0);
-            *receiver_balance += tx.amount;
+            ids.push(id);
+        }
+        self.emit(ChainEvent::NewHead {
+            height,
+            hash: Chain::hash(&block.header).expect("Failed to hash block header"),
+            header: block.header.clone(),
+            transactions: ids,
+        });
+    }
+
+    /// Removes the tip block and reverts its changes to the state. The
+    /// genesis block cannot be disconnected.
+    pub fn disconnect_tip(&mut self) -> Option<Block> {
+        if self.chain.len() <= 1 {
+            return None;
         }
+        let block = self.chain.pop()?;
+        let undo = self.undo.pop().expect("Missing undo data for tip");
+        self.state.undo(undo);
+        self.index.disconnect();
+        Some(block)
     }
 
+    /// Switches to `new_chain` if it has more total work and is valid. Our
+    /// blocks after the fork point are disconnected and the new ones
+    /// connected; if any of them fails, the original chain is restored.
     pub fn resolve_conflict(&mut self, new_chain: &[Block]) -> bool {
-        if new_chain.len() <= self.chain.len() {
+        if Chain::total_work(new_chain) <= Chain::total_work(&self.chain) {
             return false;
         }
 
-        for (i, block) in new_chain.iter().enumerate() {
-            if i == 0 {
-                continue;
-            }
-            let prev_block = &new_chain[i - 1];
-            if block.header.previous_hash != Chain::hash(&prev_block.header).unwrap() {
+        let fork = self
+            .chain
+            .iter()
+            .zip(new_chain)
+            .take_while(|(ours, theirs)| {
+                Chain::hash(&ours.header).ok() == Chain::hash(&theirs.header).ok()
+            })
+            .count();
+        if fork == 0 {
+            info!("Rejected chain: {}", BlockError::GenesisMismatch);
+            return false;
+        }
+
+        let mut disconnected = Vec::new();
+        while self.chain.len() > fork {
+            disconnected.extend(self.disconnect_tip());
+        }
+        let mut connected = Vec::new();
+        for block in &new_chain[fork..] {
+            if let Err(e) = self.connect_block(block.clone()) {
+                info!("Rejected chain at block {}: {}", self.chain.len(), e);
+                while self.chain.len() > fork {
+                    self.disconnect_tip();
+                }
+                for block in disconnected.into_iter().rev() {
+                    self.connect_block(block)
+                        .expect("Previously connected block must reconnect");
+                }
                 return false;
             }
+            connected.push(self.last_hash());
+        }
+
+        // Subscribers see the switch first, then each new block as a head.
+        if !disconnected.is_empty() {
+            self.emit(ChainEvent::Reorg {
+                fork_height: fork as u64 - 1,
+                disconnected: disconnected
+                    .iter()
+                    .rev()
+                    .map(|block| Chain::hash(&block.header).expect("Failed to hash block header"))
+                    .collect(),
+                connected,
+            });
+        }
+        for height in fork..self.chain.len() {
+            self.announce_block(height as u64);
         }
 
-        self.chain = new_chain.to_vec();
+        // Transfers that only made it into our abandoned blocks go back to
+        // the pending pool, and those the new blocks include leave it.
+        let included: HashSet<String> = new_chain[fork..]
+            .iter()
+            .flat_map(|block| &block.transactions)
+            .map(Transaction::id)
+            .collect();
+        self.current_transaction
+            .retain(|tx| !included.contains(&tx.id()));
+        for block in disconnected.into_iter().rev() {
+            for tx in block.transactions {
+                let hash = Chain::hash(&tx).ok();
+                if !tx.is_mint() && !hash.is_some_and(|h| included.contains(&h)) {
+                    self.current_transaction.push(tx);
+                }
+            }
+        }
         true
     }
 
+    /// Checks that `block` links to `parent`, carries enough proof of work
+    /// and commits to its own transactions, and follows the coinbase rules:
+    /// the genesis block (no parent) must be our own, every other block
+    /// starts with exactly one coinbase paying at most the scheduled reward
+    /// for its `height`.
+    pub fn validate_block(
+        &self,
+        block: &Block,
+        height: usize,
+        parent: Option<&Block>,
+    ) -> Result<(), BlockError> {
+        let Some(parent) = parent else {
+            if Chain::hash(&block.header).ok() != Some(self.genesis_hash()) {
+                return Err(BlockError::GenesisMismatch);
+            }
+            return Ok(());
+        };
+
+        let parent_hash = Chain::hash(&parent.header).expect("Failed to hash block header");
+        if block.header.previous_hash != parent_hash {
+            return Err(BlockError::InvalidPreviousHash);
+        }
+        if block.header.timestamp < parent.header.timestamp
+            || block.header.timestamp > Utc::now().timestamp_millis() + MAX_FUTURE_DRIFT
+        {
+            return Err(BlockError::InvalidTimestamp);
+        }
+        let difficulty = block.header.difficulty;
+        if !(self.spec.difficulty..=MAX_DIFFICULTY).contains(&difficulty) {
+            return Err(BlockError::InvalidDifficulty {
+                minimum: self.spec.difficulty,
+                found: difficulty,
+            });
+        }
+        let hash = Chain::hash(&block.header).expect("Failed to hash block header");
+        if !Chain::meets_difficulty(&hash, difficulty) {
+            return Err(BlockError::InsufficientWork);
+        }
+        if block.count as usize != block.transactions.len() {
+            return Err(BlockError::CountMismatch);
+        }
+        if Chain::get_merkle(block.transactions.clone()).ok().as_ref() != Some(&block.header.merkle)
+        {
+            return Err(BlockError::MerkleMismatch);
+        }
+        if !block
+            .transactions
+            .iter()
+            .all(|tx| tx.is_unlocked(height as u64, block.header.timestamp))
+        {
+            return Err(BlockError::TransactionLocked);
+        }
+
+        let mut txs = block.transactions.iter();
+        match txs.next() {
+            Some(tx) if tx.kind == TxKind::Coinbase => {
+                if tx.nonce != height as u64 {
+                    return Err(BlockError::CoinbaseHeightMismatch);
+                }
+                let reward = self.block_reward(height);
+                if tx.amount > reward {
+                    return Err(BlockError::InvalidReward {
+                        expected: reward,
+                        found: tx.amount,
+                    });
+                }
+            }
+            _ => return Err(BlockError::MissingCoinbase),
+        }
+        for tx in txs {
+            match tx.kind {
+                TxKind::Transfer | TxKind::Htlc | TxKind::Contract | TxKind::IssueToken => {}
+                TxKind::Coinbase => return Err(BlockError::MultipleCoinbase),
+                TxKind::Allocation => return Err(BlockError::UnexpectedAllocation),
+            }
+        }
+        Ok(())
+    }
+
     fn get_merkle(transactions: Vec<Transaction>) -> Result<String, serde_json::Error> {
         let mut merkle = Vec::new();
         for t in &transactions {
@@ -243,15 +1660,55 @@ impl Chain {
     }
 
     pub fn proof_of_work(header: &mut BlockHeader) {
-        let prefix = "0".repeat(header.difficulty as usize);
-        loop {
+        Chain::proof_of_work_metered(header, &HashMeter::default(), || false);
+    }
+
+    /// Proof of work that reports the hashes it tries to `meter` and gives
+    /// up, returning false, once `cancelled` says so. `cancelled` is asked
+    /// between batches of hashes, so it may be slowish.
+    pub fn proof_of_work_metered(
+        header: &mut BlockHeader,
+        meter: &HashMeter,
+        mut cancelled: impl FnMut() -> bool,
+    ) -> bool {
+        meter.start();
+        let mut tried = 0;
+        let found = loop {
             let hash = Chain::hash(header).expect("Failed to hash header");
-            if hash.starts_with(&prefix) {
-                println!("Block hash: {}", hash);
-                break;
+            tried += 1;
+            if Chain::meets_difficulty(&hash, header.difficulty) {
+                info!("Block hash: {}", hash);
+                break true;
+            }
+            if tried == mining::METER_INTERVAL {
+                meter.add(tried);
+                tried = 0;
+                if cancelled() {
+                    break false;
+                }
             }
             header.nonce += 1;
+        };
+        meter.add(tried);
+        meter.stop();
+        found
+    }
+
+    /// Whether `hash` is below the target for `difficulty`, i.e. starts
+    /// with `difficulty` zero hex digits.
+    pub fn meets_difficulty(hash: &str, difficulty: u32) -> bool {
+        hash.len() >= difficulty as usize
+            && hash.bytes().take(difficulty as usize).all(|b| b == b'0')
+    }
+
+    /// Expected number of hashes it took to mine `blocks`, as a base-16
+    /// number: a block of difficulty `d` is worth `16^d`.
+    pub fn total_work(blocks: &[Block]) -> Work {
+        let mut work = Work::default();
+        for block in blocks {
+            work.add(block.header.difficulty);
         }
+        work
     }
 
     pub fn hash<T: serde::Serialize>(item: &T) -> Result<String, serde_json::Error> {
@@ -262,3 +1719,216 @@ impl Chain {
         Ok(hex::encode(res))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{self, units};
+
+    #[test]
+    fn rejects_vesting_schedules_that_overflow() {
+        let (mut chain, _p2p_rx) = testing::chain(LedgerModel::Account, &[("alice", 100)]);
+        assert!(!chain.schedule_vesting(
+            "alice".into(),
+            "bob".into(),
+            units(10),
+            1,
+            u64::MAX / 2 + 1,
+            3
+        ));
+        assert!(!chain.schedule_vesting("alice".into(), "bob".into(), units(10), u64::MAX, 1, 2));
+        assert!(chain.pending_transactions().is_empty());
+    }
+
+    #[test]
+    fn parses_amounts_with_the_chain_symbol() {
+        let (chain, _p2p_rx) = testing::chain(LedgerModel::Account, &[]);
+        assert_eq!(chain.parse_amount("12"), Ok(units(12)));
+        assert_eq!(chain.parse_amount(" 12 TST "), Ok(units(12)));
+        assert_eq!(
+            chain.parse_amount("12 RST"),
+            Err(AmountError::WrongSymbol(String::from("RST")))
+        );
+        assert_eq!(
+            chain.parse_amount("1.5 TST"),
+            Err(AmountError::TooManyDecimals(0))
+        );
+    }
+
+    #[tokio::test]
+    async fn htlcs_are_claimed_before_their_timeout() {
+        let (mut chain, _p2p_rx) = testing::chain(LedgerModel::Account, &[("alice", 100)]);
+        let (preimage, hash_lock) = htlc::new_secret();
+        let id = chain
+            .lock_htlc("alice".into(), "bob".into(), units(40), hash_lock, 3)
+            .unwrap();
+        assert!(chain.generate_new_block());
+        assert_eq!(chain.get_balance("alice"), Some(units(60)));
+        assert!(!chain.refund_htlc(id.clone()));
+        assert!(!chain.claim_htlc(id.clone(), htlc::new_secret().0));
+        assert_eq!(chain.htlc_preimage(&id), None);
+
+        assert!(chain.claim_htlc(id.clone(), preimage.clone()));
+        assert!(chain.generate_new_block());
+        assert_eq!(chain.get_balance("bob"), Some(units(40)));
+        assert_eq!(chain.get_htlc(&id), None);
+        assert_eq!(chain.htlc_preimage(&id), Some(preimage));
+    }
+
+    #[tokio::test]
+    async fn htlcs_are_refunded_from_their_timeout_on() {
+        let (mut chain, _p2p_rx) = testing::chain(LedgerModel::Account, &[("alice", 100)]);
+        let (preimage, hash_lock) = htlc::new_secret();
+        assert!(chain
+            .lock_htlc(
+                "alice".into(),
+                "bob".into(),
+                units(40),
+                hash_lock.clone(),
+                0
+            )
+            .is_none());
+        let id = chain
+            .lock_htlc("alice".into(), "bob".into(), units(40), hash_lock, 3)
+            .unwrap();
+        assert!(chain.generate_new_block());
+        assert!(chain.generate_new_block());
+        // Block 3 is the first the refund may go into, and the claim not.
+        assert!(!chain.claim_htlc(id.clone(), preimage));
+        assert!(chain.refund_htlc(id.clone()));
+        assert!(chain.generate_new_block());
+        assert_eq!(chain.get_balance("alice"), Some(units(100)));
+        assert_eq!(chain.get_balance("bob"), None);
+        assert_eq!(chain.get_htlc(&id), None);
+        assert_eq!(chain.htlc_preimage(&id), None);
+    }
+
+    #[tokio::test]
+    async fn multisig_transfers_need_the_threshold_of_signatures() {
+        let (mut chain, _p2p_rx) = testing::chain(LedgerModel::Account, &[("alice", 100)]);
+        let keys: Vec<_> = (0..3).map(|_| multisig::generate_keypair()).collect();
+        let public_keys = keys.iter().map(|(_, public)| public.clone()).collect();
+        let account = chain.create_multisig_account(public_keys, 2).unwrap();
+        assert!(chain
+            .new_transaction("alice".into(), account.clone(), units(50))
+            .is_some());
+        assert!(chain.generate_new_block());
+
+        let mut tx = chain
+            .build_transaction(account.clone(), "bob".into(), units(20))
+            .unwrap();
+        tx.sign(&keys[0].0).unwrap();
+        assert!(!chain.submit_transaction(tx.clone()));
+        tx.sign(&keys[2].0).unwrap();
+        assert!(chain.submit_transaction(tx));
+        assert!(chain.generate_new_block());
+        assert_eq!(chain.get_balance(&account), Some(units(30)));
+        assert_eq!(chain.get_balance("bob"), Some(units(20)));
+    }
+
+    #[tokio::test]
+    async fn utxo_transfers_select_outputs_and_return_change() {
+        let (mut chain, _p2p_rx) = testing::chain(
+            LedgerModel::Utxo,
+            &[("alice", 30), ("alice", 30), ("alice", 30)],
+        );
+        let id = chain
+            .new_transaction("alice".into(), "bob".into(), units(50))
+            .unwrap();
+        let tx = chain.get_transaction(&id).unwrap();
+        assert_eq!(tx.inputs.len(), 2);
+        let amounts: Vec<_> = tx
+            .outputs
+            .iter()
+            .map(|output| (output.lock.owner().to_string(), output.amount))
+            .collect();
+        assert_eq!(
+            amounts,
+            [("bob".into(), units(50)), ("alice".into(), units(10))]
+        );
+
+        // The rest: the last allocation and the pending change, exactly.
+        let id = chain
+            .new_transaction("alice".into(), "carol".into(), units(40))
+            .unwrap();
+        let tx = chain.get_transaction(&id).unwrap();
+        assert_eq!(tx.inputs.len(), 2);
+        assert_eq!(tx.outputs.len(), 1);
+        assert!(chain
+            .new_transaction("alice".into(), "carol".into(), units(1))
+            .is_none());
+
+        assert!(chain.generate_new_block());
+        assert_eq!(chain.get_balance("alice"), Some(Amount::ZERO));
+        assert_eq!(chain.get_balance("bob"), Some(units(50)));
+        assert_eq!(chain.get_balance("carol"), Some(units(40)));
+        assert!(chain.get_utxos("alice").is_empty());
+    }
+
+    #[test]
+    fn utxo_transfers_need_enough_outputs() {
+        let (mut chain, _p2p_rx) =
+            testing::chain(LedgerModel::Utxo, &[("alice", 30), ("alice", 30)]);
+        assert!(chain
+            .new_transaction("alice".into(), "bob".into(), units(61))
+            .is_none());
+        assert!(chain
+            .new_transaction("bob".into(), "alice".into(), units(1))
+            .is_none());
+        assert!(chain.pending_transactions().is_empty());
+    }
+
+    #[tokio::test]
+    async fn utxo_transfers_do_not_spend_locked_change() {
+        let (mut chain, _p2p_rx) =
+            testing::chain(LedgerModel::Utxo, &[("alice", 50), ("alice", 50)]);
+        assert!(chain.new_locked_transaction(
+            "alice".into(),
+            "bob".into(),
+            units(10),
+            Some(100),
+            None
+        ));
+        // Only the other allocation is spendable now.
+        assert!(chain
+            .new_transaction("alice".into(), "carol".into(), units(60))
+            .is_none());
+        assert!(chain
+            .new_transaction("alice".into(), "carol".into(), units(30))
+            .is_some());
+        assert!(chain.generate_new_block());
+        assert_eq!(chain.get_balance("carol"), Some(units(30)));
+        assert_eq!(chain.pending_transactions().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn utxo_vesting_chains_installments_until_they_unlock() {
+        let (mut chain, _p2p_rx) = testing::chain(LedgerModel::Utxo, &[("alice", 90)]);
+        assert!(chain.schedule_vesting("alice".into(), "bob".into(), units(90), 1, 1, 3));
+        for height in 1..=3 {
+            assert!(chain.generate_new_block());
+            assert_eq!(chain.get_balance("bob"), Some(units(30 * height)));
+        }
+        assert!(chain.pending_transactions().is_empty());
+    }
+
+    #[tokio::test]
+    async fn later_transactions_wait_for_a_locked_nonce() {
+        let (mut chain, _p2p_rx) = testing::chain(LedgerModel::Account, &[("alice", 100)]);
+        assert!(chain.new_locked_transaction(
+            "alice".into(),
+            "bob".into(),
+            units(10),
+            Some(2),
+            None
+        ));
+        assert!(chain
+            .new_transaction("alice".into(), "bob".into(), units(10))
+            .is_some());
+        assert!(chain.generate_new_block());
+        assert_eq!(chain.get_balance("bob"), None);
+        assert_eq!(chain.pending_transactions().len(), 2);
+        assert!(chain.generate_new_block());
+        assert_eq!(chain.get_balance("bob"), Some(units(20)));
+    }
+}
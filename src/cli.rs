@@ -0,0 +1,554 @@
+use crate::blockchain::Chain;
+use crate::config::NodeConfig;
+use crate::p2p::P2pMessage;
+use crate::rpc;
+use crate::spec::ChainSpec;
+use crate::store::{self, Archive, Snapshot, Store, StoreLock};
+use crate::swap::Leg;
+use clap::{Args, Parser, Subcommand};
+use std::error::Error;
+use std::fs;
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
+use tokio::sync::mpsc;
+
+/// Data directory of commands other than `run` when none is given.
+pub const DEFAULT_DATA_DIR: &str = "data";
+
+/// A toy blockchain node. Without a command it runs one, like `run`.
+///
+/// Settings come from the config file, then `BLOCKCHAIN_*` environment
+/// variables, then flags, each overriding the one before.
+#[derive(Debug, Parser)]
+#[command(version, about)]
+pub struct Cli {
+    /// TOML config file.
+    #[arg(long, global = true, env = "BLOCKCHAIN_CONFIG")]
+    pub config: Option<PathBuf>,
+    /// Directory the chain is kept in. `run` keeps nothing on disk without
+    /// it; the other commands default to `data`.
+    #[arg(long, global = true, env = "BLOCKCHAIN_DATA_DIR")]
+    pub data_dir: Option<PathBuf>,
+    /// `off`, `error`, `warn`, `info`, `debug` or `trace`.
+    #[arg(long, global = true, env = "BLOCKCHAIN_LOG_LEVEL")]
+    pub log_level: Option<String>,
+    #[command(subcommand)]
+    pub command: Option<Command>,
+    #[command(flatten)]
+    pub run: RunArgs,
+}
+
+impl Cli {
+    /// The config file with environment variables and flags applied on
+    /// top, checked before anything starts.
+    pub fn node_config(&self, run: &RunArgs) -> Result<NodeConfig, Box<dyn Error>> {
+        let mut config = match &self.config {
+            Some(path) => NodeConfig::load(path)?,
+            None => NodeConfig::default(),
+        };
+        if let Some(data_dir) = &self.data_dir {
+            config.data_dir = Some(data_dir.clone());
+        }
+        if let Some(level) = &self.log_level {
+            config.logging.level = level.clone();
+        }
+        run.apply(&mut config);
+        config.validate()?;
+        Ok(config)
+    }
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// Creates a data directory for a chain.
+    Init(InitArgs),
+    /// Runs a node: the TUI, or with `--headless` just the chain, P2P and
+    /// the API.
+    Run(RunArgs),
+    /// Mines blocks on the stored chain.
+    Mine {
+        /// Address that receives the block rewards.
+        #[arg(long)]
+        miner: String,
+        /// How many blocks to mine.
+        #[arg(long, default_value_t = 1)]
+        blocks: u32,
+    },
+    /// Queues a transfer on the stored chain and prints its id. It is
+    /// included by the next `mine`.
+    Send {
+        sender: String,
+        receiver: String,
+        /// Amount in tokens, e.g. `12.5` or `12.5 RST`.
+        amount: String,
+    },
+    /// Prints the balance of an account.
+    Balance { account: String },
+    #[command(subcommand)]
+    Account(AccountCommand),
+    #[command(subcommand)]
+    Block(BlockCommand),
+    /// Writes the spec, blocks and pending transactions to a file.
+    Export { file: PathBuf },
+    /// Adopts the chain in an exported file if it is valid and longer than
+    /// the stored one.
+    Import { file: PathBuf },
+    /// Swaps tokens between two chains through running nodes' JSON-RPC,
+    /// with HTLCs. Both nodes must be mining.
+    Swap(SwapArgs),
+}
+
+/// Manages accounts.
+#[derive(Debug, Subcommand)]
+pub enum AccountCommand {
+    /// Creates an empty account.
+    New { name: String },
+}
+
+/// Reads blocks.
+#[derive(Debug, Subcommand)]
+pub enum BlockCommand {
+    /// Prints a block, by height or hash, as JSON.
+    Get { id: String },
+}
+
+#[derive(Debug, Args)]
+pub struct InitArgs {
+    /// Chain spec file. Without one a private dev chain is created.
+    #[arg(long)]
+    pub spec: Option<PathBuf>,
+    /// Token name of a dev chain.
+    #[arg(long, default_value = "Rust Token", conflicts_with = "spec")]
+    pub token_name: String,
+    /// Token symbol of a dev chain.
+    #[arg(long, default_value = "RST", conflicts_with = "spec")]
+    pub token_symbol: String,
+    /// Difficulty of a dev chain.
+    #[arg(long, default_value_t = 2, conflicts_with = "spec")]
+    pub difficulty: u32,
+}
+
+#[derive(Debug, Args)]
+pub struct SwapArgs {
+    /// JSON-RPC address of a node on the first chain.
+    #[arg(long)]
+    pub first_node: SocketAddr,
+    /// JSON-RPC address of a node on the second chain.
+    #[arg(long)]
+    pub second_node: SocketAddr,
+    /// Pays `first_amount` on the first chain and picks the secret.
+    #[arg(long)]
+    pub first: String,
+    /// Pays `second_amount` on the second chain.
+    #[arg(long)]
+    pub second: String,
+    /// Amount in the first chain's tokens.
+    #[arg(long)]
+    pub first_amount: String,
+    /// Amount in the second chain's tokens.
+    #[arg(long)]
+    pub second_amount: String,
+    /// Blocks until the second lock can be refunded. The first lock gets
+    /// twice as many.
+    #[arg(long, default_value_t = 10)]
+    pub timeout: u64,
+}
+
+impl SwapArgs {
+    /// The two legs of the swap, in the order they are locked.
+    pub fn legs(&self) -> (Leg, Leg) {
+        let first = Leg {
+            node: self.first_node,
+            sender: self.first.clone(),
+            receiver: self.second.clone(),
+            amount: self.first_amount.clone(),
+            timeout: self.timeout.saturating_mul(2),
+        };
+        let second = Leg {
+            node: self.second_node,
+            sender: self.second.clone(),
+            receiver: self.first.clone(),
+            amount: self.second_amount.clone(),
+            timeout: self.timeout,
+        };
+        (first, second)
+    }
+}
+
+#[derive(Debug, Clone, Default, Args)]
+pub struct RunArgs {
+    /// P2P port [default: 8080]. JSON-RPC and REST listen 1000 ports above
+    /// it.
+    #[arg(env = "BLOCKCHAIN_PORT")]
+    pub port: Option<u16>,
+    /// Address to take peers on [default: 127.0.0.1]; `0.0.0.0` takes them
+    /// from other hosts.
+    #[arg(long, env = "BLOCKCHAIN_BIND")]
+    pub bind: Option<IpAddr>,
+    /// Peers to connect to at startup.
+    #[arg(env = "BLOCKCHAIN_PEERS", value_delimiter = ',')]
+    pub peers: Vec<SocketAddr>,
+    /// Chain spec file. Defaults to the spec in the data directory, or a
+    /// dev chain set up at the prompt.
+    #[arg(long, env = "BLOCKCHAIN_SPEC")]
+    pub spec: Option<PathBuf>,
+    /// Token name of a dev chain.
+    #[arg(long, env = "BLOCKCHAIN_TOKEN_NAME")]
+    pub token_name: Option<String>,
+    /// Token symbol of a dev chain.
+    #[arg(long, env = "BLOCKCHAIN_TOKEN_SYMBOL")]
+    pub token_symbol: Option<String>,
+    /// Run without the TUI until SIGINT or SIGTERM.
+    #[arg(long, env = "BLOCKCHAIN_HEADLESS")]
+    pub headless: bool,
+    /// Address that receives block rewards. Prompted for in the TUI if not
+    /// given.
+    #[arg(long, env = "BLOCKCHAIN_MINER")]
+    pub miner: Option<String>,
+    /// Start mining right away, at most one block every `block_time`
+    /// seconds of the spec. `--mine=false` turns off mining enabled
+    /// elsewhere.
+    #[arg(
+        long,
+        env = "BLOCKCHAIN_MINE",
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "true"
+    )]
+    pub mine: Option<bool>,
+    /// Difficulty to mine at. Defaults to the spec's.
+    #[arg(long, env = "BLOCKCHAIN_DIFFICULTY")]
+    pub difficulty: Option<u32>,
+    /// Address of the JSON-RPC, REST and WebSocket server.
+    #[arg(long, env = "BLOCKCHAIN_RPC_ADDR")]
+    pub rpc_addr: Option<SocketAddr>,
+    /// Do not serve the API. `--no-rpc=false` serves it even if disabled
+    /// elsewhere.
+    #[arg(
+        long,
+        env = "BLOCKCHAIN_NO_RPC",
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "true"
+    )]
+    pub no_rpc: Option<bool>,
+}
+
+impl RunArgs {
+    fn apply(&self, config: &mut NodeConfig) {
+        let network = &mut config.network;
+        if let Some(bind) = self.bind {
+            network.bind = bind;
+        }
+        if let Some(port) = self.port {
+            network.port = port;
+        }
+        if !self.peers.is_empty() {
+            network.peers = self.peers.clone();
+        }
+        if let Some(spec) = &self.spec {
+            network.spec = Some(spec.clone());
+        }
+        if let Some(name) = &self.token_name {
+            network.token_name = Some(name.clone());
+        }
+        if let Some(symbol) = &self.token_symbol {
+            network.token_symbol = Some(symbol.clone());
+        }
+        if let Some(miner) = &self.miner {
+            config.mining.miner = Some(miner.clone());
+        }
+        if let Some(mine) = self.mine {
+            config.mining.enabled = mine;
+        }
+        if let Some(difficulty) = self.difficulty {
+            config.mining.difficulty = Some(difficulty);
+        }
+        if let Some(address) = self.rpc_addr {
+            config.rpc.address = Some(address);
+        }
+        if let Some(no_rpc) = self.no_rpc {
+            config.rpc.enabled = !no_rpc;
+        }
+    }
+}
+
+/// A stored chain opened by a command. Saving writes it back.
+struct Opened {
+    store: Store,
+    _lock: StoreLock,
+    chain: Chain,
+    // Keeps the channel the chain announces on open; nothing reads it.
+    _p2p_rx: mpsc::Receiver<P2pMessage>,
+}
+
+impl Opened {
+    fn new(dir: &Path, miner_address: String) -> Result<Opened, Box<dyn Error>> {
+        if !dir.join(store::SPEC_FILE).exists() {
+            return Err(format!("{} holds no chain; run `init` first", dir.display()).into());
+        }
+        let store = Store::open(dir)?;
+        let lock = store.lock()?;
+        let spec = store.load_spec()?.expect("Spec file exists");
+        let (p2p_tx, p2p_rx) = mpsc::channel(100);
+        let mut chain = Chain::new(spec, miner_address, p2p_tx);
+        store.load(&mut chain)?;
+        Ok(Opened {
+            store,
+            _lock: lock,
+            chain,
+            _p2p_rx: p2p_rx,
+        })
+    }
+
+    fn save(&self) -> Result<(), Box<dyn Error>> {
+        self.store.save(&self.chain)
+    }
+}
+
+/// Runs every command but `run` and `swap`, which need the network.
+/// Results go to stdout, one per line, so scripts can read them.
+pub fn execute(command: Command, config: &NodeConfig) -> Result<(), Box<dyn Error>> {
+    let dir = config
+        .data_dir
+        .clone()
+        .unwrap_or_else(|| PathBuf::from(DEFAULT_DATA_DIR));
+    match command {
+        Command::Init(args) => init(&dir, args),
+        Command::Run(_) => unreachable!("`run` is handled by the binary"),
+        Command::Swap(_) => unreachable!("`swap` is handled by the binary"),
+        Command::Mine { miner, blocks } => {
+            let mut opened = Opened::new(&dir, miner)?;
+            for _ in 0..blocks {
+                if !opened.chain.generate_new_block() {
+                    opened.save()?;
+                    return Err("mining failed; see the log".into());
+                }
+                let height = opened.chain.get_chain().len() - 1;
+                println!("{} {}", height, opened.chain.last_hash());
+            }
+            opened.save()
+        }
+        Command::Send {
+            sender,
+            receiver,
+            amount,
+        } => {
+            let mut opened = Opened::new(&dir, String::new())?;
+            let amount = opened.chain.parse_amount(&amount)?;
+            let id = opened
+                .chain
+                .new_transaction(sender, receiver, amount)
+                .ok_or("transaction was rejected; see the log")?;
+            println!("{}", id);
+            opened.save()
+        }
+        Command::Balance { account } => {
+            let opened = Opened::new(&dir, String::new())?;
+            let balance = opened
+                .chain
+                .get_balance(&account)
+                .ok_or_else(|| format!("unknown account {}", account))?;
+            println!("{}", opened.chain.format_amount(balance));
+            Ok(())
+        }
+        Command::Account(AccountCommand::New { name }) => {
+            let mut opened = Opened::new(&dir, String::new())?;
+            if !opened.chain.create_account(name.clone()) {
+                return Err(format!("account {} already exists", name).into());
+            }
+            println!("{}", name);
+            opened.save()
+        }
+        Command::Block(BlockCommand::Get { id }) => {
+            let opened = Opened::new(&dir, String::new())?;
+            let chain = &opened.chain;
+            let height = match id.parse::<u64>() {
+                Ok(height) => height,
+                Err(_) => chain
+                    .block_height(&id)
+                    .ok_or_else(|| format!("no block with hash {}", id))?,
+            };
+            let block = chain
+                .get_block(height)
+                .ok_or_else(|| format!("no block at height {}", height))?;
+            let json = rpc::block_json(block, height).map_err(|e| e.message)?;
+            println!("{}", serde_json::to_string_pretty(&json)?);
+            Ok(())
+        }
+        Command::Export { file } => {
+            let opened = Opened::new(&dir, String::new())?;
+            let archive = Archive {
+                spec: opened.chain.get_spec().clone(),
+                snapshot: Snapshot::of(&opened.chain),
+            };
+            fs::write(&file, serde_json::to_vec_pretty(&archive)?)?;
+            println!(
+                "{} {}",
+                opened.chain.get_chain().len() - 1,
+                opened.chain.last_hash()
+            );
+            Ok(())
+        }
+        Command::Import { file } => import(&dir, &file),
+    }
+}
+
+fn init(dir: &Path, args: InitArgs) -> Result<(), Box<dyn Error>> {
+    let spec = match &args.spec {
+        Some(path) => ChainSpec::load(path)?,
+        None => ChainSpec::dev(args.token_name, args.token_symbol, args.difficulty),
+    };
+    spec.validate()?;
+    let store = Store::open(dir)?;
+    let _lock = store.lock()?;
+    if store.load_spec()?.is_some() {
+        return Err(format!("{} already holds a chain", dir.display()).into());
+    }
+    store.save_spec(&spec)?;
+    let (p2p_tx, _p2p_rx) = mpsc::channel(100);
+    let chain = Chain::new(spec, String::new(), p2p_tx);
+    store.save(&chain)?;
+    println!("{}", chain.genesis_hash());
+    Ok(())
+}
+
+fn import(dir: &Path, file: &Path) -> Result<(), Box<dyn Error>> {
+    let Archive { spec, mut snapshot } = serde_json::from_str(&fs::read_to_string(file)?)?;
+    spec.validate()
+        .map_err(|e| format!("{}: {}", file.display(), e))?;
+    // Genesis is mined from the spec alone, so make the archive prove its
+    // difficulty with the first block before spending that work.
+    if let Some(first) = snapshot.blocks.first() {
+        let hash = Chain::hash(first.header())?;
+        if !Chain::meets_difficulty(&hash, spec.difficulty) {
+            return Err(format!(
+                "{}: the first block does not meet the spec's difficulty",
+                file.display()
+            )
+            .into());
+        }
+    }
+
+    let store = Store::open(dir)?;
+    let _lock = store.lock()?;
+    let existing = store.load_spec()?;
+    match &existing {
+        Some(held) if held != &spec => {
+            return Err(format!("{} holds a different chain", dir.display()).into());
+        }
+        None if snapshot.blocks.is_empty() => {
+            return Err(format!("{} holds no blocks", file.display()).into());
+        }
+        _ => {}
+    }
+    let (p2p_tx, _p2p_rx) = mpsc::channel(100);
+    let mut chain = Chain::new(spec, String::new(), p2p_tx);
+    if existing.is_some() {
+        store.load(&mut chain)?;
+    }
+    let mut blocks = vec![chain.get_chain()[0].clone()];
+    blocks.append(&mut snapshot.blocks);
+    if Chain::total_work(&blocks) > Chain::total_work(chain.get_chain())
+        && !chain.resolve_conflict(&blocks)
+    {
+        return Err("the imported chain is invalid; see the log".into());
+    }
+    snapshot.restore_pool(&mut chain);
+
+    // Nothing is written until the whole archive has been taken in, so a
+    // failed import leaves the directory as it was.
+    if existing.is_none() {
+        store.save_spec(chain.get_spec())?;
+    }
+    store.save(&chain)?;
+    println!("{} {}", chain.get_chain().len() - 1, chain.last_hash());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::spec::LedgerModel;
+    use crate::testing;
+
+    /// Parses `args` against a config file holding `toml`.
+    fn config(name: &str, toml: &str, args: &[&str]) -> NodeConfig {
+        let path = std::env::temp_dir().join(format!("{}-{}.toml", name, std::process::id()));
+        fs::write(&path, toml).unwrap();
+        let mut argv = vec!["blockchain", "--config", path.to_str().unwrap()];
+        argv.extend_from_slice(args);
+        let cli = Cli::try_parse_from(argv).unwrap();
+        let config = cli.node_config(&cli.run);
+        fs::remove_file(&path).unwrap();
+        config.unwrap()
+    }
+
+    #[test]
+    fn flags_override_the_file() {
+        let toml = "[network]\nport = 7000\n\n[mining]\nminer = \"alice\"\n";
+        let flagged = config(
+            "cli-flags",
+            toml,
+            &["8000", "--miner", "bob", "--bind", "0.0.0.0"],
+        );
+        assert_eq!(flagged.p2p_address(), "0.0.0.0:8000".parse().unwrap());
+        assert_eq!(flagged.mining.miner.as_deref(), Some("bob"));
+
+        let unflagged = config("cli-no-flags", toml, &[]);
+        assert_eq!(unflagged.p2p_address(), "127.0.0.1:7000".parse().unwrap());
+        assert_eq!(unflagged.mining.miner.as_deref(), Some("alice"));
+    }
+
+    #[test]
+    fn switches_turn_settings_off_as_well_as_on() {
+        let on = "[mining]\nenabled = true\n";
+        assert!(config("cli-mine-on", on, &[]).mining.enabled);
+        assert!(!config("cli-mine-off", on, &["--mine=false"]).mining.enabled);
+        assert!(config("cli-mine-flag", "", &["--mine"]).mining.enabled);
+
+        let off = "[rpc]\nenabled = false\n";
+        assert!(!config("cli-rpc-off", off, &[]).rpc.enabled);
+        assert!(config("cli-rpc-on", off, &["--no-rpc=false"]).rpc.enabled);
+        assert!(!config("cli-no-rpc", "", &["--no-rpc"]).rpc.enabled);
+    }
+
+    #[tokio::test]
+    async fn imports_nothing_until_the_archive_checks_out() {
+        let dir = std::env::temp_dir().join(format!("cli-import-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let file = dir.with_extension("json");
+        let (mut chain, _p2p_rx) = testing::chain(LedgerModel::Account, &[("alice", 100)]);
+        assert!(chain.generate_new_block());
+        let import_with = |spec: ChainSpec| {
+            let archive = Archive {
+                spec,
+                snapshot: Snapshot::of(&chain),
+            };
+            fs::write(&file, serde_json::to_string(&archive).unwrap()).unwrap();
+            import(&dir, &file)
+        };
+        let written = || {
+            [store::SPEC_FILE, store::CHAIN_FILE]
+                .iter()
+                .any(|name| dir.join(name).exists())
+        };
+
+        let mut unmineable = chain.get_spec().clone();
+        unmineable.difficulty = crate::blockchain::MAX_DIFFICULTY;
+        let error = import_with(unmineable).unwrap_err();
+        assert!(error.to_string().contains("difficulty"), "{}", error);
+        assert!(!written());
+
+        let foreign = testing::spec(LedgerModel::Account, &[("bob", 100)]);
+        assert!(import_with(foreign).is_err());
+        assert!(!written());
+
+        import_with(chain.get_spec().clone()).unwrap();
+        let opened = Opened::new(&dir, String::new()).unwrap();
+        assert_eq!(opened.chain.last_hash(), chain.last_hash());
+        drop(opened);
+        fs::remove_dir_all(&dir).unwrap();
+        fs::remove_file(&file).unwrap();
+    }
+}
@@ -0,0 +1,304 @@
+use crate::blockchain::MAX_DIFFICULTY;
+use crate::token;
+use log::LevelFilter;
+use serde::{Deserialize, Serialize};
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::path::{Path, PathBuf};
+
+/// Settings of a node, read from a TOML file. Every setting can also be
+/// given as an environment variable or a command-line flag, which win in
+/// that order.
+///
+/// ```toml
+/// data_dir = "data"
+///
+/// [network]
+/// port = 8080
+/// peers = ["127.0.0.1:8081"]
+/// spec = "specs/local.json"
+///
+/// [mining]
+/// miner = "alice"
+/// enabled = true
+///
+/// [rpc]
+/// address = "127.0.0.1:9080"
+///
+/// [logging]
+/// level = "info"
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct NodeConfig {
+    /// Where the chain is kept. Without one a node keeps nothing on disk.
+    pub data_dir: Option<PathBuf>,
+    pub network: NetworkConfig,
+    pub mining: MiningConfig,
+    pub rpc: RpcConfig,
+    pub logging: LoggingConfig,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct NetworkConfig {
+    /// Address the P2P listener binds to. The default only takes peers on
+    /// this host; `0.0.0.0` takes them from anywhere.
+    pub bind: IpAddr,
+    /// P2P port.
+    pub port: u16,
+    /// Peers to connect to at startup.
+    pub peers: Vec<SocketAddr>,
+    /// Chain spec file. Without one, the spec saved in the data directory
+    /// is used, or else a dev chain with the token below.
+    pub spec: Option<PathBuf>,
+    pub token_name: Option<String>,
+    pub token_symbol: Option<String>,
+}
+
+impl Default for NetworkConfig {
+    fn default() -> NetworkConfig {
+        NetworkConfig {
+            bind: IpAddr::V4(Ipv4Addr::LOCALHOST),
+            port: 8080,
+            peers: Vec::new(),
+            spec: None,
+            token_name: None,
+            token_symbol: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MiningConfig {
+    /// Address that receives block rewards.
+    pub miner: Option<String>,
+    /// Start mining right away, at most one block every `block_time`
+    /// seconds of the spec.
+    pub enabled: bool,
+    /// Difficulty to mine at. Defaults to the spec's.
+    pub difficulty: Option<u32>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RpcConfig {
+    /// Serve JSON-RPC, REST and WebSocket subscriptions.
+    pub enabled: bool,
+    /// Defaults to 127.0.0.1 on the P2P port plus 1000.
+    pub address: Option<SocketAddr>,
+}
+
+impl Default for RpcConfig {
+    fn default() -> RpcConfig {
+        RpcConfig {
+            enabled: true,
+            address: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LoggingConfig {
+    /// `off`, `error`, `warn`, `info`, `debug` or `trace`.
+    pub level: String,
+}
+
+impl Default for LoggingConfig {
+    fn default() -> LoggingConfig {
+        LoggingConfig {
+            level: String::from("info"),
+        }
+    }
+}
+
+/// Everything wrong with a configuration, so it can be fixed in one go.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConfigError(pub Vec<String>);
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "invalid configuration:")?;
+        for problem in &self.0 {
+            write!(f, "\n  - {}", problem)?;
+        }
+        Ok(())
+    }
+}
+
+impl Error for ConfigError {}
+
+impl NodeConfig {
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<NodeConfig, Box<dyn Error>> {
+        let path = path.as_ref();
+        let contents = fs::read_to_string(path)
+            .map_err(|e| format!("cannot read {}: {}", path.display(), e))?;
+        toml::from_str(&contents).map_err(|e| format!("{}: {}", path.display(), e).into())
+    }
+
+    pub fn log_level(&self) -> Option<LevelFilter> {
+        self.logging.level.parse().ok()
+    }
+
+    /// Where the API listens, if it is enabled.
+    pub fn p2p_address(&self) -> SocketAddr {
+        SocketAddr::new(self.network.bind, self.network.port)
+    }
+
+    pub fn rpc_address(&self) -> Option<SocketAddr> {
+        if !self.rpc.enabled {
+            return None;
+        }
+        self.rpc.address.or_else(|| {
+            let port = self.network.port.checked_add(1000)?;
+            Some(SocketAddr::from(([127, 0, 0, 1], port)))
+        })
+    }
+
+    /// Checks the settings that can be checked without starting anything.
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        let mut problems = Vec::new();
+        if self.network.port == 0 {
+            problems.push(String::from("network.port must not be 0"));
+        }
+        if let Some(spec) = &self.network.spec {
+            if !spec.is_file() {
+                problems.push(format!("network.spec {} does not exist", spec.display()));
+            }
+        }
+        if self
+            .network
+            .token_name
+            .as_ref()
+            .is_some_and(|name| name.trim().is_empty())
+        {
+            problems.push(String::from("network.token_name must not be empty"));
+        }
+        if let Some(symbol) = &self.network.token_symbol {
+            if !token::is_valid_symbol(symbol) {
+                problems.push(format!(
+                    "network.token_symbol {} must be 1 to {} capital letters or digits",
+                    symbol,
+                    token::MAX_SYMBOL_LEN
+                ));
+            }
+        }
+        if self
+            .mining
+            .miner
+            .as_ref()
+            .is_some_and(|miner| miner.trim().is_empty())
+        {
+            problems.push(String::from("mining.miner must not be empty"));
+        }
+        if self.mining.difficulty.is_some_and(|d| d > MAX_DIFFICULTY) {
+            problems.push(format!(
+                "mining.difficulty must be at most {}",
+                MAX_DIFFICULTY
+            ));
+        }
+        if self.rpc.enabled && self.rpc_address().is_none() {
+            problems.push(String::from(
+                "rpc.address must be set when network.port is above 64535",
+            ));
+        }
+        if self.log_level().is_none() {
+            problems.push(format!(
+                "logging.level {} must be one of off, error, warn, info, debug, trace",
+                self.logging.level
+            ));
+        }
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            Err(ConfigError(problems))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(toml: &str) -> Result<NodeConfig, toml::de::Error> {
+        toml::from_str(toml)
+    }
+
+    #[test]
+    fn fills_in_defaults() {
+        let config = parse("[mining]\nminer = \"alice\"\n").unwrap();
+        assert_eq!(config.network, NetworkConfig::default());
+        assert_eq!(config.mining.miner.as_deref(), Some("alice"));
+        assert!(!config.mining.enabled);
+        assert!(config.rpc.enabled);
+        assert_eq!(config.log_level(), Some(LevelFilter::Info));
+        assert_eq!(config.validate(), Ok(()));
+        assert_eq!(parse("").unwrap(), NodeConfig::default());
+    }
+
+    #[test]
+    fn rejects_unknown_settings() {
+        assert!(parse("data_directory = \"data\"").is_err());
+        assert!(parse("[network]\nprot = 8080").is_err());
+        assert!(parse("[network]\nport = \"8080\"").is_err());
+    }
+
+    #[test]
+    fn serves_the_api_above_the_p2p_port() {
+        let mut config = NodeConfig::default();
+        assert_eq!(config.rpc_address(), "127.0.0.1:9080".parse().ok());
+        config.rpc.address = "0.0.0.0:7000".parse().ok();
+        assert_eq!(config.rpc_address(), "0.0.0.0:7000".parse().ok());
+        config.rpc.enabled = false;
+        assert_eq!(config.rpc_address(), None);
+    }
+
+    #[test]
+    fn takes_peers_on_localhost_unless_bound_elsewhere() {
+        assert_eq!(
+            NodeConfig::default().p2p_address(),
+            "127.0.0.1:8080".parse().unwrap()
+        );
+        let config = parse("[network]\nbind = \"0.0.0.0\"\nport = 7000\n").unwrap();
+        assert_eq!(config.p2p_address(), "0.0.0.0:7000".parse().unwrap());
+        assert_eq!(config.rpc_address(), "127.0.0.1:8000".parse().ok());
+    }
+
+    #[test]
+    fn reports_every_problem_at_once() {
+        let config = parse(
+            r#"
+            [network]
+            port = 65000
+            token_symbol = "bad symbol"
+
+            [mining]
+            miner = " "
+            difficulty = 65
+
+            [logging]
+            level = "loud"
+            "#,
+        )
+        .unwrap();
+        let ConfigError(problems) = config.validate().unwrap_err();
+        let fields: Vec<_> = problems
+            .iter()
+            .map(|problem| problem.split(' ').next().unwrap())
+            .collect();
+        assert_eq!(
+            fields,
+            [
+                "network.token_symbol",
+                "mining.miner",
+                "mining.difficulty",
+                "rpc.address",
+                "logging.level"
+            ]
+        );
+    }
+}
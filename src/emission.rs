@@ -0,0 +1,141 @@
+use crate::amount::Amount;
+use serde::{Deserialize, Serialize};
+
+pub const INITIAL_REWARD: u64 = 420;
+pub const HALVING_INTERVAL: u64 = 1_000;
+pub const MAX_SUPPLY: u64 = 1_000_000;
+
+/// Consensus rules for how much a block's coinbase may mint.
+///
+/// The reward for block `h` (h >= 1) is `initial_reward` halved once every
+/// `halving_interval` blocks, never dropping below `tail_emission`. Genesis
+/// allocations plus all block rewards can never exceed `max_supply`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RewardSchedule {
+    pub initial_reward: Amount,
+    pub halving_interval: u64,
+    pub tail_emission: Amount,
+    pub max_supply: Amount,
+}
+
+impl Default for RewardSchedule {
+    fn default() -> Self {
+        RewardSchedule {
+            initial_reward: Amount::from_base_units(INITIAL_REWARD),
+            halving_interval: HALVING_INTERVAL,
+            tail_emission: Amount::ZERO,
+            max_supply: Amount::from_base_units(MAX_SUPPLY),
+        }
+    }
+}
+
+impl RewardSchedule {
+    /// Reward for the block at `height` before the supply cap is applied.
+    pub fn base_reward(&self, height: u64) -> Amount {
+        if height == 0 {
+            return Amount::ZERO;
+        }
+        let halvings = (height - 1) / self.halving_interval.max(1);
+        let halved = if halvings >= 64 {
+            0
+        } else {
+            self.initial_reward.base_units() >> halvings
+        };
+        Amount::from_base_units(halved).max(self.tail_emission)
+    }
+
+    /// Most that blocks `1..=height` can have minted in total, ignoring the cap.
+    /// Saturates at `u64::MAX` base units.
+    fn scheduled_emission(&self, height: u64) -> u64 {
+        let interval = self.halving_interval.max(1);
+        let mut total: u64 = 0;
+        let mut start = 1;
+        while start <= height {
+            let reward = self.base_reward(start);
+            if reward == self.tail_emission {
+                // Constant from here on.
+                let blocks = height - start + 1;
+                return total.saturating_add(blocks.saturating_mul(reward.base_units()));
+            }
+            let end = (start + interval - 1).min(height);
+            let blocks = end - start + 1;
+            total = total.saturating_add(blocks.saturating_mul(reward.base_units()));
+            start = end + 1;
+        }
+        total
+    }
+
+    /// Maximum coinbase amount for the block at `height`, given the supply
+    /// already `allocated` in genesis.
+    pub fn reward_at(&self, height: u64, allocated: Amount) -> Amount {
+        if height == 0 {
+            return Amount::ZERO;
+        }
+        let cap = self
+            .max_supply
+            .checked_sub(allocated)
+            .unwrap_or(Amount::ZERO)
+            .base_units();
+        let before = self.scheduled_emission(height - 1).min(cap);
+        self.base_reward(height)
+            .min(Amount::from_base_units(cap - before))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::units;
+
+    fn schedule(initial: u64, interval: u64, tail: u64, max: u64) -> RewardSchedule {
+        RewardSchedule {
+            initial_reward: units(initial),
+            halving_interval: interval,
+            tail_emission: units(tail),
+            max_supply: units(max),
+        }
+    }
+
+    #[test]
+    fn halves_every_interval_down_to_the_tail() {
+        let schedule = schedule(100, 10, 0, u64::MAX);
+        assert_eq!(schedule.base_reward(0), Amount::ZERO);
+        assert_eq!(schedule.base_reward(1), units(100));
+        assert_eq!(schedule.base_reward(10), units(100));
+        assert_eq!(schedule.base_reward(11), units(50));
+        assert_eq!(schedule.base_reward(21), units(25));
+        assert_eq!(schedule.base_reward(10 * 64 + 1), Amount::ZERO);
+
+        let schedule = RewardSchedule {
+            tail_emission: units(20),
+            ..schedule
+        };
+        assert_eq!(schedule.base_reward(21), units(25));
+        assert_eq!(schedule.base_reward(31), units(20));
+        assert_eq!(schedule.base_reward(u64::MAX), units(20));
+    }
+
+    #[test]
+    fn stops_at_the_supply_cap() {
+        // 105 left after genesis: ten blocks of 10, one of 5, then nothing.
+        let schedule = schedule(10, 10, 5, 200);
+        assert_eq!(schedule.reward_at(0, units(95)), Amount::ZERO);
+        assert_eq!(schedule.reward_at(1, units(95)), units(10));
+        assert_eq!(schedule.reward_at(10, units(95)), units(10));
+        assert_eq!(schedule.reward_at(11, units(95)), units(5));
+        assert_eq!(schedule.reward_at(12, units(95)), Amount::ZERO);
+        assert_eq!(schedule.reward_at(1, units(200)), Amount::ZERO);
+        assert_eq!(schedule.reward_at(1, units(300)), Amount::ZERO);
+    }
+
+    #[test]
+    fn total_emission_never_exceeds_the_cap() {
+        let schedule = schedule(7, 3, 1, 60);
+        let allocated = units(20);
+        let minted: u64 = (1..100)
+            .map(|height| schedule.reward_at(height, allocated).base_units())
+            .sum();
+        assert_eq!(minted, 40);
+        assert!(schedule.reward_at(u64::MAX, allocated).is_zero());
+    }
+}
@@ -0,0 +1,45 @@
+use crate::blockchain::{BlockHeader, Transaction};
+use serde::{Deserialize, Serialize};
+use tokio::sync::broadcast;
+
+/// Events a slow subscriber may fall behind by before it starts missing
+/// them.
+pub const CAPACITY: usize = 256;
+
+pub type EventSender = broadcast::Sender<ChainEvent>;
+
+/// Something that happened to the chain, for API subscribers.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ChainEvent {
+    /// A block became the tip.
+    NewHead {
+        height: u64,
+        hash: String,
+        header: BlockHeader,
+        transactions: Vec<String>,
+    },
+    /// A transaction entered the pending pool.
+    PendingTransaction {
+        id: String,
+        transaction: Box<Transaction>,
+    },
+    /// Blocks after `fork_height` were replaced. Hashes are in chain order;
+    /// a `new_head` follows for each connected block.
+    Reorg {
+        fork_height: u64,
+        disconnected: Vec<String>,
+        connected: Vec<String>,
+    },
+    /// A transaction involving `address` was queued (`block_height` is
+    /// `None`) or included in a block.
+    AddressActivity {
+        address: String,
+        tx_id: String,
+        block_height: Option<u64>,
+    },
+}
+
+pub fn channel() -> EventSender {
+    broadcast::channel(CAPACITY).0
+}
@@ -0,0 +1,65 @@
+use crate::amount::Amount;
+use rand_core::{OsRng, RngCore};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use utoipa::ToSchema;
+
+/// Funds locked by an HTLC transaction. `receiver` can claim them by
+/// revealing the preimage of `hash_lock` before `timeout_height`; from that
+/// height on, `sender` can take them back instead.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Htlc {
+    pub sender: String,
+    pub receiver: String,
+    pub amount: Amount,
+    /// Hex-encoded sha256 of the secret.
+    pub hash_lock: String,
+    pub timeout_height: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+pub enum HtlcAction {
+    /// Moves `amount` from `sender` into a new HTLC for `receiver`. The
+    /// transaction's id becomes the HTLC id.
+    Lock {
+        hash_lock: String,
+        timeout_height: u64,
+    },
+    /// Pays the HTLC out to its receiver, revealing the hex-encoded secret.
+    Claim { htlc_id: String, preimage: String },
+    /// Pays an expired HTLC back to its sender.
+    Refund { htlc_id: String },
+}
+
+/// Generates a random secret, returned as hex-encoded (preimage, hash).
+pub fn new_secret() -> (String, String) {
+    let mut preimage = [0u8; 32];
+    OsRng.fill_bytes(&mut preimage);
+    let preimage = hex::encode(preimage);
+    let hash = hash_secret(&preimage).expect("Freshly encoded secret is valid hex");
+    (preimage, hash)
+}
+
+/// Hash lock for a hex-encoded preimage, or `None` if it is not valid hex.
+pub fn hash_secret(preimage: &str) -> Option<String> {
+    let bytes = hex::decode(preimage).ok()?;
+    Some(hex::encode(Sha256::digest(bytes)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn secrets_hash_to_their_lock() {
+        let (preimage, hash) = new_secret();
+        assert_eq!(preimage.len(), 64);
+        assert_eq!(hash_secret(&preimage), Some(hash.clone()));
+        assert_ne!(new_secret().1, hash);
+        assert_eq!(
+            hash_secret("").as_deref(),
+            Some("e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855")
+        );
+        assert_eq!(hash_secret("not hex"), None);
+    }
+}
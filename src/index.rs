@@ -0,0 +1,252 @@
+use crate::amount::Amount;
+use crate::blockchain::Block;
+use crate::state::TxReceipt;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use utoipa::ToSchema;
+
+/// A native (`None`) or issued token balance of an account.
+type BalanceKey = (String, Option<String>);
+
+/// Where a transaction sits in the chain.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+pub struct TxLocation {
+    pub tx_id: String,
+    pub block_height: u64,
+    pub index: u32,
+}
+
+/// A credit or debit of one balance, with the balance after it.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+pub struct LedgerEntry {
+    pub tx_id: String,
+    pub block_height: u64,
+    pub index: u32,
+    /// Timestamp of the block, in milliseconds.
+    pub timestamp: i64,
+    pub credit: Amount,
+    pub debit: Amount,
+    pub balance: Amount,
+}
+
+/// One page of a longer list, with the length of the whole list.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Page<T> {
+    pub items: Vec<T>,
+    pub total: usize,
+}
+
+/// What indexing a block added, so it can be taken out again.
+#[derive(Debug, Default)]
+struct IndexedBlock {
+    hash: String,
+    addresses: Vec<String>,
+    balances: Vec<BalanceKey>,
+}
+
+/// Lookups over the connected chain that would otherwise need a scan of
+/// every block: blocks by hash, transactions by address, and the ledger of
+/// every change to every balance. Transactions by id are found through
+/// their receipts in the state.
+#[derive(Debug, Default)]
+pub struct ChainIndex {
+    blocks: Vec<IndexedBlock>,
+    by_hash: HashMap<String, u64>,
+    by_address: HashMap<String, Vec<TxLocation>>,
+    ledgers: HashMap<BalanceKey, Vec<LedgerEntry>>,
+}
+
+impl ChainIndex {
+    /// Indexes the block at the next height. `receipts` are those of its
+    /// transactions, in order.
+    pub fn connect(&mut self, hash: String, block: &Block, receipts: &[TxReceipt]) {
+        let height = self.blocks.len() as u64;
+        let mut indexed = IndexedBlock {
+            hash: hash.clone(),
+            ..IndexedBlock::default()
+        };
+        for (tx, receipt) in block.transactions().iter().zip(receipts) {
+            let mut addresses: Vec<&str> = vec![&tx.sender, &tx.receiver];
+            for change in &receipt.balance_changes {
+                addresses.push(&change.account);
+                let key = (change.account.clone(), change.token.clone());
+                self.ledgers
+                    .entry(key.clone())
+                    .or_default()
+                    .push(LedgerEntry {
+                        tx_id: receipt.tx_id.clone(),
+                        block_height: height,
+                        index: receipt.index,
+                        timestamp: block.header().timestamp(),
+                        credit: change.after.checked_sub(change.before).unwrap_or_default(),
+                        debit: change.before.checked_sub(change.after).unwrap_or_default(),
+                        balance: change.after,
+                    });
+                if !indexed.balances.contains(&key) {
+                    indexed.balances.push(key);
+                }
+            }
+            addresses.retain(|address| !address.is_empty());
+            addresses.sort();
+            addresses.dedup();
+            for address in addresses {
+                self.by_address
+                    .entry(address.to_string())
+                    .or_default()
+                    .push(TxLocation {
+                        tx_id: receipt.tx_id.clone(),
+                        block_height: height,
+                        index: receipt.index,
+                    });
+                if !indexed.addresses.iter().any(|a| a == address) {
+                    indexed.addresses.push(address.to_string());
+                }
+            }
+        }
+        self.by_hash.insert(hash, height);
+        self.blocks.push(indexed);
+    }
+
+    /// Removes the most recently indexed block.
+    pub fn disconnect(&mut self) {
+        let Some(indexed) = self.blocks.pop() else {
+            return;
+        };
+        let height = self.blocks.len() as u64;
+        self.by_hash.remove(&indexed.hash);
+        for address in indexed.addresses {
+            if let Some(history) = self.by_address.get_mut(&address) {
+                while history.last().is_some_and(|l| l.block_height == height) {
+                    history.pop();
+                }
+                if history.is_empty() {
+                    self.by_address.remove(&address);
+                }
+            }
+        }
+        for key in indexed.balances {
+            if let Some(ledger) = self.ledgers.get_mut(&key) {
+                while ledger.last().is_some_and(|e| e.block_height == height) {
+                    ledger.pop();
+                }
+                if ledger.is_empty() {
+                    self.ledgers.remove(&key);
+                }
+            }
+        }
+    }
+
+    pub fn height_of(&self, hash: &str) -> Option<u64> {
+        self.by_hash.get(hash).copied()
+    }
+
+    /// Transactions that involve `address`, newest first.
+    pub fn address_history(&self, address: &str, offset: usize, limit: usize) -> Page<TxLocation> {
+        let history = self.by_address.get(address).map_or(&[][..], |h| &h[..]);
+        Page {
+            items: history
+                .iter()
+                .rev()
+                .skip(offset)
+                .take(limit)
+                .cloned()
+                .collect(),
+            total: history.len(),
+        }
+    }
+
+    /// Every change to the balance of `account` in `token` (`None` for the
+    /// native token), oldest first.
+    pub fn ledger(&self, account: &str, token: Option<&str>) -> &[LedgerEntry] {
+        let key = (account.to_string(), token.map(str::to_string));
+        self.ledgers.get(&key).map_or(&[], |ledger| &ledger[..])
+    }
+
+    /// Balance of `account` in `token` after the block at `height`, or
+    /// `None` if it never had one by then.
+    pub fn balance_at(&self, account: &str, token: Option<&str>, height: u64) -> Option<Amount> {
+        let ledger = self.ledger(account, token);
+        let count = ledger.partition_point(|entry| entry.block_height <= height);
+        count.checked_sub(1).map(|i| ledger[i].balance)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::blockchain::Chain;
+    use crate::spec::LedgerModel;
+    use crate::testing::{self, units};
+
+    fn send(chain: &mut Chain, receiver: &str, amount: u64) -> String {
+        chain
+            .new_transaction("alice".into(), receiver.into(), units(amount))
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn pages_through_history_newest_first() {
+        let (mut chain, _p2p_rx) = testing::chain(LedgerModel::Account, &[("alice", 100)]);
+        let first = send(&mut chain, "bob", 10);
+        assert!(chain.generate_new_block());
+        let second = send(&mut chain, "bob", 20);
+        let third = send(&mut chain, "carol", 5);
+        assert!(chain.generate_new_block());
+
+        let page = chain.address_history("alice", 0, 2);
+        assert_eq!(page.total, 4);
+        let ids: Vec<_> = page.items.iter().map(|l| l.tx_id.as_str()).collect();
+        assert_eq!(ids, [third.as_str(), second.as_str()]);
+        let page = chain.address_history("alice", 2, 10);
+        assert_eq!(page.items.len(), 2);
+        assert_eq!(page.items[0].tx_id, first);
+        assert_eq!(page.items[1].block_height, 0);
+        assert!(chain.address_history("alice", 10, 10).items.is_empty());
+        assert_eq!(chain.address_history("dave", 0, 10).total, 0);
+    }
+
+    #[tokio::test]
+    async fn keeps_a_ledger_of_every_balance() {
+        let (mut chain, _p2p_rx) = testing::chain(LedgerModel::Account, &[("alice", 100)]);
+        send(&mut chain, "bob", 10);
+        assert!(chain.generate_new_block());
+        send(&mut chain, "bob", 20);
+        assert!(chain.generate_new_block());
+
+        let ledger = chain.account_ledger("alice", None);
+        let entries: Vec<_> = ledger
+            .iter()
+            .map(|e| (e.block_height, e.credit, e.debit, e.balance))
+            .collect();
+        assert_eq!(
+            entries,
+            [
+                (0, units(100), units(0), units(100)),
+                (1, units(0), units(10), units(90)),
+                (2, units(0), units(20), units(70)),
+            ]
+        );
+        assert_eq!(chain.balance_at("bob", 0), None);
+        assert_eq!(chain.balance_at("bob", 1), Some(units(10)));
+        assert_eq!(chain.balance_at("bob", 2), Some(units(30)));
+        assert_eq!(chain.balance_at("bob", 3), None);
+    }
+
+    #[tokio::test]
+    async fn forgets_blocks_that_are_reorganized_away() {
+        let (mut chain, _p2p_rx) = testing::chain(LedgerModel::Account, &[("alice", 100)]);
+        let (mut longer, _longer_rx) = testing::chain(LedgerModel::Account, &[("alice", 100)]);
+        send(&mut chain, "bob", 10);
+        assert!(chain.generate_new_block());
+        let replaced = chain.last_hash();
+        assert!(longer.generate_new_block());
+        assert!(longer.generate_new_block());
+
+        assert!(chain.resolve_conflict(longer.get_chain()));
+        assert_eq!(chain.block_height(&replaced), None);
+        assert_eq!(chain.block_height(&longer.last_hash()), Some(2));
+        assert_eq!(chain.address_history("bob", 0, 10).total, 0);
+        assert!(chain.account_ledger("bob", None).is_empty());
+        assert_eq!(chain.account_ledger("alice", None).len(), 1);
+        assert_eq!(chain.balance_at("bob", 1), None);
+    }
+}
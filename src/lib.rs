@@ -0,0 +1,26 @@
+pub mod amount;
+pub mod api;
+pub mod blockchain;
+pub mod cli;
+pub mod config;
+pub mod emission;
+pub mod events;
+pub mod htlc;
+pub mod index;
+pub mod mining;
+pub mod multisig;
+pub mod node;
+pub mod p2p;
+pub mod rest;
+pub mod rpc;
+pub mod smt;
+pub mod spec;
+pub mod state;
+pub mod store;
+pub mod swap;
+#[cfg(test)]
+mod testing;
+pub mod token;
+pub mod utxo;
+pub mod vm;
+pub mod ws;
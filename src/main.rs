@@ -1,25 +1,27 @@
+use ::blockchain::blockchain::MAX_DIFFICULTY;
+use ::blockchain::cli::{self, Cli, Command};
+use ::blockchain::config::NodeConfig;
+use ::blockchain::events::ChainEvent;
+use ::blockchain::mining::{HashRate, Miner, MinerStatus};
+use ::blockchain::{api, blockchain, node::Node, p2p, spec::ChainSpec, store::Store, swap};
+use clap::Parser;
 use crossterm::{
     event::{self, DisableMouseCapture, EnableMouseCapture, Event, KeyCode},
     execute,
     terminal::{disable_raw_mode, enable_raw_mode, EnterAlternateScreen, LeaveAlternateScreen},
 };
-use log::LevelFilter;
-use std::{
-    error::Error,
-    io::{self, Write},
-};
+use log::info;
+use std::{error::Error, io, sync::Arc, time::Duration};
 use tokio::sync::mpsc;
 use tui::{
     backend::{Backend, CrosstermBackend},
     layout::{Constraint, Direction, Layout},
     style::{Color, Modifier, Style},
-    widgets::{Block, Borders, List, ListItem, ListState, Paragraph},
+    text::{Span, Spans},
+    widgets::{Block, Borders, List, ListItem, ListState, Paragraph, Row, Sparkline, Table, Tabs},
     Terminal,
 };
 
-mod blockchain;
-mod p2p;
-
 struct StatefulList<T> {
     state: ListState,
     items: Vec<T>,
@@ -27,10 +29,9 @@ struct StatefulList<T> {
 
 impl<T> StatefulList<T> {
     fn with_items(items: Vec<T>) -> StatefulList<T> {
-        StatefulList {
-            state: ListState::default(),
-            items,
-        }
+        let mut state = ListState::default();
+        state.select((!items.is_empty()).then_some(0));
+        StatefulList { state, items }
     }
 
     fn next(&mut self) {
@@ -62,32 +63,612 @@ impl<T> StatefulList<T> {
     }
 }
 
-enum InputMode {
-    Normal,
-    Editing,
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Action {
+    NewTransaction,
+    MineBlock,
+    ToggleMining,
+    CreateAccount,
+    CheckBalance,
+    ChangeDifficulty,
+    ChangeReward,
+    FindBlock,
+}
+
+impl Action {
+    const ALL: [Action; 8] = [
+        Action::NewTransaction,
+        Action::MineBlock,
+        Action::ToggleMining,
+        Action::CreateAccount,
+        Action::CheckBalance,
+        Action::ChangeDifficulty,
+        Action::ChangeReward,
+        Action::FindBlock,
+    ];
+
+    fn label(self) -> &'static str {
+        match self {
+            Action::NewTransaction => "New Transaction",
+            Action::MineBlock => "Mine Block",
+            Action::ToggleMining => "Start/Stop Mining",
+            Action::CreateAccount => "Create Account",
+            Action::CheckBalance => "Check Balance",
+            Action::ChangeDifficulty => "Change Difficulty",
+            Action::ChangeReward => "Change Reward",
+            Action::FindBlock => "Find Block",
+        }
+    }
+
+    /// What the action's form asks for, in order.
+    fn fields(self) -> &'static [&'static str] {
+        match self {
+            Action::NewTransaction => &["Sender", "Receiver", "Amount"],
+            Action::MineBlock | Action::ToggleMining => &[],
+            Action::CreateAccount => &["Account name"],
+            Action::CheckBalance => &["Account"],
+            Action::ChangeDifficulty => &["Difficulty"],
+            Action::ChangeReward => &["Reward per block"],
+            Action::FindBlock => &["Height or hash"],
+        }
+    }
+}
+
+/// A menu action being filled in, one field at a time.
+struct Form {
+    action: Action,
+    values: Vec<String>,
+}
+
+impl Form {
+    fn prompt(&self) -> String {
+        let fields = self.action.fields();
+        format!(
+            "{}: {} ({}/{}, Esc to cancel)",
+            self.action.label(),
+            fields[self.values.len()],
+            self.values.len() + 1,
+            fields.len()
+        )
+    }
+}
+
+/// The panel that arrow keys and Enter act on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Focus {
+    Menu,
+    Dashboard,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Tab {
+    Blocks,
+    Peers,
+    Mempool,
+    Mining,
+}
+
+impl Tab {
+    const ALL: [Tab; 4] = [Tab::Blocks, Tab::Peers, Tab::Mempool, Tab::Mining];
+
+    fn title(self) -> &'static str {
+        match self {
+            Tab::Blocks => "Blocks",
+            Tab::Peers => "Peers",
+            Tab::Mempool => "Mempool",
+            Tab::Mining => "Mining",
+        }
+    }
+
+    /// The tab `step` places to the right, wrapping around.
+    fn cycle(self, step: isize) -> Tab {
+        let index = Tab::ALL.iter().position(|tab| *tab == self).unwrap_or(0);
+        let len = Tab::ALL.len() as isize;
+        Tab::ALL[(index as isize + step).rem_euclid(len) as usize]
+    }
 }
 
 struct App {
     input: String,
-    input_mode: InputMode,
+    /// The form being filled in; the input box edits its current field.
+    form: Option<Form>,
     messages: Vec<String>,
-    chain: blockchain::Chain,
-    p2p: p2p::P2p,
-    menu: StatefulList<String>,
+    node: Node,
+    miner: Miner,
+    menu: StatefulList<Action>,
+    focus: Focus,
+    tab: Tab,
+    /// Height of the block highlighted in the blocks panel. `None` keeps
+    /// the newest block highlighted as blocks come in.
+    block: Option<u64>,
+    /// Whether the highlighted block is opened up in the blocks panel.
+    inspecting: bool,
+    detail_scroll: u16,
+}
+
+impl App {
+    fn select(&mut self) {
+        let action = self.menu.items[self.menu.state.selected().unwrap_or(0)];
+        self.open(action);
+    }
+
+    fn open(&mut self, action: Action) {
+        if action.fields().is_empty() {
+            self.finish(action, &[]);
+        } else {
+            self.form = Some(Form {
+                action,
+                values: Vec::new(),
+            });
+        }
+    }
+
+    /// Moves the blocks panel's highlight `older` blocks down the chain, or
+    /// up it if negative.
+    fn move_block(&mut self, older: i64) {
+        let tip = self.node.chain().get_chain().len() as u64 - 1;
+        let height = self.block.unwrap_or(tip) as i64 - older;
+        let height = height.clamp(0, tip as i64) as u64;
+        self.block = (height < tip).then_some(height);
+    }
+
+    fn inspect(&mut self, height: u64) {
+        self.block = Some(height);
+        self.inspecting = true;
+        self.detail_scroll = 0;
+        self.focus = Focus::Dashboard;
+        self.tab = Tab::Blocks;
+    }
+
+    /// Takes the input as the current field of the form, and runs the
+    /// action once every field is filled in. Invalid input stays in the
+    /// box to be corrected.
+    fn submit_input(&mut self) {
+        let Some(form) = &self.form else {
+            return;
+        };
+        let (action, index) = (form.action, form.values.len());
+        let value = self.input.trim().to_string();
+        if let Err(e) = self.check_field(action, index, &value) {
+            self.messages.push(format!("Error: {}", e));
+            return;
+        }
+        self.input.clear();
+        let form = self.form.as_mut().expect("Form is open");
+        form.values.push(value);
+        if form.values.len() == action.fields().len() {
+            let form = self.form.take().expect("Form is open");
+            self.finish(action, &form.values);
+        }
+    }
+
+    fn cancel(&mut self) {
+        if let Some(form) = self.form.take() {
+            self.messages
+                .push(format!("{} cancelled", form.action.label()));
+        }
+        self.input.clear();
+    }
+
+    fn finish(&mut self, action: Action, values: &[String]) {
+        let message = match self.perform(action, values) {
+            Ok(message) => message,
+            Err(e) => format!("Error: {}", e),
+        };
+        self.messages.push(message);
+    }
+
+    /// Checks one field as soon as it is entered.
+    fn check_field(&self, action: Action, index: usize, value: &str) -> Result<(), String> {
+        if value.is_empty() {
+            return Err(format!("{} must not be empty", action.fields()[index]));
+        }
+        let chain = self.node.chain();
+        match (action, index) {
+            (Action::NewTransaction, 0) if chain.get_balance(value).is_none() => {
+                Err(format!("Unknown account {}", value))
+            }
+            (Action::NewTransaction, 2) | (Action::ChangeReward, 0) => chain
+                .parse_amount(value)
+                .map(|_| ())
+                .map_err(|e| e.to_string()),
+            (Action::ChangeDifficulty, 0) => {
+                let minimum = chain.get_spec().difficulty;
+                match value.parse::<u32>() {
+                    Ok(difficulty) if (minimum..=MAX_DIFFICULTY).contains(&difficulty) => Ok(()),
+                    _ => Err(format!(
+                        "Difficulty must be a number from {} to {}",
+                        minimum, MAX_DIFFICULTY
+                    )),
+                }
+            }
+            (Action::FindBlock, 0) if find_block(&chain, value).is_none() => {
+                Err(format!("No block with height or hash {}", value))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Runs a filled-in action and describes what happened.
+    fn perform(&mut self, action: Action, values: &[String]) -> Result<String, String> {
+        let mut chain = self.node.chain();
+        match action {
+            Action::NewTransaction => {
+                let (sender, receiver) = (&values[0], &values[1]);
+                let amount = chain.parse_amount(&values[2]).map_err(|e| e.to_string())?;
+                if let Some(balance) = chain.get_balance(sender).filter(|b| *b < amount) {
+                    return Err(format!(
+                        "{} only has {}",
+                        sender,
+                        chain.format_amount(balance)
+                    ));
+                }
+                let id = chain
+                    .new_transaction(sender.clone(), receiver.clone(), amount)
+                    .ok_or("Transaction rejected")?;
+                Ok(format!(
+                    "Queued {} from {} to {} ({})",
+                    chain.format_amount(amount),
+                    sender,
+                    receiver,
+                    &id[..16]
+                ))
+            }
+            Action::MineBlock => {
+                if !self.miner.start(Some(1)) {
+                    return Err(String::from("The miner is already running"));
+                }
+                Ok(format!("Mining block {}", chain.get_chain().len()))
+            }
+            Action::ToggleMining => {
+                if self.miner.status().running {
+                    self.miner.stop();
+                    return Ok(String::from("Mining stopped"));
+                }
+                self.miner.start(None);
+                Ok(format!(
+                    "Mining started, at most one block every {} s",
+                    chain.get_spec().block_time.max(1)
+                ))
+            }
+            Action::CreateAccount => {
+                let name = &values[0];
+                if !chain.create_account(name.clone()) {
+                    return Err(format!("Account {} already exists", name));
+                }
+                Ok(format!("Created account {}", name))
+            }
+            Action::CheckBalance => {
+                let account = &values[0];
+                let balance = chain
+                    .get_balance(account)
+                    .ok_or_else(|| format!("Unknown account {}", account))?;
+                Ok(format!(
+                    "Balance of {}: {}",
+                    account,
+                    chain.format_amount(balance)
+                ))
+            }
+            Action::ChangeDifficulty => {
+                let difficulty = values[0].parse::<u32>().map_err(|e| e.to_string())?;
+                if !chain.update_difficulty(difficulty) {
+                    return Err(format!("Difficulty {} is out of range", difficulty));
+                }
+                Ok(format!("Difficulty set to {}", difficulty))
+            }
+            Action::ChangeReward => {
+                let reward = chain.parse_amount(&values[0]).map_err(|e| e.to_string())?;
+                if !chain.update_reward(reward) {
+                    let max = chain.block_reward(chain.get_chain().len());
+                    return Err(format!(
+                        "Reward must be at most {}",
+                        chain.format_amount(max)
+                    ));
+                }
+                Ok(format!(
+                    "Block reward set to {}",
+                    chain.format_amount(reward)
+                ))
+            }
+            Action::FindBlock => {
+                let height = find_block(&chain, &values[0])
+                    .ok_or_else(|| format!("No block with height or hash {}", values[0]))?;
+                drop(chain);
+                self.inspect(height);
+                Ok(format!("Showing block {}", height))
+            }
+        }
+    }
+}
+
+/// Height of the block `id` names: a height, or else a header hash.
+fn find_block(chain: &blockchain::Chain, id: &str) -> Option<u64> {
+    match id.parse::<u64>() {
+        Ok(height) => chain.get_block(height).map(|_| height),
+        Err(_) => chain.block_height(id),
+    }
+}
+
+fn format_time(timestamp: i64) -> String {
+    chrono::DateTime::from_timestamp_millis(timestamp)
+        .map(|time| time.format("%Y-%m-%d %H:%M:%S").to_string())
+        .unwrap_or_else(|| timestamp.to_string())
+}
+
+/// A transaction's amount, in the native token or the token it moves.
+fn format_tx_amount(chain: &blockchain::Chain, tx: &blockchain::Transaction) -> String {
+    let Some(symbol) = &tx.token else {
+        return chain.format_amount(tx.amount);
+    };
+    let decimals = tx
+        .issue
+        .as_ref()
+        .map(|issue| issue.decimals)
+        .or_else(|| chain.get_token(symbol).map(|token| token.decimals))
+        .unwrap_or(0);
+    format!("{} {}", tx.amount.format(decimals), symbol)
+}
+
+/// Cells of the peers table, by address.
+fn peer_rows(peers: &p2p::PeerList) -> Vec<[String; 4]> {
+    let peers = peers.lock().expect("Peer list lock poisoned");
+    let mut peers: Vec<_> = peers.values().collect();
+    peers.sort_by_key(|peer| peer.addr);
+    peers
+        .into_iter()
+        .map(|peer| {
+            [
+                peer.addr.to_string(),
+                format!("{:?}", peer.direction),
+                peer.latency_ms
+                    .map_or_else(|| String::from("-"), |ms| format!("{} ms", ms)),
+                peer.height.to_string(),
+            ]
+        })
+        .collect()
+}
+
+/// Cells of the mempool table, oldest first. The fee is the most a
+/// transaction can burn, since gas is only known once it runs.
+fn mempool_rows(chain: &blockchain::Chain) -> Vec<[String; 6]> {
+    chain
+        .pending_transactions()
+        .iter()
+        .map(|tx| {
+            let fee = tx.max_fee();
+            [
+                tx.id()[..16].to_string(),
+                format!("{:?}", tx.kind),
+                tx.sender.clone(),
+                tx.receiver.clone(),
+                format_tx_amount(chain, tx),
+                if fee.is_zero() {
+                    String::from("-")
+                } else {
+                    format!("up to {}", chain.format_amount(fee))
+                },
+            ]
+        })
+        .collect()
+}
+
+/// One line per block for the blocks panel, newest first.
+fn block_rows(chain: &blockchain::Chain) -> Vec<ListItem<'static>> {
+    chain
+        .get_chain()
+        .iter()
+        .enumerate()
+        .rev()
+        .map(|(height, block)| {
+            let header = block.header();
+            let hash = blockchain::Chain::hash(header).expect("Failed to hash block header");
+            ListItem::new(format!(
+                "{:>6}  {}  {}  {:>3} txs  difficulty {}",
+                height,
+                &hash[..16],
+                format_time(header.timestamp()),
+                block.transactions().len(),
+                header.difficulty()
+            ))
+        })
+        .collect()
+}
+
+fn bold_row<const N: usize>(cells: [&'static str; N]) -> Row<'static> {
+    Row::new(cells).style(Style::default().add_modifier(Modifier::BOLD))
+}
+
+/// Seconds between consecutive blocks, for at most the last `count` blocks.
+/// Genesis is left out: its timestamp comes from the spec.
+fn block_times(chain: &blockchain::Chain, count: usize) -> Vec<u64> {
+    let timestamps: Vec<i64> = chain.get_chain()[1..]
+        .iter()
+        .map(|block| block.header().timestamp())
+        .collect();
+    intervals(&timestamps, count)
+}
+
+/// Whole seconds between consecutive millisecond `timestamps`, the last
+/// `count` of them. Clocks can disagree, so a block older than its parent
+/// counts as 0.
+fn intervals(timestamps: &[i64], count: usize) -> Vec<u64> {
+    let times: Vec<u64> = timestamps
+        .windows(2)
+        .map(|pair| ((pair[1] - pair[0]).max(0) / 1000) as u64)
+        .collect();
+    times[times.len().saturating_sub(count)..].to_vec()
+}
+
+fn format_rate(per_second: f64) -> String {
+    match per_second {
+        r if r >= 1e6 => format!("{:.2} MH/s", r / 1e6),
+        r if r >= 1e3 => format!("{:.2} kH/s", r / 1e3),
+        r => format!("{:.0} H/s", r),
+    }
+}
+
+fn mining_stats(
+    chain: &blockchain::Chain,
+    status: &MinerStatus,
+    rate: Option<HashRate>,
+    times: &[u64],
+) -> Vec<Spans<'static>> {
+    let miner = match (status.running, status.height) {
+        (true, Some(height)) => format!("mining block {}", height),
+        (true, None) => String::from("waiting for the next block"),
+        (false, _) => String::from("stopped"),
+    };
+    let hash_rate = match rate {
+        None => String::from("no block mined yet"),
+        Some(rate) if rate.mining => format!(
+            "{} (mining, {} hashes in {:.1} s)",
+            format_rate(rate.per_second()),
+            rate.hashes,
+            rate.elapsed.as_secs_f64()
+        ),
+        Some(rate) => format!(
+            "{} (last search: {} hashes in {:.1} s)",
+            format_rate(rate.per_second()),
+            rate.hashes,
+            rate.elapsed.as_secs_f64()
+        ),
+    };
+    let average = if times.is_empty() {
+        String::from("-")
+    } else {
+        format!(
+            "{:.1} s over {} blocks",
+            times.iter().sum::<u64>() as f64 / times.len() as f64,
+            times.len()
+        )
+    };
+    vec![
+        Spans::from(format!(
+            "Miner:       {} ({} mined, {} abandoned)",
+            miner, status.mined, status.abandoned
+        )),
+        Spans::from(format!("Hash rate:   {}", hash_rate)),
+        Spans::from(format!("Difficulty:  {}", chain.difficulty())),
+        Spans::from(format!("Target time: {} s", chain.get_spec().block_time)),
+        Spans::from(format!("Block time:  {}", average)),
+    ]
+}
+
+/// Header fields and transactions of the block at `height`.
+fn block_detail(chain: &blockchain::Chain, height: u64) -> Vec<Spans<'static>> {
+    let Some(block) = chain.get_block(height) else {
+        return Vec::new();
+    };
+    let header = block.header();
+    let field = |name: &str, value: String| {
+        Spans::from(vec![
+            Span::styled(
+                format!("{:<12}", name),
+                Style::default().add_modifier(Modifier::BOLD),
+            ),
+            Span::raw(value),
+        ])
+    };
+    let mut lines = vec![
+        field("Height", height.to_string()),
+        field(
+            "Hash",
+            blockchain::Chain::hash(header).expect("Failed to hash block header"),
+        ),
+        field("Previous", header.previous_hash().to_string()),
+        field("Merkle root", header.merkle().to_string()),
+        field("State root", header.state_root().to_string()),
+        field("Time", format_time(header.timestamp())),
+        field("Nonce", header.nonce().to_string()),
+        field("Difficulty", header.difficulty().to_string()),
+        Spans::default(),
+        field("Transactions", block.transactions().len().to_string()),
+    ];
+    lines.extend(block.transactions().iter().map(|tx| {
+        let sender = if tx.sender.is_empty() {
+            "-"
+        } else {
+            &tx.sender
+        };
+        Spans::from(format!(
+            "  {}  {:?}  {} -> {}  {}",
+            &tx.id()[..16],
+            tx.kind,
+            sender,
+            tx.receiver,
+            format_tx_amount(chain, tx)
+        ))
+    }));
+    lines
 }
 
 #[tokio::main]
-async fn main() -> Result<(), Box<dyn Error>> {
+async fn main() {
+    if let Err(e) = try_main(Cli::parse()).await {
+        eprintln!("error: {}", e);
+        std::process::exit(1);
+    }
+}
+
+async fn try_main(mut cli: Cli) -> Result<(), Box<dyn Error>> {
+    let command = cli.command.take();
+    let run = match &command {
+        Some(Command::Run(run)) => run.clone(),
+        _ => cli.run.clone(),
+    };
+    let config = cli.node_config(&run)?;
     env_logger::Builder::new()
-        .filter_level(LevelFilter::Info)
+        .filter_level(config.log_level().expect("Validated log level"))
         .init();
+    match command {
+        Some(Command::Run(_)) | None => {}
+        Some(Command::Swap(args)) => {
+            let (first, second) = args.legs();
+            return swap::run(first, second).await;
+        }
+        Some(command) => return cli::execute(command, &config),
+    }
+
+    let store = config.data_dir.as_ref().map(Store::open).transpose()?;
+    let _lock = store.as_ref().map(Store::lock).transpose()?;
+    let network = &config.network;
+    let spec = match (&network.spec, &store) {
+        (Some(path), _) => Some(ChainSpec::load(path)?),
+        (None, Some(store)) => store.load_spec()?,
+        (None, None) => None,
+    };
+    let spec = spec.or_else(|| {
+        if network.token_name.is_none() && network.token_symbol.is_none() {
+            return None;
+        }
+        Some(ChainSpec::dev(
+            network
+                .token_name
+                .clone()
+                .unwrap_or_else(|| String::from("Rust Token")),
+            network
+                .token_symbol
+                .clone()
+                .unwrap_or_else(|| String::from("RST")),
+            config.mining.difficulty.unwrap_or(2),
+        ))
+    });
+
+    let (p2p_tx, mut p2p_rx) = mpsc::channel(100);
 
-    let args: Vec<String> = std::env::args().collect();
-    if args.len() != 2 {
-        eprintln!("Usage: {} <port>", args[0]);
-        return Ok(());
+    if run.headless {
+        let miner_address = config
+            .mining
+            .miner
+            .clone()
+            .ok_or("--headless needs a miner address")?;
+        let spec = spec
+            .ok_or("--headless needs a chain spec, a data directory holding one or a dev token")?;
+        let (node, peers) =
+            start_node(&config, store.as_ref(), miner_address, spec, p2p_tx).await?;
+        run_headless(&node, &peers, &mut p2p_rx, config.mining.enabled).await;
+        return flush(&node, store.as_ref());
     }
-    let port = args[1].parse::<u16>().unwrap();
 
     // setup terminal
     enable_raw_mode()?;
@@ -96,33 +677,29 @@ async fn main() -> Result<(), Box<dyn Error>> {
     let backend = CrosstermBackend::new(stdout);
     let mut terminal = Terminal::new(backend)?;
 
-    let (p2p_tx, mut p2p_rx) = mpsc::channel(100);
-    let p2p = p2p::P2p::new(port, vec![]).await?;
-
-    let (miner_address, difficulty, token_name, token_symbol) = get_initial_setup(&mut terminal).await?;
+    let (miner_address, spec) =
+        get_initial_setup(&mut terminal, config.mining.miner.clone(), spec).await?;
+    let (node, peers) = start_node(&config, store.as_ref(), miner_address, spec, p2p_tx).await?;
 
-    let menu_items = vec![
-        "New Transaction".to_string(),
-        "Mine Block".to_string(),
-        "Create Account".to_string(),
-        "Check Balance".to_string(),
-    ];
+    let miner = new_miner(&node);
+    if config.mining.enabled {
+        miner.start(None);
+    }
     let mut app = App {
         input: String::new(),
-        input_mode: InputMode::Normal,
+        form: None,
         messages: Vec::new(),
-        chain: blockchain::Chain::new(
-            miner_address,
-            difficulty,
-            token_name,
-            token_symbol,
-            p2p_tx.clone(),
-        ),
-        p2p,
-        menu: StatefulList::with_items(menu_items),
+        node,
+        miner,
+        menu: StatefulList::with_items(Action::ALL.to_vec()),
+        focus: Focus::Menu,
+        tab: Tab::Blocks,
+        block: None,
+        inspecting: false,
+        detail_scroll: 0,
     };
 
-    let res = run_app(&mut terminal, &mut app, &mut p2p_rx).await;
+    let res = run_app(&mut terminal, &mut app, &peers, &mut p2p_rx).await;
 
     // restore terminal
     disable_raw_mode()?;
@@ -137,14 +714,153 @@ async fn main() -> Result<(), Box<dyn Error>> {
         println!("{:?}", err)
     }
 
-    Ok(())
+    app.miner.stop();
+    app.miner.join().await;
+    flush(&app.node, store.as_ref())
+}
+
+/// Creates the chain, restores it from the data directory and starts P2P
+/// and the API servers. Messages from peers go to `p2p_tx`; the chain's
+/// own blocks and transactions go out to the peers.
+async fn start_node(
+    config: &NodeConfig,
+    store: Option<&Store>,
+    miner_address: String,
+    spec: ChainSpec,
+    p2p_tx: mpsc::Sender<p2p::P2pMessage>,
+) -> Result<(Node, p2p::P2pHandle), Box<dyn Error>> {
+    spec.validate()?;
+    if let Some(store) = store {
+        if store.load_spec()?.is_some_and(|saved| saved != spec) {
+            return Err(format!("{} holds a different chain", store.dir().display()).into());
+        }
+        store.save_spec(&spec)?;
+    }
+    let (outgoing_tx, mut outgoing_rx) = mpsc::channel::<p2p::P2pMessage>(100);
+    let mut chain = blockchain::Chain::new(spec, miner_address, outgoing_tx);
+    if let Some(store) = store {
+        store.load(&mut chain)?;
+    }
+    if let Some(difficulty) = config.mining.difficulty {
+        if !chain.update_difficulty(difficulty) {
+            return Err(format!(
+                "mining.difficulty must be from {} to {}",
+                chain.get_spec().difficulty,
+                MAX_DIFFICULTY
+            )
+            .into());
+        }
+    }
+
+    let handshake = p2p::Handshake {
+        network_id: chain.get_spec().network_id.clone(),
+        genesis_hash: chain.genesis_hash(),
+        height: 0,
+    };
+    let peers = config.network.peers.clone();
+    let mut p2p = p2p::P2p::new(config.p2p_address(), peers, handshake).await?;
+    let node = Node::new(chain, p2p.peer_list());
+    let height_node = node.clone();
+    p2p.set_height_source(Arc::new(move || {
+        height_node.chain().get_chain().len() as u64 - 1
+    }));
+    let peers = p2p.handle();
+    let outgoing = peers.clone();
+    tokio::spawn(async move {
+        while let Some(p2p_message) = outgoing_rx.recv().await {
+            outgoing.broadcast(p2p_message.message);
+        }
+    });
+    tokio::spawn(async move { p2p.run(p2p_tx).await });
+
+    if let Some(api_addr) = config.rpc_address() {
+        let api_node = node.clone();
+        tokio::spawn(async move {
+            if let Err(e) = api::serve(api_addr, api_node).await {
+                log::warn!("API server stopped: {}", e);
+            }
+        });
+    }
+    Ok((node, peers))
+}
+
+/// Runs until SIGINT or SIGTERM, applying what peers send. With `mine`,
+/// mines a block every `block_time` seconds.
+async fn run_headless(
+    node: &Node,
+    peers: &p2p::P2pHandle,
+    p2p_rx: &mut mpsc::Receiver<p2p::P2pMessage>,
+    mine: bool,
+) {
+    let miner = new_miner(node);
+    if mine {
+        miner.start(None);
+    }
+    let shutdown = shutdown_signal();
+    tokio::pin!(shutdown);
+    info!("Running headless. Stop with Ctrl-C or SIGTERM.");
+    loop {
+        tokio::select! {
+            _ = &mut shutdown => {
+                info!("Shutting down.");
+                break;
+            }
+            Some(p2p_message) = p2p_rx.recv() => {
+                p2p::receive(node, peers, p2p_message);
+            }
+        }
+    }
+    // The block being mined is abandoned; its transactions stay pending.
+    miner.stop();
+    miner.join().await;
+}
+
+/// A miner that keeps to the spec's block time when mining continuously.
+fn new_miner(node: &Node) -> Miner {
+    let block_time = node.chain().get_spec().block_time.max(1);
+    Miner::new(node.clone(), Duration::from_secs(block_time))
+}
+
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        let mut terminate = signal(SignalKind::terminate()).expect("Failed to listen for SIGTERM");
+        tokio::select! {
+            _ = tokio::signal::ctrl_c() => {}
+            _ = terminate.recv() => {}
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = tokio::signal::ctrl_c().await;
+}
+
+/// Saves the chain to the data directory, if there is one.
+fn flush(node: &Node, store: Option<&Store>) -> Result<(), Box<dyn Error>> {
+    match store {
+        Some(store) => store.save(&node.chain()),
+        None => Ok(()),
+    }
 }
 
 async fn run_app<B: Backend>(
     terminal: &mut Terminal<B>,
     app: &mut App,
+    peers: &p2p::P2pHandle,
     p2p_rx: &mut mpsc::Receiver<p2p::P2pMessage>,
 ) -> Result<(), Box<dyn Error>> {
+    // Read keys on their own thread: a read abandoned when a P2P message
+    // wins the select below would swallow the next key.
+    let (key_tx, mut key_rx) = mpsc::unbounded_channel();
+    std::thread::spawn(move || {
+        while let Ok(event) = event::read() {
+            if key_tx.send(event).is_err() {
+                break;
+            }
+        }
+    });
+    let mut refresh = tokio::time::interval(Duration::from_secs(1));
+    let mut events = app.node.subscribe();
     loop {
         terminal.draw(|f| {
             let chunks = Layout::default()
@@ -161,10 +877,22 @@ async fn run_app<B: Backend>(
                 .menu
                 .items
                 .iter()
-                .map(|i| ListItem::new(i.clone()))
+                .map(|action| ListItem::new(action.label()))
                 .collect();
+            let focused = |focus| {
+                if app.focus == focus {
+                    Style::default().fg(Color::Cyan)
+                } else {
+                    Style::default()
+                }
+            };
             let menu = List::new(menu_items)
-                .block(Block::default().borders(Borders::ALL).title("Menu"))
+                .block(
+                    Block::default()
+                        .borders(Borders::ALL)
+                        .border_style(focused(Focus::Menu))
+                        .title("Menu"),
+                )
                 .highlight_style(
                     Style::default()
                         .bg(Color::Blue)
@@ -173,18 +901,125 @@ async fn run_app<B: Backend>(
                 .highlight_symbol("> ");
             f.render_stateful_widget(menu, left_chunks[0], &mut app.menu.state);
 
+            let (input_style, input_title) = match &app.form {
+                Some(form) => (Style::default().fg(Color::Yellow), form.prompt()),
+                None => (Style::default(), String::from("Input")),
+            };
             let input = Paragraph::new(app.input.as_ref())
-                .style(match app.input_mode {
-                    InputMode::Normal => Style::default(),
-                    InputMode::Editing => Style::default().fg(Color::Yellow),
-                })
-                .block(Block::default().borders(Borders::ALL).title("Input"));
+                .style(input_style)
+                .block(Block::default().borders(Borders::ALL).title(input_title));
             f.render_widget(input, left_chunks[1]);
 
+            let right_chunks = Layout::default()
+                .direction(Direction::Vertical)
+                .constraints(
+                    [
+                        Constraint::Length(3),
+                        Constraint::Percentage(60),
+                        Constraint::Percentage(40),
+                    ]
+                    .as_ref(),
+                )
+                .split(chunks[1]);
+
+            let titles = Tab::ALL
+                .iter()
+                .map(|tab| Spans::from(tab.title()))
+                .collect();
+            let tabs = Tabs::new(titles)
+                .select(Tab::ALL.iter().position(|tab| *tab == app.tab).unwrap_or(0))
+                .block(
+                    Block::default()
+                        .borders(Borders::ALL)
+                        .border_style(focused(Focus::Dashboard))
+                        .title("Dashboard (Tab to focus, Left/Right to switch)"),
+                )
+                .highlight_style(
+                    Style::default()
+                        .fg(Color::Yellow)
+                        .add_modifier(Modifier::BOLD),
+                );
+            f.render_widget(tabs, right_chunks[0]);
+
+            let panel = Block::default()
+                .borders(Borders::ALL)
+                .border_style(focused(Focus::Dashboard));
+            let chain = app.node.chain();
+            match app.tab {
+                Tab::Blocks => {
+                    let tip = chain.get_chain().len() as u64 - 1;
+                    let height = app.block.unwrap_or(tip).min(tip);
+                    if app.inspecting {
+                        let detail = Paragraph::new(block_detail(&chain, height))
+                            .scroll((app.detail_scroll, 0))
+                            .block(panel.title(format!("Block {} (Esc to go back)", height)));
+                        f.render_widget(detail, right_chunks[1]);
+                    } else {
+                        let mut state = ListState::default();
+                        state.select(Some((tip - height) as usize));
+                        let blocks = List::new(block_rows(&chain))
+                            .block(panel.title("Blocks (Enter to open, / to find)"))
+                            .highlight_style(Style::default().add_modifier(Modifier::REVERSED));
+                        f.render_stateful_widget(blocks, right_chunks[1], &mut state);
+                    }
+                }
+                Tab::Peers => {
+                    let peers = peer_rows(app.node.peers());
+                    let count = peers.len();
+                    let table = Table::new(peers.into_iter().map(Row::new))
+                        .header(bold_row(["Address", "Direction", "Latency", "Height"]))
+                        .widths(&[
+                            Constraint::Length(22),
+                            Constraint::Length(10),
+                            Constraint::Length(9),
+                            Constraint::Length(8),
+                        ])
+                        .block(panel.title(format!("Peers ({})", count)));
+                    f.render_widget(table, right_chunks[1]);
+                }
+                Tab::Mempool => {
+                    let pending = mempool_rows(&chain);
+                    let count = pending.len();
+                    let table = Table::new(pending.into_iter().map(Row::new))
+                        .header(bold_row(["Id", "Kind", "From", "To", "Amount", "Fee"]))
+                        .widths(&[
+                            Constraint::Length(16),
+                            Constraint::Length(11),
+                            Constraint::Percentage(15),
+                            Constraint::Percentage(15),
+                            Constraint::Percentage(20),
+                            Constraint::Percentage(20),
+                        ])
+                        .block(panel.title(format!("Mempool ({} pending)", count)));
+                    f.render_widget(table, right_chunks[1]);
+                }
+                Tab::Mining => {
+                    let area = panel.inner(right_chunks[1]);
+                    f.render_widget(panel.title("Mining"), right_chunks[1]);
+                    let rows = Layout::default()
+                        .direction(Direction::Vertical)
+                        .constraints([Constraint::Length(6), Constraint::Min(1)].as_ref())
+                        .split(area);
+                    let times = block_times(&chain, rows[1].width as usize);
+                    let rate = app.node.hash_meter().read();
+                    let stats = mining_stats(&chain, &app.miner.status(), rate, &times);
+                    f.render_widget(Paragraph::new(stats), rows[0]);
+                    let sparkline = Sparkline::default()
+                        .block(Block::default().title("Block times (s), oldest to newest"))
+                        .data(&times)
+                        .style(Style::default().fg(Color::Green));
+                    f.render_widget(sparkline, rows[1]);
+                }
+            }
+            drop(chain);
+
+            // Keep the newest messages in view.
+            let visible = right_chunks[2].height.saturating_sub(2) as usize;
             let messages: Vec<ListItem> = app
                 .messages
                 .iter()
                 .enumerate()
+                .skip(app.messages.len().saturating_sub(visible))
                 .map(|(i, m)| {
                     let content = format!("{}: {}", i, m);
                     ListItem::new(content)
@@ -192,50 +1027,72 @@ async fn run_app<B: Backend>(
                 .collect();
             let messages =
                 List::new(messages).block(Block::default().borders(Borders::ALL).title("Messages"));
-            f.render_widget(messages, chunks[1]);
+            f.render_widget(messages, right_chunks[2]);
         })?;
 
         tokio::select! {
-            key_event = tokio::task::spawn_blocking(event::read) => {
-                if let Ok(Event::Key(key)) = key_event? {
-                    match app.input_mode {
-                        InputMode::Normal => match key.code {
-                            KeyCode::Char('q') => {
+            Some(event) = key_rx.recv() => {
+                if let Event::Key(key) = event {
+                    if app.form.is_none() {
+                        match (app.focus, key.code) {
+                            (_, KeyCode::Char('q')) => {
                                 return Ok(());
                             }
-                            KeyCode::Down => {
+                            (_, KeyCode::Tab) => {
+                                app.focus = match app.focus {
+                                    Focus::Menu => Focus::Dashboard,
+                                    Focus::Dashboard => Focus::Menu,
+                                };
+                            }
+                            (_, KeyCode::Char('/')) => {
+                                app.open(Action::FindBlock);
+                            }
+                            (Focus::Dashboard, KeyCode::Left) => {
+                                app.tab = app.tab.cycle(-1);
+                            }
+                            (Focus::Dashboard, KeyCode::Right) => {
+                                app.tab = app.tab.cycle(1);
+                            }
+                            (Focus::Menu, KeyCode::Down) => {
                                 app.menu.next();
                             }
-                            KeyCode::Up => {
+                            (Focus::Menu, KeyCode::Up) => {
                                 app.menu.previous();
                             }
-                            KeyCode::Enter => {
-                                app.input_mode = InputMode::Editing;
+                            (Focus::Menu, KeyCode::Enter) => {
+                                app.select();
+                            }
+                            (Focus::Dashboard, KeyCode::Down) if app.inspecting && app.tab == Tab::Blocks => {
+                                app.detail_scroll = app.detail_scroll.saturating_add(1);
+                            }
+                            (Focus::Dashboard, KeyCode::Up) if app.inspecting && app.tab == Tab::Blocks => {
+                                app.detail_scroll = app.detail_scroll.saturating_sub(1);
+                            }
+                            (Focus::Dashboard, KeyCode::Esc) if app.tab == Tab::Blocks => {
+                                app.inspecting = false;
+                            }
+                            (Focus::Dashboard, KeyCode::Down) if app.tab == Tab::Blocks => {
+                                app.move_block(1);
+                            }
+                            (Focus::Dashboard, KeyCode::Up) if app.tab == Tab::Blocks => {
+                                app.move_block(-1);
+                            }
+                            (Focus::Dashboard, KeyCode::PageDown) if app.tab == Tab::Blocks => {
+                                app.move_block(10);
+                            }
+                            (Focus::Dashboard, KeyCode::PageUp) if app.tab == Tab::Blocks => {
+                                app.move_block(-10);
+                            }
+                            (Focus::Dashboard, KeyCode::Enter) if app.tab == Tab::Blocks => {
+                                let tip = app.node.chain().get_chain().len() as u64 - 1;
+                                app.inspect(app.block.unwrap_or(tip));
                             }
                             _ => {}
-                        },
-                        InputMode::Editing => match key.code {
+                        }
+                    } else {
+                        match key.code {
                             KeyCode::Enter => {
-                                let selected = app.menu.state.selected().unwrap_or(0);
-                                let action = app.menu.items[selected].clone();
-                                match action.as_str() {
-                                    "New Transaction" => {
-                                        app.messages.push("New Transaction selected".to_string());
-                                    }
-                                    "Mine Block" => {
-                                        app.chain.generate_new_block();
-                                        app.messages.push("New block mined".to_string());
-                                    }
-                                    "Create Account" => {
-                                        app.messages.push("Create Account selected".to_string());
-                                    }
-                                    "Check Balance" => {
-                                        app.messages.push("Check Balance selected".to_string());
-                                    }
-                                    _ => {}
-                                }
-                                app.input.clear();
-                                app.input_mode = InputMode::Normal;
+                                app.submit_input();
                             }
                             KeyCode::Char(c) => {
                                 app.input.push(c);
@@ -244,27 +1101,53 @@ async fn run_app<B: Backend>(
                                 app.input.pop();
                             }
                             KeyCode::Esc => {
-                                app.input_mode = InputMode::Normal;
+                                app.cancel();
                             }
                             _ => {}
-                        },
+                        }
                     }
                 }
             }
             Some(p2p_message) = p2p_rx.recv() => {
-                app.messages.push(format!("{:?}", p2p_message));
+                app.messages.push(format!(
+                    "Received {} from {}",
+                    p2p_message.message, p2p_message.sender
+                ));
+                p2p::receive(&app.node, peers, p2p_message);
+            }
+            event = events.recv() => {
+                match event {
+                    Ok(ChainEvent::NewHead { height, hash, .. }) => {
+                        app.messages.push(format!("New block {} ({})", height, &hash[..16]));
+                    }
+                    Ok(ChainEvent::Reorg { fork_height, disconnected, .. }) => {
+                        app.messages.push(format!(
+                            "Reorg: {} blocks after {} replaced",
+                            disconnected.len(),
+                            fork_height
+                        ));
+                    }
+                    // Falling behind only costs some messages.
+                    _ => {}
+                }
             }
+            // Redraw now and then, so peers and the hash rate stay current.
+            _ = refresh.tick() => {}
         }
     }
 }
 
+/// Prompts for the miner address unless one was given and, when no chain
+/// spec was found, for the settings of a throwaway dev chain.
 async fn get_initial_setup<B: Backend>(
     terminal: &mut Terminal<B>,
-) -> Result<(String, u32, String, String), Box<dyn Error>> {
-    let mut miner_address = String::new();
-    let mut difficulty_str = String::new();
-    let mut token_name = String::new();
-    let mut token_symbol = String::new();
+    miner_address: Option<String>,
+    spec: Option<ChainSpec>,
+) -> Result<(String, ChainSpec), Box<dyn Error>> {
+    let mut miner_address = miner_address.unwrap_or_default();
+    let difficulty_str: String;
+    let token_name: String;
+    let token_symbol: String;
 
     let mut input = String::new();
 
@@ -273,8 +1156,11 @@ async fn get_initial_setup<B: Backend>(
             loop {
                 terminal.draw(|f| {
                     let size = f.size();
-                    let input_panel = Paragraph::new(format!("{}\n> {}", $prompt, input))
-                        .block(Block::default().borders(Borders::ALL).title("Initial Setup"));
+                    let input_panel = Paragraph::new(format!("{}\n> {}", $prompt, input)).block(
+                        Block::default()
+                            .borders(Borders::ALL)
+                            .title("Initial Setup"),
+                    );
                     f.render_widget(input_panel, size);
                 })?;
                 if let Event::Key(key) = event::read()? {
@@ -297,12 +1183,119 @@ async fn get_initial_setup<B: Backend>(
         };
     }
 
-    get_input!("Enter miner address:", miner_address);
+    if miner_address.is_empty() {
+        get_input!("Enter miner address:", miner_address);
+    }
+    if let Some(spec) = spec {
+        return Ok((miner_address, spec));
+    }
     get_input!("Enter difficulty:", difficulty_str);
     get_input!("Enter token name:", token_name);
     get_input!("Enter token symbol:", token_symbol);
 
     let difficulty = difficulty_str.trim().parse::<u32>()?;
 
-    Ok((miner_address, difficulty, token_name, token_symbol))
+    Ok((
+        miner_address,
+        ChainSpec::dev(token_name, token_symbol, difficulty),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ::blockchain::amount::Amount;
+    use ::blockchain::p2p::{PeerInfo, PeerList};
+    use ::blockchain::spec::Allocation;
+    use ::blockchain::vm::Op;
+
+    fn chain() -> (blockchain::Chain, mpsc::Receiver<p2p::P2pMessage>) {
+        let mut spec = ChainSpec::dev(String::from("Test"), String::from("TST"), 1);
+        spec.allocations.push(Allocation {
+            address: String::from("alice"),
+            amount: Amount::from_base_units(100),
+        });
+        let (p2p_tx, p2p_rx) = mpsc::channel(64);
+        let chain = blockchain::Chain::new(spec, String::from("miner"), p2p_tx);
+        (chain, p2p_rx)
+    }
+
+    #[test]
+    fn lists_peers_by_address() {
+        let peers = PeerList::default();
+        for (port, direction, latency_ms) in [
+            (9002, p2p::Direction::Inbound, None),
+            (9001, p2p::Direction::Outbound, Some(12)),
+        ] {
+            let addr = ([127, 0, 0, 1], port).into();
+            let peer = PeerInfo {
+                addr,
+                direction,
+                height: port as u64 - 9000,
+                latency_ms,
+            };
+            peers.lock().unwrap().insert(addr, peer);
+        }
+        assert_eq!(
+            peer_rows(&peers),
+            [
+                ["127.0.0.1:9001", "Outbound", "12 ms", "1"].map(String::from),
+                ["127.0.0.1:9002", "Inbound", "-", "2"].map(String::from),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn shows_the_most_a_pending_transaction_can_burn() {
+        let (mut chain, _p2p_rx) = chain();
+        let code = vec![Op::Stop];
+        let contract = chain.deploy_contract("alice".into(), code).unwrap();
+        assert!(chain.generate_new_block());
+        let amount = Amount::from_base_units(5);
+        chain.new_transaction("alice".into(), "bob".into(), amount);
+        chain.call_contract("alice".into(), contract, Amount::ZERO, vec![], 50);
+
+        let rows = mempool_rows(&chain);
+        let amounts_and_fees: Vec<(&str, &str)> = rows
+            .iter()
+            .map(|row| (row[4].as_str(), row[5].as_str()))
+            .collect();
+        assert_eq!(
+            amounts_and_fees,
+            [("5 TST", "-"), ("0 TST", "up to 50 TST")]
+        );
+        assert_eq!(rows[1][1], "Contract");
+    }
+
+    #[test]
+    fn formats_hash_rates() {
+        assert_eq!(format_rate(12.4), "12 H/s");
+        assert_eq!(format_rate(1_500.0), "1.50 kH/s");
+        assert_eq!(format_rate(2_500_000.0), "2.50 MH/s");
+
+        let (chain, _p2p_rx) = chain();
+        let rate = HashRate {
+            mining: true,
+            hashes: 3_000,
+            elapsed: Duration::from_secs(2),
+        };
+        let stats = mining_stats(&chain, &MinerStatus::default(), Some(rate), &[4, 8]);
+        let text: Vec<String> = stats
+            .iter()
+            .map(|spans| spans.0.iter().map(|span| span.content.as_ref()).collect())
+            .collect();
+        assert_eq!(
+            text[1],
+            "Hash rate:   1.50 kH/s (mining, 3000 hashes in 2.0 s)"
+        );
+        assert_eq!(text[4], "Block time:  6.0 s over 2 blocks");
+    }
+
+    #[test]
+    fn block_times_are_whole_seconds_between_blocks() {
+        let timestamps = [1_000, 4_000, 3_500, 10_900];
+        assert_eq!(intervals(&timestamps, 10), [3, 0, 7]);
+        assert_eq!(intervals(&timestamps, 2), [0, 7]);
+        assert!(intervals(&timestamps[..1], 10).is_empty());
+    }
 }
@@ -0,0 +1,352 @@
+use crate::events::ChainEvent;
+use crate::node::Node;
+use chrono::Utc;
+use log::info;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread;
+use std::time::{Duration, Instant};
+use tokio::sync::broadcast::{self, error::TryRecvError};
+use tokio::task::JoinHandle;
+
+/// Hashes tried between updates of a `HashMeter`, so the search does not
+/// take a lock for every nonce.
+pub const METER_INTERVAL: u64 = 1024;
+
+/// Counts the hashes tried by proof of work, so the hash rate can be shown
+/// while a block is being mined. Clones share the count.
+#[derive(Debug, Clone, Default)]
+pub struct HashMeter {
+    run: Arc<Mutex<Run>>,
+}
+
+#[derive(Debug, Default)]
+struct Run {
+    started: Option<Instant>,
+    finished: Option<Instant>,
+    hashes: u64,
+}
+
+/// A reading of a `HashMeter`: the running search, or else the last one.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct HashRate {
+    pub mining: bool,
+    pub hashes: u64,
+    pub elapsed: Duration,
+}
+
+impl HashRate {
+    pub fn per_second(&self) -> f64 {
+        let seconds = self.elapsed.as_secs_f64();
+        if seconds > 0.0 {
+            self.hashes as f64 / seconds
+        } else {
+            0.0
+        }
+    }
+}
+
+impl HashMeter {
+    /// Starts counting a new search.
+    pub fn start(&self) {
+        *self.run.lock().expect("Hash meter lock poisoned") = Run {
+            started: Some(Instant::now()),
+            finished: None,
+            hashes: 0,
+        };
+    }
+
+    pub fn add(&self, hashes: u64) {
+        self.run.lock().expect("Hash meter lock poisoned").hashes += hashes;
+    }
+
+    pub fn stop(&self) {
+        self.run.lock().expect("Hash meter lock poisoned").finished = Some(Instant::now());
+    }
+
+    /// `None` until the first search starts.
+    pub fn read(&self) -> Option<HashRate> {
+        let run = self.run.lock().expect("Hash meter lock poisoned");
+        let started = run.started?;
+        let end = run.finished.unwrap_or_else(Instant::now);
+        Some(HashRate {
+            mining: run.finished.is_none(),
+            hashes: run.hashes,
+            elapsed: end.duration_since(started),
+        })
+    }
+}
+
+/// What a `Miner` is up to, for display.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MinerStatus {
+    pub running: bool,
+    /// Height of the block being searched for.
+    pub height: Option<u64>,
+    pub mined: u64,
+    /// Templates given up because the tip moved before they were done.
+    pub abandoned: u64,
+}
+
+enum Attempt {
+    Mined(u64),
+    Abandoned,
+    Cancelled,
+    Failed,
+}
+
+/// Mines on a blocking thread in the background. The chain is only locked
+/// to build a block template and to connect the finished block, so the TUI
+/// and networking keep going while the nonce is searched for; a template is
+/// dropped as soon as another block becomes the tip.
+#[derive(Clone)]
+pub struct Miner {
+    node: Node,
+    /// Shortest time between blocks of a continuous run.
+    pace: Duration,
+    shared: Arc<Shared>,
+}
+
+#[derive(Default)]
+struct Shared {
+    /// Bumped by every start and stop; a worker runs while it is current.
+    run: AtomicU64,
+    status: Mutex<MinerStatus>,
+    worker: Mutex<Option<JoinHandle<()>>>,
+}
+
+impl Miner {
+    pub fn new(node: Node, pace: Duration) -> Miner {
+        Miner {
+            node,
+            pace,
+            shared: Arc::default(),
+        }
+    }
+
+    /// Mines `blocks` blocks, or until stopped if `None`. Continuous runs
+    /// wait until the tip is `pace` old before each block. Returns false if
+    /// the miner is already running.
+    pub fn start(&self, blocks: Option<u64>) -> bool {
+        let mut status = self.status_lock();
+        if status.running {
+            return false;
+        }
+        status.running = true;
+        drop(status);
+        let run = self.shared.run.fetch_add(1, Ordering::SeqCst) + 1;
+        let miner = self.clone();
+        let worker = tokio::task::spawn_blocking(move || miner.work(run, blocks));
+        *self.shared.worker.lock().expect("Miner lock poisoned") = Some(worker);
+        true
+    }
+
+    /// Stops the worker, abandoning the block it is searching for. Its
+    /// transactions stay pending.
+    pub fn stop(&self) {
+        self.shared.run.fetch_add(1, Ordering::SeqCst);
+        let mut status = self.status_lock();
+        status.running = false;
+        status.height = None;
+    }
+
+    /// Waits for a stopped worker to let go of the chain.
+    pub async fn join(&self) {
+        let worker = self
+            .shared
+            .worker
+            .lock()
+            .expect("Miner lock poisoned")
+            .take();
+        if let Some(worker) = worker {
+            let _ = worker.await;
+        }
+    }
+
+    pub fn status(&self) -> MinerStatus {
+        self.status_lock().clone()
+    }
+
+    fn status_lock(&self) -> MutexGuard<'_, MinerStatus> {
+        self.shared.status.lock().expect("Miner lock poisoned")
+    }
+
+    fn is_current(&self, run: u64) -> bool {
+        self.shared.run.load(Ordering::SeqCst) == run
+    }
+
+    fn work(&self, run: u64, mut blocks: Option<u64>) {
+        info!("Miner started.");
+        while self.is_current(run) && blocks != Some(0) {
+            if blocks.is_none() && !self.wait_for_pace(run) {
+                break;
+            }
+            let searching = |height| {
+                let mut status = self.status_lock();
+                if self.is_current(run) {
+                    status.height = height;
+                }
+            };
+            match attempt(&self.node, searching, || !self.is_current(run)) {
+                Attempt::Mined(_) => {
+                    self.status_lock().mined += 1;
+                    blocks = blocks.map(|left| left - 1);
+                }
+                Attempt::Abandoned => self.status_lock().abandoned += 1,
+                Attempt::Cancelled => break,
+                Attempt::Failed if blocks.is_some() => break,
+                // A continuous run tries again later rather than spin.
+                Attempt::Failed => thread::sleep(Duration::from_secs(1)),
+            }
+        }
+        let mut status = self.status_lock();
+        if self.is_current(run) {
+            status.running = false;
+            status.height = None;
+        }
+        info!("Miner stopped.");
+    }
+
+    /// Sleeps until the tip is `pace` old. False if stopped meanwhile.
+    fn wait_for_pace(&self, run: u64) -> bool {
+        loop {
+            if !self.is_current(run) {
+                return false;
+            }
+            let tip = self
+                .node
+                .chain()
+                .get_chain()
+                .last()
+                .map(|block| block.header().timestamp());
+            let age = Utc::now().timestamp_millis() - tip.unwrap_or(0);
+            let left = self.pace.as_millis() as i64 - age;
+            if left <= 0 {
+                return true;
+            }
+            thread::sleep(Duration::from_millis(left.min(100) as u64));
+        }
+    }
+}
+
+/// Mines one block without holding the chain during the search, starting
+/// over whenever the tip moves first. Returns the height of the block, or
+/// `None` if no template could be made.
+pub fn mine_block(node: &Node) -> Option<u64> {
+    loop {
+        match attempt(node, |_| {}, || false) {
+            Attempt::Mined(height) => return Some(height),
+            Attempt::Abandoned | Attempt::Cancelled => continue,
+            Attempt::Failed => return None,
+        }
+    }
+}
+
+/// Builds a template under the chain lock, searches for its nonce without
+/// it and connects the block if it is still on top of the tip.
+fn attempt(
+    node: &Node,
+    mut searching: impl FnMut(Option<u64>),
+    cancelled: impl Fn() -> bool,
+) -> Attempt {
+    let (mut template, mut events) = {
+        let mut chain = node.chain();
+        // Subscribed under the lock, so no new head slips in unseen.
+        let events = node.subscribe();
+        match chain.block_template() {
+            Some(template) => (template, events),
+            None => return Attempt::Failed,
+        }
+    };
+    let height = template.height();
+    searching(Some(height));
+    let mut tip_moved = false;
+    let found = template.search(node.hash_meter(), || {
+        tip_moved = tip_changed(&mut events);
+        tip_moved || cancelled()
+    });
+    searching(None);
+    if !found {
+        if tip_moved {
+            info!(
+                "The tip moved; abandoning the template for block {}",
+                height
+            );
+            return Attempt::Abandoned;
+        }
+        return Attempt::Cancelled;
+    }
+    if node.chain().submit_template(template) {
+        Attempt::Mined(height)
+    } else {
+        Attempt::Abandoned
+    }
+}
+
+/// Whether a block became the tip since the last call. Missed events count
+/// as a new tip.
+fn tip_changed(events: &mut broadcast::Receiver<ChainEvent>) -> bool {
+    loop {
+        match events.try_recv() {
+            Ok(ChainEvent::NewHead { .. } | ChainEvent::Reorg { .. }) => return true,
+            Ok(_) => continue,
+            Err(TryRecvError::Empty | TryRecvError::Closed) => return false,
+            Err(TryRecvError::Lagged(_)) => return true,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::spec::LedgerModel;
+    use crate::testing;
+
+    #[tokio::test]
+    async fn mines_the_blocks_asked_for() {
+        let (node, _p2p_rx) = testing::node(&[("alice", 100)]);
+        let miner = Miner::new(node.clone(), Duration::ZERO);
+        assert!(miner.start(Some(3)));
+        assert!(!miner.start(None));
+        miner.join().await;
+        let status = miner.status();
+        assert!(!status.running);
+        assert_eq!(status.mined, 3);
+        assert_eq!(node.chain().get_chain().len(), 4);
+        assert!(node.hash_meter().read().is_some_and(|rate| !rate.mining));
+    }
+
+    #[tokio::test]
+    async fn abandons_templates_when_a_peer_block_arrives() {
+        let (node, _p2p_rx) = testing::node(&[("alice", 100)]);
+        let (mut peer, _peer_rx) = testing::chain(LedgerModel::Account, &[("alice", 100)]);
+        assert!(peer.generate_new_block());
+        let block = peer.get_block(1).unwrap().clone();
+
+        let mut block = Some(block);
+        let searching = |height: Option<u64>| {
+            if let (Some(1), Some(block)) = (height, block.take()) {
+                node.chain().add_block(block).unwrap();
+            }
+        };
+        assert!(matches!(
+            attempt(&node, searching, || false),
+            Attempt::Abandoned
+        ));
+        assert_eq!(node.chain().last_hash(), peer.last_hash());
+        assert_eq!(mine_block(&node), Some(2));
+    }
+
+    #[tokio::test]
+    async fn notices_new_tips() {
+        let (node, _p2p_rx) = testing::node(&[("alice", 100)]);
+        let mut events = node.subscribe();
+        node.chain()
+            .new_transaction("alice".into(), "bob".into(), testing::units(1))
+            .unwrap();
+        assert!(!tip_changed(&mut events));
+        assert_eq!(mine_block(&node), Some(1));
+        assert!(tip_changed(&mut events));
+        assert!(!tip_changed(&mut events));
+    }
+}
@@ -0,0 +1,217 @@
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
+use rand_core::OsRng;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::HashSet;
+use std::fmt;
+use utoipa::ToSchema;
+
+/// Addresses of multisig accounts start with this, followed by a hash of the
+/// account's keys and threshold.
+pub const MULTISIG_PREFIX: &str = "msig:";
+
+/// An M-of-N account: spending from it needs valid signatures from at least
+/// `threshold` of `public_keys`. Transactions carry the account itself, so
+/// nothing has to be registered on chain beforehand.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+pub struct MultisigAccount {
+    pub threshold: u8,
+    /// Hex-encoded ed25519 public keys, sorted.
+    pub public_keys: Vec<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+pub struct TxSignature {
+    pub public_key: String,
+    pub signature: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MultisigError {
+    InvalidThreshold,
+    InvalidKey(String),
+    DuplicateKey(String),
+}
+
+impl fmt::Display for MultisigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MultisigError::InvalidThreshold => {
+                write!(f, "threshold must be between 1 and the number of keys")
+            }
+            MultisigError::InvalidKey(key) => write!(f, "'{}' is not a valid ed25519 key", key),
+            MultisigError::DuplicateKey(key) => write!(f, "key {} is listed twice", key),
+        }
+    }
+}
+
+impl std::error::Error for MultisigError {}
+
+fn decode_key<const N: usize>(key: &str) -> Result<[u8; N], MultisigError> {
+    hex::decode(key)
+        .ok()
+        .and_then(|bytes| bytes.try_into().ok())
+        .ok_or_else(|| MultisigError::InvalidKey(key.to_string()))
+}
+
+fn verifying_key(key: &str) -> Result<VerifyingKey, MultisigError> {
+    VerifyingKey::from_bytes(&decode_key(key)?)
+        .map_err(|_| MultisigError::InvalidKey(key.to_string()))
+}
+
+impl MultisigAccount {
+    pub fn new(mut public_keys: Vec<String>, threshold: u8) -> Result<Self, MultisigError> {
+        for key in &public_keys {
+            verifying_key(key)?;
+        }
+        public_keys.sort();
+        if let Some(pair) = public_keys.windows(2).find(|pair| pair[0] == pair[1]) {
+            return Err(MultisigError::DuplicateKey(pair[0].clone()));
+        }
+        if threshold == 0 || threshold as usize > public_keys.len() {
+            return Err(MultisigError::InvalidThreshold);
+        }
+        Ok(MultisigAccount {
+            threshold,
+            public_keys,
+        })
+    }
+
+    pub fn address(&self) -> String {
+        let mut hasher = Sha256::new();
+        hasher.update([self.threshold]);
+        for key in &self.public_keys {
+            hasher.update(key.as_bytes());
+        }
+        let hash = hex::encode(hasher.finalize());
+        format!("{}{}", MULTISIG_PREFIX, &hash[..40])
+    }
+
+    pub fn is_multisig_address(address: &str) -> bool {
+        address.starts_with(MULTISIG_PREFIX)
+    }
+
+    /// Whether at least `threshold` distinct member keys signed `message`.
+    pub fn verify(&self, message: &[u8], signatures: &[TxSignature]) -> bool {
+        let mut signers = HashSet::new();
+        for sig in signatures {
+            if !self.public_keys.contains(&sig.public_key) || signers.contains(&sig.public_key) {
+                continue;
+            }
+            let Ok(key) = verifying_key(&sig.public_key) else {
+                continue;
+            };
+            let Ok(bytes) = decode_key::<64>(&sig.signature) else {
+                continue;
+            };
+            if key.verify(message, &Signature::from_bytes(&bytes)).is_ok() {
+                signers.insert(sig.public_key.clone());
+            }
+        }
+        signers.len() >= self.threshold as usize
+    }
+}
+
+/// Generates a new keypair, returned as hex-encoded (secret, public) keys.
+pub fn generate_keypair() -> (String, String) {
+    let signing_key = SigningKey::generate(&mut OsRng);
+    (
+        hex::encode(signing_key.to_bytes()),
+        hex::encode(signing_key.verifying_key().to_bytes()),
+    )
+}
+
+/// Signs `message` with a hex-encoded secret key.
+pub fn sign(secret_key: &str, message: &[u8]) -> Result<TxSignature, MultisigError> {
+    let signing_key = SigningKey::from_bytes(&decode_key(secret_key)?);
+    Ok(TxSignature {
+        public_key: hex::encode(signing_key.verifying_key().to_bytes()),
+        signature: hex::encode(signing_key.sign(message).to_bytes()),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn keypairs(n: usize) -> Vec<(String, String)> {
+        (0..n).map(|_| generate_keypair()).collect()
+    }
+
+    fn account(keys: &[(String, String)], threshold: u8) -> MultisigAccount {
+        let public_keys = keys.iter().map(|(_, public)| public.clone()).collect();
+        MultisigAccount::new(public_keys, threshold).unwrap()
+    }
+
+    fn signatures(keys: &[(String, String)], message: &[u8]) -> Vec<TxSignature> {
+        keys.iter()
+            .map(|(secret, _)| sign(secret, message).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn needs_threshold_distinct_member_signatures() {
+        let keys = keypairs(3);
+        let account = account(&keys, 2);
+        let message = b"transfer";
+        let signed = signatures(&keys, message);
+
+        assert!(!account.verify(message, &[]));
+        assert!(!account.verify(message, &signed[..1]));
+        assert!(account.verify(message, &signed[..2]));
+        assert!(account.verify(message, &signed));
+        // The same signer twice counts once.
+        assert!(!account.verify(message, &[signed[0].clone(), signed[0].clone()]));
+        // Outsiders do not count, even with valid signatures.
+        let outsider = signatures(&keypairs(1), message);
+        assert!(!account.verify(message, &[signed[0].clone(), outsider[0].clone()]));
+    }
+
+    #[test]
+    fn ignores_signatures_of_other_messages() {
+        let keys = keypairs(2);
+        let account = account(&keys, 2);
+        let mut signed = signatures(&keys, b"transfer");
+        assert!(!account.verify(b"other", &signed));
+        signed[1].signature = String::from("not hex");
+        assert!(!account.verify(b"transfer", &signed));
+    }
+
+    #[test]
+    fn validates_keys_and_threshold() {
+        let keys: Vec<String> = keypairs(2).into_iter().map(|(_, public)| public).collect();
+        assert_eq!(
+            MultisigAccount::new(keys.clone(), 0),
+            Err(MultisigError::InvalidThreshold)
+        );
+        assert_eq!(
+            MultisigAccount::new(keys.clone(), 3),
+            Err(MultisigError::InvalidThreshold)
+        );
+        assert_eq!(
+            MultisigAccount::new(vec![keys[0].clone(), keys[0].clone()], 1),
+            Err(MultisigError::DuplicateKey(keys[0].clone()))
+        );
+        assert_eq!(
+            MultisigAccount::new(vec![String::from("abcd")], 1),
+            Err(MultisigError::InvalidKey(String::from("abcd")))
+        );
+    }
+
+    #[test]
+    fn addresses_do_not_depend_on_key_order() {
+        let keys: Vec<String> = keypairs(3).into_iter().map(|(_, public)| public).collect();
+        let mut reversed = keys.clone();
+        reversed.reverse();
+        let account = MultisigAccount::new(keys.clone(), 2).unwrap();
+        assert_eq!(
+            account.address(),
+            MultisigAccount::new(reversed, 2).unwrap().address()
+        );
+        assert_ne!(
+            account.address(),
+            MultisigAccount::new(keys, 3).unwrap().address()
+        );
+        assert!(MultisigAccount::is_multisig_address(&account.address()));
+    }
+}
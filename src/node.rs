@@ -0,0 +1,47 @@
+use crate::blockchain::Chain;
+use crate::events::{ChainEvent, EventSender};
+use crate::mining::HashMeter;
+use crate::p2p::PeerList;
+use std::sync::{Arc, Mutex, MutexGuard};
+use tokio::sync::broadcast;
+
+/// Handles to the parts of a running node, shared between the TUI and the
+/// API servers.
+#[derive(Clone)]
+pub struct Node {
+    chain: Arc<Mutex<Chain>>,
+    peers: PeerList,
+    events: EventSender,
+    hash_meter: HashMeter,
+}
+
+impl Node {
+    pub fn new(chain: Chain, peers: PeerList) -> Node {
+        Node {
+            events: chain.events(),
+            hash_meter: chain.hash_meter(),
+            chain: Arc::new(Mutex::new(chain)),
+            peers,
+        }
+    }
+
+    /// Locks the chain. Keep the guard short-lived; mine with
+    /// `mining::Miner` or `mining::mine_block`, which search without it.
+    pub fn chain(&self) -> MutexGuard<'_, Chain> {
+        self.chain.lock().expect("Chain lock poisoned")
+    }
+
+    pub fn peers(&self) -> &PeerList {
+        &self.peers
+    }
+
+    /// Receives chain events from now on. Does not need the chain lock.
+    pub fn subscribe(&self) -> broadcast::Receiver<ChainEvent> {
+        self.events.subscribe()
+    }
+
+    /// Progress of our proof of work. Does not need the chain lock either.
+    pub fn hash_meter(&self) -> &HashMeter {
+        &self.hash_meter
+    }
+}
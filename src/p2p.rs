@@ -1,92 +1,757 @@
+use crate::blockchain::{Block, Chain, Transaction, TxStatus};
+use crate::node::Node;
 use log::{info, warn};
 use serde::{Deserialize, Serialize};
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::net::{TcpListener, TcpStream};
-use tokio::sync::mpsc;
 use std::collections::HashMap;
-use std::net::SocketAddr;
 use std::error::Error;
-use crate::blockchain::{Block, Transaction};
+use std::fmt;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc;
+use utoipa::ToSchema;
+
+/// Largest message a peer may send. Whole chains go out in one message.
+pub const MAX_FRAME: usize = 64 * 1024 * 1024;
+
+/// Largest frame accepted before the peer's handshake has been checked.
+const MAX_HELLO_FRAME: usize = 4 * 1024;
+
+/// How long either side of a new connection may take over the handshake.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How often each peer is pinged to measure its latency.
+const PING_INTERVAL: Duration = Duration::from_secs(15);
+
+/// Sent by both sides when a connection opens. Peers whose handshake differs
+/// from ours are on another network and get disconnected.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Handshake {
+    pub network_id: String,
+    pub genesis_hash: String,
+    /// Height of the sender's chain. Not part of the match.
+    #[serde(default)]
+    pub height: u64,
+}
+
+impl Handshake {
+    /// Whether `other` comes from a node on our network.
+    pub fn matches(&self, other: &Handshake) -> bool {
+        self.network_id == other.network_id && self.genesis_hash == other.genesis_hash
+    }
+}
 
 #[derive(Debug, Serialize, Deserialize)]
 pub enum Message {
+    Hello(Handshake),
     NewBlock(Block),
-    NewTransaction(Transaction),
-    GetBlocks(SocketAddr),
+    NewTransaction(Box<Transaction>),
+    /// Asks for the whole chain, answered with `Blocks`.
+    GetBlocks,
     Blocks(Vec<Block>),
+    /// Answered with a `Pong` carrying the same value: when the ping was
+    /// sent, in milliseconds on the sender's clock.
+    Ping(u64),
+    Pong(u64),
+}
+
+impl fmt::Display for Message {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Message::Hello(handshake) => write!(f, "hello at height {}", handshake.height),
+            Message::NewBlock(block) => {
+                let hash = Chain::hash(block.header()).unwrap_or_default();
+                write!(f, "block {}", &hash[..hash.len().min(12)])
+            }
+            Message::NewTransaction(tx) => write!(f, "transaction {}", &tx.id()[..12]),
+            Message::GetBlocks => write!(f, "request for blocks"),
+            Message::Blocks(blocks) => write!(f, "{} blocks", blocks.len()),
+            Message::Ping(_) => write!(f, "ping"),
+            Message::Pong(_) => write!(f, "pong"),
+        }
+    }
+}
+
+/// Which side opened a connection.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum Direction {
+    Inbound,
+    Outbound,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+pub struct PeerInfo {
+    #[schema(value_type = String)]
+    pub addr: SocketAddr,
+    pub direction: Direction,
+    /// Chain height the peer last showed us: at the handshake, then with
+    /// each block or chain it sends.
+    pub height: u64,
+    /// Round trip of the last ping, or of the handshake for connections we
+    /// opened, in milliseconds. `None` until the first is answered.
+    pub latency_ms: Option<u64>,
 }
 
+/// Peers we currently have a connection with, shared with the rest of the
+/// node.
+pub type PeerList = Arc<Mutex<HashMap<SocketAddr, PeerInfo>>>;
+
+/// A message with the peer it came from. On the wire `sender` is the
+/// sender's listening address; once received it is replaced by the
+/// connection the message arrived on, which is where replies go.
 #[derive(Debug, Serialize, Deserialize)]
 pub struct P2pMessage {
     pub sender: SocketAddr,
     pub message: Message,
 }
 
-pub struct Peer {
+/// Serializes `message` as a frame: its length as a big-endian `u32`, then
+/// the JSON.
+fn frame(message: &P2pMessage) -> io::Result<Vec<u8>> {
+    let json = serde_json::to_vec(message)?;
+    if json.len() > MAX_FRAME {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "message too large",
+        ));
+    }
+    let mut frame = Vec::with_capacity(4 + json.len());
+    frame.extend_from_slice(&(json.len() as u32).to_be_bytes());
+    frame.extend_from_slice(&json);
+    Ok(frame)
+}
+
+async fn write_frame<W: AsyncWrite + Unpin>(
+    writer: &mut W,
+    message: &P2pMessage,
+) -> io::Result<()> {
+    writer.write_all(&frame(message)?).await
+}
+
+/// Reads one frame of at most `limit` bytes. `None` if the connection was
+/// closed between frames.
+async fn read_frame<R: AsyncRead + Unpin>(
+    reader: &mut R,
+    limit: usize,
+) -> io::Result<Option<P2pMessage>> {
+    let mut length = [0; 4];
+    match reader.read_exact(&mut length).await {
+        Ok(_) => {}
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e),
+    }
+    let length = u32::from_be_bytes(length) as usize;
+    if length > limit {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "frame too large",
+        ));
+    }
+    let mut json = vec![0; length];
+    reader.read_exact(&mut json).await?;
+    Ok(Some(serde_json::from_slice(&json)?))
+}
+
+/// The write side of each connection, fed whole frames.
+type Writers = Arc<Mutex<HashMap<SocketAddr, mpsc::UnboundedSender<Arc<Vec<u8>>>>>>;
+
+/// Sends messages to connected peers. Clones share the connections.
+#[derive(Clone)]
+pub struct P2pHandle {
+    local_addr: SocketAddr,
+    writers: Writers,
+}
+
+impl P2pHandle {
+    /// Sends `message` to every connected peer.
+    pub fn broadcast(&self, message: Message) {
+        let Some(frame) = self.frame(message) else {
+            return;
+        };
+        for writer in self.writers().values() {
+            let _ = writer.send(frame.clone());
+        }
+    }
+
+    /// Sends `message` to the peer connected at `addr`, if it still is.
+    pub fn send(&self, addr: SocketAddr, message: Message) {
+        let Some(frame) = self.frame(message) else {
+            return;
+        };
+        if let Some(writer) = self.writers().get(&addr) {
+            let _ = writer.send(frame);
+        }
+    }
+
+    fn frame(&self, message: Message) -> Option<Arc<Vec<u8>>> {
+        let message = P2pMessage {
+            sender: self.local_addr,
+            message,
+        };
+        match frame(&message) {
+            Ok(frame) => Some(Arc::new(frame)),
+            Err(e) => {
+                warn!("Failed to send {}: {}", message.message, e);
+                None
+            }
+        }
+    }
+
+    fn writers(&self) -> MutexGuard<'_, HashMap<SocketAddr, mpsc::UnboundedSender<Arc<Vec<u8>>>>> {
+        self.writers.lock().expect("Peer writers lock poisoned")
+    }
+}
+
+/// Applies a message from a peer to the chain. Blocks on top of our tip
+/// are connected; any other unknown block makes us fetch the sender's
+/// chain, which replaces ours if it has more work. New transactions go to
+/// the pending pool. Whatever we accept is passed on to our other peers.
+pub fn receive(node: &Node, peers: &P2pHandle, message: P2pMessage) {
+    let P2pMessage { sender, message } = message;
+    let mut chain = node.chain();
+    match message {
+        Message::Hello(_) | Message::Ping(_) | Message::Pong(_) => {}
+        Message::NewBlock(block) => {
+            let hash = Chain::hash(block.header()).expect("Failed to hash block header");
+            if let Some(height) = chain.block_height(&hash) {
+                peer_reached(node, sender, height);
+                return;
+            }
+            if block.header().previous_hash() != chain.last_hash() {
+                info!(
+                    "Block {} from {} is not on our tip; asking for its chain",
+                    hash, sender
+                );
+                peers.send(sender, Message::GetBlocks);
+                return;
+            }
+            match chain.add_block(block) {
+                Ok(()) => {
+                    info!("Added block {} from {}", hash, sender);
+                    peer_reached(node, sender, chain.get_chain().len() as u64 - 1);
+                }
+                Err(e) => info!("Rejected block {} from {}: {}", hash, sender, e),
+            }
+        }
+        Message::NewTransaction(tx) => {
+            if matches!(chain.transaction_status(&tx.id()), TxStatus::Unknown) {
+                chain.submit_transaction(*tx);
+            }
+        }
+        Message::GetBlocks => peers.send(sender, Message::Blocks(chain.get_chain().to_vec())),
+        Message::Blocks(blocks) => {
+            peer_reached(node, sender, blocks.len().saturating_sub(1) as u64);
+            if chain.resolve_conflict(&blocks) {
+                info!(
+                    "Switched to the chain of {} at height {}",
+                    sender,
+                    chain.get_chain().len() - 1
+                );
+                if let Some(tip) = chain.get_chain().last() {
+                    peers.broadcast(Message::NewBlock(tip.clone()));
+                }
+            }
+        }
+    }
+}
+
+/// Records that the chain of the peer at `addr` has reached `height`.
+fn peer_reached(node: &Node, addr: SocketAddr, height: u64) {
+    let mut peers = node.peers().lock().expect("Peer list lock poisoned");
+    if let Some(peer) = peers.get_mut(&addr) {
+        peer.height = height;
+    }
+}
+
+/// Connects to `addr` and exchanges handshakes, failing if the peer is on
+/// a different network or genesis, or takes longer than
+/// `HANDSHAKE_TIMEOUT` to answer.
+async fn connect(
+    addr: SocketAddr,
+    local_addr: SocketAddr,
+    handshake: &Handshake,
+) -> Result<(TcpStream, PeerInfo), Box<dyn Error>> {
+    let connecting = async {
+        let mut stream = TcpStream::connect(addr).await?;
+        let start = Instant::now();
+        send_hello(&mut stream, local_addr, handshake).await?;
+        let theirs = read_hello(&mut stream, handshake)
+            .await?
+            .ok_or_else(|| format!("peer {} is on a different network", addr))?;
+        let info = PeerInfo {
+            addr,
+            direction: Direction::Outbound,
+            height: theirs.height,
+            latency_ms: Some(start.elapsed().as_millis() as u64),
+        };
+        Ok::<_, Box<dyn Error>>((stream, info))
+    };
+    tokio::time::timeout(HANDSHAKE_TIMEOUT, connecting)
+        .await
+        .map_err(|_| format!("peer {} did not answer the handshake in time", addr))?
+}
+
+/// Answers an inbound connection's handshake, failing like `connect`.
+async fn accept(
+    stream: &mut TcpStream,
     addr: SocketAddr,
-    stream: TcpStream,
+    local_addr: SocketAddr,
+    handshake: &Handshake,
+) -> Result<PeerInfo, Box<dyn Error>> {
+    let accepting = async {
+        let theirs = read_hello(stream, handshake)
+            .await?
+            .ok_or("handshake does not match our network")?;
+        send_hello(stream, local_addr, handshake).await?;
+        Ok::<_, Box<dyn Error>>(PeerInfo {
+            addr,
+            direction: Direction::Inbound,
+            height: theirs.height,
+            latency_ms: None,
+        })
+    };
+    tokio::time::timeout(HANDSHAKE_TIMEOUT, accepting)
+        .await
+        .map_err(|_| "no handshake in time")?
 }
 
-impl Peer {
-    pub async fn new(addr: SocketAddr) -> Result<Self, Box<dyn Error>> {
-        let stream = TcpStream::connect(addr).await?;
-        Ok(Peer { addr, stream })
+async fn send_hello(
+    stream: &mut TcpStream,
+    local_addr: SocketAddr,
+    handshake: &Handshake,
+) -> io::Result<()> {
+    let hello = P2pMessage {
+        sender: local_addr,
+        message: Message::Hello(handshake.clone()),
+    };
+    write_frame(stream, &hello).await
+}
+
+/// Reads the peer's handshake, or `None` if it does not match ours. Until
+/// then frames are held to `MAX_HELLO_FRAME`.
+async fn read_hello(
+    stream: &mut TcpStream,
+    handshake: &Handshake,
+) -> io::Result<Option<Handshake>> {
+    match read_frame(stream, MAX_HELLO_FRAME).await? {
+        Some(P2pMessage {
+            message: Message::Hello(theirs),
+            ..
+        }) if handshake.matches(&theirs) => Ok(Some(theirs)),
+        _ => Ok(None),
     }
 }
 
+/// Tells the P2P layer how long our chain is, for handshakes.
+pub type HeightSource = Arc<dyn Fn() -> u64 + Send + Sync>;
+
 pub struct P2p {
-    peers: HashMap<SocketAddr, Peer>,
     listener: TcpListener,
     peer_addrs: Vec<SocketAddr>,
+    connections: Connections,
+}
+
+/// What every connection shares, whichever side opened it.
+#[derive(Clone)]
+struct Connections {
+    handshake: Handshake,
+    peer_list: PeerList,
+    handle: P2pHandle,
+    height: HeightSource,
 }
 
 impl P2p {
-    pub async fn new(port: u16, peer_addrs: Vec<SocketAddr>) -> Result<Self, Box<dyn Error>> {
-        let addr = SocketAddr::from(([127, 0, 0, 1], port));
+    pub async fn new(
+        addr: SocketAddr,
+        peer_addrs: Vec<SocketAddr>,
+        handshake: Handshake,
+    ) -> Result<Self, Box<dyn Error>> {
         let listener = TcpListener::bind(addr).await?;
+        let handle = P2pHandle {
+            local_addr: listener.local_addr()?,
+            writers: Arc::default(),
+        };
         Ok(P2p {
-            peers: HashMap::new(),
             listener,
             peer_addrs,
+            connections: Connections {
+                handshake,
+                peer_list: PeerList::default(),
+                handle,
+                height: Arc::new(|| 0),
+            },
         })
     }
 
-    pub async fn run(&mut self, tx: mpsc::Sender<P2pMessage>) {
+    pub fn peer_list(&self) -> PeerList {
+        self.connections.peer_list.clone()
+    }
+
+    /// For sending to the peers `run` connects with.
+    pub fn handle(&self) -> P2pHandle {
+        self.connections.handle.clone()
+    }
+
+    /// Where the height in our handshakes comes from; 0 until set.
+    pub fn set_height_source(&mut self, height: HeightSource) {
+        self.connections.height = height;
+    }
+
+    /// Connects to the configured peers and accepts connections from new
+    /// ones, passing every message they send on to `tx`. Inbound
+    /// handshakes run on their own tasks, so a peer that never sends one
+    /// holds up nobody else.
+    pub async fn run(self, tx: mpsc::Sender<P2pMessage>) {
         info!("P2P network running.");
+        let connections = self.connections;
+        let local_addr = connections.handle.local_addr;
+        for addr in self.peer_addrs {
+            match connect(addr, local_addr, &connections.handshake()).await {
+                Ok((stream, info)) => {
+                    info!("Connected to peer {}", addr);
+                    connections.spawn(stream, info, tx.clone());
+                }
+                Err(e) => warn!("Failed to connect to peer {}: {}", addr, e),
+            }
+        }
         loop {
-            let (mut stream, addr) = self.listener.accept().await.unwrap();
+            let (mut stream, addr) = match self.listener.accept().await {
+                Ok(accepted) => accepted,
+                Err(e) => {
+                    warn!("Failed to accept a connection: {}", e);
+                    continue;
+                }
+            };
             info!("New connection from {}", addr);
+            let connections = connections.clone();
             let tx = tx.clone();
             tokio::spawn(async move {
-                let mut buffer = vec![0; 1024];
-                loop {
-                    match stream.read(&mut buffer).await {
-                        Ok(0) => {
-                            info!("Connection with {} closed.", addr);
-                            break;
-                        }
-                        Ok(n) => {
-                            if let Ok(message) = serde_json::from_slice::<P2pMessage>(&buffer[..n]) {
-                                info!("Received message: {:?}", message);
-                                tx.send(message).await.unwrap();
-                            }
+                let handshake = connections.handshake();
+                match accept(&mut stream, addr, local_addr, &handshake).await {
+                    Ok(info) => connections.spawn(stream, info, tx),
+                    Err(e) => warn!("Refusing {}: {}", addr, e),
+                }
+            });
+        }
+    }
+}
+
+impl Connections {
+    fn handshake(&self) -> Handshake {
+        Handshake {
+            height: (self.height)(),
+            ..self.handshake.clone()
+        }
+    }
+
+    /// Reads frames from the peer into `tx` and writes whatever the handle
+    /// sends it, until either side closes. Asks for the peer's chain first
+    /// if it is longer than ours. Pings and pongs are answered and timed
+    /// here rather than passed on.
+    fn spawn(&self, stream: TcpStream, info: PeerInfo, tx: mpsc::Sender<P2pMessage>) {
+        let addr = info.addr;
+        let behind = info.height > (self.height)();
+        let (mut reader, mut writer) = stream.into_split();
+        let (frames, mut outbox) = mpsc::unbounded_channel::<Arc<Vec<u8>>>();
+        self.handle.writers().insert(addr, frames);
+        self.peer_list
+            .lock()
+            .expect("Peer list lock poisoned")
+            .insert(addr, info);
+        if behind {
+            self.handle.send(addr, Message::GetBlocks);
+        }
+        let writing = tokio::spawn(async move {
+            while let Some(frame) = outbox.recv().await {
+                if let Err(e) = writer.write_all(&frame).await {
+                    warn!("Failed to write to {}: {}", addr, e);
+                    break;
+                }
+            }
+        });
+        let started = Instant::now();
+        let pinger = self.handle.clone();
+        let pinging = tokio::spawn(async move {
+            let mut interval = tokio::time::interval(PING_INTERVAL);
+            loop {
+                interval.tick().await;
+                pinger.send(addr, Message::Ping(started.elapsed().as_millis() as u64));
+            }
+        });
+        let handle = self.handle.clone();
+        let peer_list = self.peer_list.clone();
+        tokio::spawn(async move {
+            loop {
+                match read_frame(&mut reader, MAX_FRAME).await {
+                    Ok(Some(P2pMessage {
+                        message: Message::Ping(sent),
+                        ..
+                    })) => handle.send(addr, Message::Pong(sent)),
+                    Ok(Some(P2pMessage {
+                        message: Message::Pong(sent),
+                        ..
+                    })) => {
+                        let now = started.elapsed().as_millis() as u64;
+                        let mut peers = peer_list.lock().expect("Peer list lock poisoned");
+                        if let Some(peer) = peers.get_mut(&addr) {
+                            peer.latency_ms = Some(now.saturating_sub(sent));
                         }
-                        Err(e) => {
-                            warn!("Failed to read from stream: {}", e);
+                    }
+                    Ok(Some(mut message)) => {
+                        info!("Received {} from {}", message.message, addr);
+                        message.sender = addr;
+                        if tx.send(message).await.is_err() {
                             break;
                         }
                     }
+                    Ok(None) => {
+                        info!("Connection with {} closed.", addr);
+                        break;
+                    }
+                    Err(e) => {
+                        warn!("Dropping {}: {}", addr, e);
+                        break;
+                    }
                 }
-            });
+            }
+            handle.writers().remove(&addr);
+            peer_list
+                .lock()
+                .expect("Peer list lock poisoned")
+                .remove(&addr);
+            writing.abort();
+            pinging.abort();
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{self, units};
+
+    fn handle() -> P2pHandle {
+        P2pHandle {
+            local_addr: "127.0.0.1:1".parse().unwrap(),
+            writers: Writers::default(),
+        }
+    }
+
+    fn from(sender: &str, message: Message) -> P2pMessage {
+        P2pMessage {
+            sender: sender.parse().unwrap(),
+            message,
+        }
+    }
+
+    #[tokio::test]
+    async fn frames_carry_messages_larger_than_a_read_buffer() {
+        let (node, _p2p_rx) = testing::node(&[("alice", 100)]);
+        for _ in 0..20 {
+            assert!(node.chain().generate_new_block());
         }
+        let blocks = node.chain().get_chain().to_vec();
+        let mut wire = Vec::new();
+        write_frame(&mut wire, &from("127.0.0.1:2", Message::Blocks(blocks)))
+            .await
+            .unwrap();
+        assert!(wire.len() > 1024);
+
+        let mut reader = wire.as_slice();
+        match read_frame(&mut reader, MAX_FRAME).await.unwrap() {
+            Some(P2pMessage {
+                message: Message::Blocks(blocks),
+                ..
+            }) => assert_eq!(blocks.len(), 21),
+            other => panic!("unexpected {:?}", other),
+        }
+        assert!(read_frame(&mut reader, MAX_FRAME).await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn rejects_frames_over_the_limit() {
+        let wire = (MAX_FRAME as u32 + 1).to_be_bytes();
+        let error = read_frame(&mut wire.as_slice(), MAX_FRAME)
+            .await
+            .unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
     }
 
-    pub async fn broadcast_message(&mut self, message: P2pMessage) -> Result<(), Box<dyn Error>> {
-        info!("Broadcasting message: {:?}", message);
-        let message_bytes = serde_json::to_vec(&message)?;
-        for peer in self.peers.values_mut() {
-            peer.stream.write_all(&message_bytes).await?;
+    #[tokio::test]
+    async fn a_silent_peer_does_not_hold_up_the_next() {
+        let handshake = Handshake {
+            network_id: "dev".into(),
+            genesis_hash: "genesis".into(),
+            height: 0,
+        };
+        let p2p = P2p::new(([127, 0, 0, 1], 0).into(), Vec::new(), handshake.clone())
+            .await
+            .unwrap();
+        let addr = p2p.handle().local_addr;
+        let peers = p2p.peer_list();
+        let (tx, _rx) = mpsc::channel(1);
+        tokio::spawn(p2p.run(tx));
+
+        let _silent = TcpStream::connect(addr).await.unwrap();
+        let local_addr = "127.0.0.1:2".parse().unwrap();
+        let (_stream, info) = tokio::time::timeout(
+            Duration::from_secs(1),
+            connect(addr, local_addr, &handshake),
+        )
+        .await
+        .expect("handshake held up by the silent peer")
+        .unwrap();
+        assert_eq!(info.direction, Direction::Outbound);
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(peers.lock().unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn pings_peers_and_answers_their_pings() {
+        let handshake = Handshake {
+            network_id: "dev".into(),
+            genesis_hash: "genesis".into(),
+            height: 0,
+        };
+        let p2p = P2p::new(([127, 0, 0, 1], 0).into(), Vec::new(), handshake.clone())
+            .await
+            .unwrap();
+        let addr = p2p.handle().local_addr;
+        let peers = p2p.peer_list();
+        let (tx, _rx) = mpsc::channel(1);
+        tokio::spawn(p2p.run(tx));
+
+        let local_addr = "127.0.0.1:2".parse().unwrap();
+        let (mut stream, _) = connect(addr, local_addr, &handshake).await.unwrap();
+        let sent = match read_frame(&mut stream, MAX_FRAME).await.unwrap() {
+            Some(P2pMessage {
+                message: Message::Ping(sent),
+                ..
+            }) => sent,
+            other => panic!("unexpected {:?}", other),
+        };
+        let inbound = || peers.lock().unwrap().values().next().unwrap().clone();
+        assert_eq!(inbound().direction, Direction::Inbound);
+        assert_eq!(inbound().latency_ms, None);
+        write_frame(&mut stream, &from("127.0.0.1:2", Message::Pong(sent)))
+            .await
+            .unwrap();
+        write_frame(&mut stream, &from("127.0.0.1:2", Message::Ping(42)))
+            .await
+            .unwrap();
+        match read_frame(&mut stream, MAX_FRAME).await.unwrap() {
+            Some(P2pMessage {
+                message: Message::Pong(42),
+                ..
+            }) => {}
+            other => panic!("unexpected {:?}", other),
         }
-        Ok(())
+        // The pong was read before the ping was answered.
+        assert!(inbound().latency_ms.is_some());
+    }
+
+    #[tokio::test]
+    async fn holds_frames_before_the_handshake_to_a_smaller_limit() {
+        let wire = (MAX_HELLO_FRAME as u32 + 1).to_be_bytes();
+        let error = read_frame(&mut wire.as_slice(), MAX_HELLO_FRAME)
+            .await
+            .unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[tokio::test]
+    async fn applies_blocks_and_transactions_from_peers() {
+        let (a, _a_rx) = testing::node(&[("alice", 100)]);
+        let (b, _b_rx) = testing::node(&[("alice", 100)]);
+        let peers = handle();
+
+        let id = a
+            .chain()
+            .new_transaction("alice".into(), "bob".into(), units(10))
+            .unwrap();
+        let tx = a.chain().get_transaction(&id).unwrap().clone();
+        receive(
+            &b,
+            &peers,
+            from("127.0.0.1:2", Message::NewTransaction(Box::new(tx))),
+        );
+        assert_eq!(b.chain().pending_transactions().len(), 1);
+
+        assert!(a.chain().generate_new_block());
+        let block = a.chain().get_block(1).unwrap().clone();
+        receive(&b, &peers, from("127.0.0.1:2", Message::NewBlock(block)));
+        assert_eq!(b.chain().last_hash(), a.chain().last_hash());
+        assert_eq!(b.chain().get_balance("bob"), Some(units(10)));
+        assert!(b.chain().pending_transactions().is_empty());
+    }
+
+    #[tokio::test]
+    async fn follows_the_height_of_peers_sending_blocks() {
+        let (a, _a_rx) = testing::node(&[("alice", 100)]);
+        let (b, _b_rx) = testing::node(&[("alice", 100)]);
+        let peers = handle();
+        let sender: SocketAddr = "127.0.0.1:2".parse().unwrap();
+        b.peers().lock().unwrap().insert(
+            sender,
+            PeerInfo {
+                addr: sender,
+                direction: Direction::Inbound,
+                height: 0,
+                latency_ms: None,
+            },
+        );
+        let height = || b.peers().lock().unwrap()[&sender].height;
+
+        for _ in 0..3 {
+            assert!(a.chain().generate_new_block());
+        }
+        let block = a.chain().get_block(1).unwrap().clone();
+        receive(
+            &b,
+            &peers,
+            from("127.0.0.1:2", Message::NewBlock(block.clone())),
+        );
+        assert_eq!(height(), 1);
+        let blocks = a.chain().get_chain().to_vec();
+        receive(&b, &peers, from("127.0.0.1:2", Message::Blocks(blocks)));
+        assert_eq!(height(), 3);
+        // A block we already have still tells us where the peer is.
+        receive(&b, &peers, from("127.0.0.1:2", Message::NewBlock(block)));
+        assert_eq!(height(), 1);
+    }
+
+    #[tokio::test]
+    async fn fetches_the_chain_behind_an_unknown_block() {
+        let (a, _a_rx) = testing::node(&[("alice", 100)]);
+        let (b, _b_rx) = testing::node(&[("alice", 100)]);
+        let peers = handle();
+        let sender: SocketAddr = "127.0.0.1:2".parse().unwrap();
+        let (frames, mut outbox) = mpsc::unbounded_channel();
+        peers.writers().insert(sender, frames);
+
+        assert!(a.chain().generate_new_block());
+        assert!(a.chain().generate_new_block());
+        let tip = a.chain().get_block(2).unwrap().clone();
+        receive(&b, &peers, from("127.0.0.1:2", Message::NewBlock(tip)));
+        assert_eq!(b.chain().get_chain().len(), 1);
+        let request = outbox.try_recv().unwrap();
+        match read_frame(&mut request.as_slice(), MAX_FRAME)
+            .await
+            .unwrap()
+        {
+            Some(P2pMessage {
+                message: Message::GetBlocks,
+                ..
+            }) => {}
+            other => panic!("unexpected {:?}", other),
+        }
+
+        let blocks = a.chain().get_chain().to_vec();
+        receive(&b, &peers, from("127.0.0.1:2", Message::Blocks(blocks)));
+        assert_eq!(b.chain().last_hash(), a.chain().last_hash());
     }
 }
@@ -0,0 +1,978 @@
+use crate::amount::Amount;
+use crate::blockchain::{Block, BlockHeader, Chain, Transaction, TxStatus};
+use crate::index::{LedgerEntry, TxLocation};
+use crate::node::Node;
+use crate::p2p::PeerInfo;
+use crate::smt::AccountProof;
+use crate::state::TxReceipt;
+use crate::token::Token;
+use axum::extract::rejection::{PathRejection, QueryRejection};
+use axum::extract::{Path, Query, State};
+use axum::http::{Method, StatusCode};
+use axum::response::{IntoResponse, Response};
+use axum::routing::get;
+use axum::{Json, Router};
+use serde::{Deserialize, Serialize};
+use utoipa::{IntoParams, OpenApi, ToSchema};
+
+/// Page size when a request does not ask for one.
+pub const DEFAULT_LIMIT: usize = 20;
+
+/// Largest page a request may ask for.
+pub const MAX_LIMIT: usize = 100;
+
+#[derive(OpenApi)]
+#[openapi(
+    info(
+        title = "Rust Blockchain REST API",
+        description = "Read-only view of the chain."
+    ),
+    paths(
+        list_blocks,
+        get_block,
+        get_transaction,
+        get_account,
+        balance_proof,
+        balance_at,
+        account_ledger,
+        scheduled_transfers,
+        supply,
+        list_tokens,
+        token_holders,
+        mempool,
+        peers
+    ),
+    components(schemas(Block, BlockHeader, Transaction, ErrorResponse, ErrorBody))
+)]
+pub struct ApiDoc;
+
+/// Every error is answered with this body and a matching HTTP status.
+#[derive(Debug, Serialize, ToSchema)]
+pub struct ErrorResponse {
+    pub error: ErrorBody,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct ErrorBody {
+    /// `bad_request`, `not_found`, `method_not_allowed` or `internal`.
+    pub code: String,
+    pub message: String,
+}
+
+#[derive(Debug)]
+pub struct ApiError {
+    status: StatusCode,
+    body: ErrorBody,
+}
+
+impl ApiError {
+    fn new(status: StatusCode, code: &str, message: impl Into<String>) -> ApiError {
+        ApiError {
+            status,
+            body: ErrorBody {
+                code: code.to_string(),
+                message: message.into(),
+            },
+        }
+    }
+
+    fn bad_request(message: impl Into<String>) -> ApiError {
+        ApiError::new(StatusCode::BAD_REQUEST, "bad_request", message)
+    }
+
+    fn not_found(message: impl Into<String>) -> ApiError {
+        ApiError::new(StatusCode::NOT_FOUND, "not_found", message)
+    }
+
+    fn method_not_allowed(message: impl Into<String>) -> ApiError {
+        ApiError::new(
+            StatusCode::METHOD_NOT_ALLOWED,
+            "method_not_allowed",
+            message,
+        )
+    }
+
+    fn internal(message: impl Into<String>) -> ApiError {
+        ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, "internal", message)
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        (self.status, Json(ErrorResponse { error: self.body })).into_response()
+    }
+}
+
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct PageParams {
+    /// Items to skip. Defaults to 0.
+    offset: Option<usize>,
+    /// Items to return, at most 100. Defaults to 20.
+    limit: Option<usize>,
+}
+
+impl PageParams {
+    fn resolve(
+        query: Result<Query<PageParams>, QueryRejection>,
+    ) -> Result<(usize, usize), ApiError> {
+        let Query(params) = query.map_err(|e| ApiError::bad_request(e.body_text()))?;
+        let limit = params.limit.unwrap_or(DEFAULT_LIMIT);
+        if limit == 0 || limit > MAX_LIMIT {
+            return Err(ApiError::bad_request(format!(
+                "limit must be between 1 and {}",
+                MAX_LIMIT
+            )));
+        }
+        Ok((params.offset.unwrap_or(0), limit))
+    }
+}
+
+/// One page of a longer list.
+#[derive(Debug, Serialize, ToSchema)]
+pub struct Paged<T> {
+    pub items: Vec<T>,
+    /// Length of the whole list.
+    pub total: usize,
+    pub offset: usize,
+    pub limit: usize,
+}
+
+impl<T: Clone> Paged<T> {
+    fn slice(all: &[T], offset: usize, limit: usize) -> Paged<T> {
+        Paged {
+            items: all.iter().skip(offset).take(limit).cloned().collect(),
+            total: all.len(),
+            offset,
+            limit,
+        }
+    }
+}
+
+/// A block with its hash and height.
+#[derive(Debug, Serialize, ToSchema)]
+pub struct BlockResponse {
+    pub height: u64,
+    pub hash: String,
+    #[serde(flatten)]
+    pub block: Block,
+}
+
+impl BlockResponse {
+    fn new(block: &Block, height: u64) -> Result<BlockResponse, ApiError> {
+        Ok(BlockResponse {
+            height,
+            hash: Chain::hash(block.header()).map_err(|e| ApiError::internal(e.to_string()))?,
+            block: block.clone(),
+        })
+    }
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct TransactionResponse {
+    pub transaction: Transaction,
+    pub status: TxStatus,
+    pub receipt: Option<TxReceipt>,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct AccountResponse {
+    pub account: String,
+    /// Native balance formatted with the token's decimals and symbol.
+    pub balance: String,
+    pub base_units: Amount,
+    /// Transactions involving the account, newest first.
+    pub history: Paged<TxLocation>,
+}
+
+/// Proof of an account's native balance under the tip's state root.
+#[derive(Debug, Serialize, ToSchema)]
+pub struct BalanceProofResponse {
+    /// The tip the proof is for; its header carries the state root.
+    pub height: u64,
+    pub block_hash: String,
+    #[schema(value_type = Object)]
+    pub proof: AccountProof,
+}
+
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct BalanceAtParams {
+    /// Height of the block to read the balance after.
+    height: u64,
+    /// Issued token to read instead of the native one.
+    token: Option<String>,
+}
+
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct TokenParams {
+    /// Issued token to read instead of the native one.
+    token: Option<String>,
+}
+
+/// An account's balance after a block.
+#[derive(Debug, Serialize, ToSchema)]
+pub struct BalanceAtResponse {
+    pub account: String,
+    pub height: u64,
+    /// The issued token, or `null` for the native one.
+    pub token: Option<String>,
+    /// Formatted with the token's decimals and symbol.
+    pub balance: String,
+    pub base_units: Amount,
+}
+
+/// Native supply in circulation after a block.
+#[derive(Debug, Serialize, ToSchema)]
+pub struct SupplyResponse {
+    pub height: u64,
+    /// Formatted with the token's decimals and symbol.
+    pub supply: String,
+    pub base_units: Amount,
+}
+
+/// An account holding an issued token.
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct TokenHolder {
+    pub account: String,
+    /// Formatted with the token's decimals and symbol.
+    pub balance: String,
+    pub base_units: Amount,
+}
+
+/// A mempool transaction with its id.
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct PendingTransaction {
+    pub id: String,
+    pub transaction: Transaction,
+}
+
+/// Read-only REST over the chain: blocks, transactions, accounts, the
+/// mempool and peers, plus the OpenAPI document at `/openapi.json`.
+pub fn router(node: Node) -> Router {
+    Router::new()
+        .route("/blocks", get(list_blocks))
+        .route("/blocks/{id}", get(get_block))
+        .route("/tx/{id}", get(get_transaction))
+        .route("/accounts/{address}", get(get_account))
+        .route("/accounts/{address}/proof", get(balance_proof))
+        .route("/accounts/{address}/balance", get(balance_at))
+        .route("/accounts/{address}/ledger", get(account_ledger))
+        .route("/accounts/{address}/scheduled", get(scheduled_transfers))
+        .route("/supply/{height}", get(supply))
+        .route("/tokens", get(list_tokens))
+        .route("/tokens/{symbol}/holders", get(token_holders))
+        .route("/mempool", get(mempool))
+        .route("/peers", get(peers))
+        .route("/openapi.json", get(openapi))
+        .method_not_allowed_fallback(wrong_method)
+        .fallback(unknown_route)
+        .with_state(node)
+}
+
+/// Runs `f` off the async workers, since the chain lock can be held for a
+/// whole round of mining.
+async fn blocking<T, F>(node: Node, f: F) -> Result<Json<T>, ApiError>
+where
+    T: Send + 'static,
+    F: FnOnce(&Node) -> Result<T, ApiError> + Send + 'static,
+{
+    tokio::task::spawn_blocking(move || f(&node))
+        .await
+        .map_err(|e| ApiError::internal(e.to_string()))?
+        .map(Json)
+}
+
+async fn unknown_route() -> ApiError {
+    ApiError::not_found("no such route")
+}
+
+async fn wrong_method(method: Method) -> ApiError {
+    ApiError::method_not_allowed(format!(
+        "{} is not allowed here; the REST API is read-only",
+        method
+    ))
+}
+
+async fn openapi() -> Json<utoipa::openapi::OpenApi> {
+    Json(ApiDoc::openapi())
+}
+
+/// Blocks, newest first.
+#[utoipa::path(
+    get,
+    path = "/blocks",
+    params(PageParams),
+    responses(
+        (status = 200, body = Paged<BlockResponse>),
+        (status = 400, body = ErrorResponse),
+    )
+)]
+async fn list_blocks(
+    State(node): State<Node>,
+    query: Result<Query<PageParams>, QueryRejection>,
+) -> Result<Json<Paged<BlockResponse>>, ApiError> {
+    let (offset, limit) = PageParams::resolve(query)?;
+    blocking(node, move |node| {
+        let chain = node.chain();
+        let blocks = chain.get_chain();
+        let items = blocks
+            .iter()
+            .enumerate()
+            .rev()
+            .skip(offset)
+            .take(limit)
+            .map(|(height, block)| BlockResponse::new(block, height as u64))
+            .collect::<Result<_, _>>()?;
+        Ok(Paged {
+            items,
+            total: blocks.len(),
+            offset,
+            limit,
+        })
+    })
+    .await
+}
+
+/// A block by height, or by hash if `id` is not a number.
+#[utoipa::path(
+    get,
+    path = "/blocks/{id}",
+    params(("id" = String, Path, description = "Block height or hash")),
+    responses(
+        (status = 200, body = BlockResponse),
+        (status = 404, body = ErrorResponse),
+    )
+)]
+async fn get_block(
+    State(node): State<Node>,
+    Path(id): Path<String>,
+) -> Result<Json<BlockResponse>, ApiError> {
+    blocking(node, move |node| {
+        let chain = node.chain();
+        let height = match id.parse::<u64>() {
+            Ok(height) => height,
+            Err(_) => chain
+                .block_height(&id)
+                .ok_or_else(|| ApiError::not_found(format!("no block with hash {}", id)))?,
+        };
+        let block = chain
+            .get_block(height)
+            .ok_or_else(|| ApiError::not_found(format!("no block at height {}", height)))?;
+        BlockResponse::new(block, height)
+    })
+    .await
+}
+
+/// A confirmed or pending transaction, its status and receipt.
+#[utoipa::path(
+    get,
+    path = "/tx/{id}",
+    params(("id" = String, Path, description = "Transaction id")),
+    responses(
+        (status = 200, body = TransactionResponse),
+        (status = 404, body = ErrorResponse),
+    )
+)]
+async fn get_transaction(
+    State(node): State<Node>,
+    Path(id): Path<String>,
+) -> Result<Json<TransactionResponse>, ApiError> {
+    blocking(node, move |node| {
+        let chain = node.chain();
+        let transaction = chain
+            .get_transaction(&id)
+            .ok_or_else(|| ApiError::not_found(format!("unknown transaction {}", id)))?;
+        Ok(TransactionResponse {
+            transaction: transaction.clone(),
+            status: chain.transaction_status(&id),
+            receipt: chain.get_receipt(&id).cloned(),
+        })
+    })
+    .await
+}
+
+/// An account's balance and a page of its history.
+#[utoipa::path(
+    get,
+    path = "/accounts/{address}",
+    params(("address" = String, Path), PageParams),
+    responses(
+        (status = 200, body = AccountResponse),
+        (status = 400, body = ErrorResponse),
+        (status = 404, body = ErrorResponse),
+    )
+)]
+async fn get_account(
+    State(node): State<Node>,
+    Path(address): Path<String>,
+    query: Result<Query<PageParams>, QueryRejection>,
+) -> Result<Json<AccountResponse>, ApiError> {
+    let (offset, limit) = PageParams::resolve(query)?;
+    blocking(node, move |node| {
+        let chain = node.chain();
+        let balance = chain
+            .get_balance(&address)
+            .ok_or_else(|| ApiError::not_found(format!("unknown account {}", address)))?;
+        let history = chain.address_history(&address, offset, limit);
+        Ok(AccountResponse {
+            balance: chain.format_amount(balance),
+            base_units: balance,
+            history: Paged {
+                items: history.items,
+                total: history.total,
+                offset,
+                limit,
+            },
+            account: address,
+        })
+    })
+    .await
+}
+
+/// A proof of an account's native balance, to check against the state
+/// root in the tip's header.
+#[utoipa::path(
+    get,
+    path = "/accounts/{address}/proof",
+    params(("address" = String, Path)),
+    responses((status = 200, body = BalanceProofResponse))
+)]
+async fn balance_proof(
+    State(node): State<Node>,
+    Path(address): Path<String>,
+) -> Result<Json<BalanceProofResponse>, ApiError> {
+    blocking(node, move |node| {
+        let chain = node.chain();
+        Ok(BalanceProofResponse {
+            height: chain.get_chain().len() as u64 - 1,
+            block_hash: chain.last_hash(),
+            proof: chain.prove_balance(&address),
+        })
+    })
+    .await
+}
+
+/// An account's native or token balance after the block at a height.
+#[utoipa::path(
+    get,
+    path = "/accounts/{address}/balance",
+    params(("address" = String, Path), BalanceAtParams),
+    responses(
+        (status = 200, body = BalanceAtResponse),
+        (status = 400, body = ErrorResponse),
+        (status = 404, body = ErrorResponse),
+    )
+)]
+async fn balance_at(
+    State(node): State<Node>,
+    Path(address): Path<String>,
+    query: Result<Query<BalanceAtParams>, QueryRejection>,
+) -> Result<Json<BalanceAtResponse>, ApiError> {
+    let Query(BalanceAtParams { height, token }) =
+        query.map_err(|e| ApiError::bad_request(e.body_text()))?;
+    blocking(node, move |node| {
+        let chain = node.chain();
+        if height >= chain.get_chain().len() as u64 {
+            return Err(ApiError::not_found(format!(
+                "no block at height {}",
+                height
+            )));
+        }
+        // Accounts the ledger has not seen by then held nothing.
+        let balance = match &token {
+            Some(symbol) => {
+                chain
+                    .get_token(symbol)
+                    .ok_or_else(|| ApiError::not_found(format!("unknown token {}", symbol)))?;
+                chain.token_balance_at(&address, symbol, height)
+            }
+            None => chain.balance_at(&address, height),
+        }
+        .unwrap_or_default();
+        Ok(BalanceAtResponse {
+            balance: chain
+                .format_amount_in(token.as_deref(), balance)
+                .unwrap_or_default(),
+            account: address,
+            height,
+            token,
+            base_units: balance,
+        })
+    })
+    .await
+}
+
+/// Every confirmed credit and debit of an account's native or token
+/// balance, oldest first, with the balance after each.
+#[utoipa::path(
+    get,
+    path = "/accounts/{address}/ledger",
+    params(("address" = String, Path), TokenParams, PageParams),
+    responses(
+        (status = 200, body = Paged<LedgerEntry>),
+        (status = 400, body = ErrorResponse),
+        (status = 404, body = ErrorResponse),
+    )
+)]
+async fn account_ledger(
+    State(node): State<Node>,
+    Path(address): Path<String>,
+    token: Result<Query<TokenParams>, QueryRejection>,
+    query: Result<Query<PageParams>, QueryRejection>,
+) -> Result<Json<Paged<LedgerEntry>>, ApiError> {
+    let Query(TokenParams { token }) = token.map_err(|e| ApiError::bad_request(e.body_text()))?;
+    let (offset, limit) = PageParams::resolve(query)?;
+    blocking(node, move |node| {
+        let chain = node.chain();
+        if let Some(symbol) = &token {
+            chain
+                .get_token(symbol)
+                .ok_or_else(|| ApiError::not_found(format!("unknown token {}", symbol)))?;
+        }
+        let ledger = chain.account_ledger(&address, token.as_deref());
+        Ok(Paged::slice(ledger, offset, limit))
+    })
+    .await
+}
+
+/// Pending time- or height-locked transfers sent or received by an
+/// account, in unlock order.
+#[utoipa::path(
+    get,
+    path = "/accounts/{address}/scheduled",
+    params(("address" = String, Path), PageParams),
+    responses(
+        (status = 200, body = Paged<PendingTransaction>),
+        (status = 400, body = ErrorResponse),
+    )
+)]
+async fn scheduled_transfers(
+    State(node): State<Node>,
+    Path(address): Path<String>,
+    query: Result<Query<PageParams>, QueryRejection>,
+) -> Result<Json<Paged<PendingTransaction>>, ApiError> {
+    let (offset, limit) = PageParams::resolve(query)?;
+    blocking(node, move |node| {
+        let chain = node.chain();
+        let scheduled: Vec<PendingTransaction> = chain
+            .scheduled_transfers(&address)
+            .into_iter()
+            .map(|tx| PendingTransaction {
+                id: tx.id(),
+                transaction: tx.clone(),
+            })
+            .collect();
+        Ok(Paged::slice(&scheduled, offset, limit))
+    })
+    .await
+}
+
+/// Supply minted up to a height, less the gas fees burned up to it.
+#[utoipa::path(
+    get,
+    path = "/supply/{height}",
+    params(("height" = u64, Path)),
+    responses(
+        (status = 200, body = SupplyResponse),
+        (status = 400, body = ErrorResponse),
+        (status = 404, body = ErrorResponse),
+    )
+)]
+async fn supply(
+    State(node): State<Node>,
+    height: Result<Path<u64>, PathRejection>,
+) -> Result<Json<SupplyResponse>, ApiError> {
+    let Path(height) = height.map_err(|e| ApiError::bad_request(e.body_text()))?;
+    blocking(node, move |node| {
+        let chain = node.chain();
+        let supply = chain
+            .circulating_supply(height as usize)
+            .ok_or_else(|| ApiError::not_found(format!("no block at height {}", height)))?;
+        Ok(SupplyResponse {
+            height,
+            supply: chain.format_amount(supply),
+            base_units: supply,
+        })
+    })
+    .await
+}
+
+/// Issued tokens, by symbol.
+#[utoipa::path(
+    get,
+    path = "/tokens",
+    params(PageParams),
+    responses(
+        (status = 200, body = Paged<Token>),
+        (status = 400, body = ErrorResponse),
+    )
+)]
+async fn list_tokens(
+    State(node): State<Node>,
+    query: Result<Query<PageParams>, QueryRejection>,
+) -> Result<Json<Paged<Token>>, ApiError> {
+    let (offset, limit) = PageParams::resolve(query)?;
+    blocking(node, move |node| {
+        let chain = node.chain();
+        let tokens: Vec<Token> = chain.list_tokens().into_iter().cloned().collect();
+        Ok(Paged::slice(&tokens, offset, limit))
+    })
+    .await
+}
+
+/// Accounts holding a token, largest balance first.
+#[utoipa::path(
+    get,
+    path = "/tokens/{symbol}/holders",
+    params(("symbol" = String, Path), PageParams),
+    responses(
+        (status = 200, body = Paged<TokenHolder>),
+        (status = 400, body = ErrorResponse),
+        (status = 404, body = ErrorResponse),
+    )
+)]
+async fn token_holders(
+    State(node): State<Node>,
+    Path(symbol): Path<String>,
+    query: Result<Query<PageParams>, QueryRejection>,
+) -> Result<Json<Paged<TokenHolder>>, ApiError> {
+    let (offset, limit) = PageParams::resolve(query)?;
+    blocking(node, move |node| {
+        let chain = node.chain();
+        let token = chain
+            .get_token(&symbol)
+            .ok_or_else(|| ApiError::not_found(format!("unknown token {}", symbol)))?;
+        let holders: Vec<TokenHolder> = chain
+            .token_holders(&symbol)
+            .into_iter()
+            .map(|(account, balance)| TokenHolder {
+                account,
+                balance: token.format_amount(balance),
+                base_units: balance,
+            })
+            .collect();
+        Ok(Paged::slice(&holders, offset, limit))
+    })
+    .await
+}
+
+/// Transactions waiting for the next block, oldest first.
+#[utoipa::path(
+    get,
+    path = "/mempool",
+    params(PageParams),
+    responses(
+        (status = 200, body = Paged<PendingTransaction>),
+        (status = 400, body = ErrorResponse),
+    )
+)]
+async fn mempool(
+    State(node): State<Node>,
+    query: Result<Query<PageParams>, QueryRejection>,
+) -> Result<Json<Paged<PendingTransaction>>, ApiError> {
+    let (offset, limit) = PageParams::resolve(query)?;
+    blocking(node, move |node| {
+        let chain = node.chain();
+        let pending = chain.pending_transactions();
+        Ok(Paged {
+            items: pending
+                .iter()
+                .skip(offset)
+                .take(limit)
+                .map(|tx| PendingTransaction {
+                    id: tx.id(),
+                    transaction: tx.clone(),
+                })
+                .collect(),
+            total: pending.len(),
+            offset,
+            limit,
+        })
+    })
+    .await
+}
+
+/// Connected peers, by address.
+#[utoipa::path(
+    get,
+    path = "/peers",
+    params(PageParams),
+    responses(
+        (status = 200, body = Paged<PeerInfo>),
+        (status = 400, body = ErrorResponse),
+    )
+)]
+async fn peers(
+    State(node): State<Node>,
+    query: Result<Query<PageParams>, QueryRejection>,
+) -> Result<Json<Paged<PeerInfo>>, ApiError> {
+    let (offset, limit) = PageParams::resolve(query)?;
+    let peers = node.peers().lock().expect("Peer list lock poisoned");
+    let mut peers: Vec<_> = peers.values().cloned().collect();
+    peers.sort_by_key(|peer| peer.addr);
+    Ok(Json(Paged::slice(&peers, offset, limit)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{self, units};
+    use serde_json::{json, Value};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpStream};
+
+    /// Sends one request to the router over HTTP and returns the status
+    /// and the JSON body.
+    async fn request(node: &Node, method: &str, path: &str) -> (u16, Value) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let app = router(node.clone());
+        tokio::spawn(async move { axum::serve(listener, app).await });
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        let head = format!(
+            "{} {} HTTP/1.1\r\nHost: {}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+            method, path, addr
+        );
+        stream.write_all(head.as_bytes()).await.unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        let (head, body) = response.split_once("\r\n\r\n").unwrap();
+        let status = head.split(' ').nth(1).unwrap().parse().unwrap();
+        (status, serde_json::from_str(body).unwrap_or(Value::Null))
+    }
+
+    #[tokio::test]
+    async fn address_history_keeps_identical_transfers_apart() {
+        let (node, _p2p_rx) = testing::node(&[("alice", 100)]);
+        let (first, second) = {
+            let mut chain = node.chain();
+            let amount = units(5);
+            let first = chain.new_transaction("alice".into(), "bob".into(), amount);
+            let second = chain.new_transaction("alice".into(), "bob".into(), amount);
+            assert!(chain.generate_new_block());
+            (first.unwrap(), second.unwrap())
+        };
+        assert_ne!(first, second);
+
+        let (status, account) = request(&node, "GET", "/accounts/bob").await;
+        assert_eq!(status, 200);
+        assert_eq!(account["base_units"], 10);
+        assert_eq!(account["history"]["total"], 2);
+        let mut ids: Vec<&str> = account["history"]["items"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|tx| tx["tx_id"].as_str().unwrap())
+            .collect();
+        ids.sort();
+        let mut expected = vec![first.as_str(), second.as_str()];
+        expected.sort();
+        assert_eq!(ids, expected);
+
+        for (id, index) in [(&first, 1), (&second, 2)] {
+            let (_, tx) = request(&node, "GET", &format!("/tx/{}", id)).await;
+            assert_eq!(tx["status"]["Confirmed"]["block_height"], 1);
+            assert_eq!(tx["receipt"]["index"], index);
+        }
+    }
+
+    #[tokio::test]
+    async fn pages_through_blocks_newest_first() {
+        let (node, _p2p_rx) = testing::node(&[]);
+        for _ in 0..3 {
+            assert!(node.chain().generate_new_block());
+        }
+        let (status, page) = request(&node, "GET", "/blocks?offset=1&limit=2").await;
+        assert_eq!(status, 200);
+        assert_eq!(page["total"], 4);
+        assert_eq!(page["offset"], 1);
+        assert_eq!(page["limit"], 2);
+        let heights: Vec<&Value> = page["items"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|b| &b["height"])
+            .collect();
+        assert_eq!(heights, [2, 1]);
+
+        let (_, page) = request(&node, "GET", "/blocks?offset=10").await;
+        assert_eq!(page["items"].as_array().unwrap().len(), 0);
+        assert_eq!(page["limit"], DEFAULT_LIMIT);
+    }
+
+    #[tokio::test]
+    async fn reports_the_supply_at_a_height() {
+        let (node, _p2p_rx) = testing::node(&[("alice", 100)]);
+        assert!(node.chain().generate_new_block());
+        let reward = node.chain().block_reward(1);
+
+        let (status, supply) = request(&node, "GET", "/supply/1").await;
+        assert_eq!(status, 200);
+        assert_eq!(supply["height"], 1);
+        assert_eq!(supply["base_units"], json!(units(100).checked_add(reward)));
+        let (status, supply) = request(&node, "GET", "/supply/0").await;
+        assert_eq!(status, 200);
+        assert_eq!(supply["base_units"], 100);
+    }
+
+    #[tokio::test]
+    async fn pages_through_tokens_and_holders() {
+        let (node, _p2p_rx) = testing::node(&[("alice", 100)]);
+        {
+            let mut chain = node.chain();
+            assert!(chain.issue_token("alice".into(), "GLD".into(), "Gold".into(), 0, units(90)));
+            assert!(chain.generate_new_block());
+            for receiver in ["bob", "carol"] {
+                assert!(chain.new_token_transaction(
+                    "alice".into(),
+                    receiver.into(),
+                    "GLD".into(),
+                    units(30)
+                ));
+            }
+            assert!(chain.new_token_transaction(
+                "carol".into(),
+                "bob".into(),
+                "GLD".into(),
+                units(10)
+            ));
+            assert!(chain.generate_new_block());
+        }
+
+        let (status, tokens) = request(&node, "GET", "/tokens").await;
+        assert_eq!(status, 200);
+        assert_eq!(tokens["total"], 1);
+        assert_eq!(tokens["items"][0]["symbol"], "GLD");
+        assert_eq!(tokens["items"][0]["supply"], 90);
+
+        let (status, holders) = request(&node, "GET", "/tokens/GLD/holders?offset=1&limit=1").await;
+        assert_eq!(status, 200);
+        assert_eq!(holders["total"], 3);
+        assert_eq!(
+            holders["items"],
+            json!([{ "account": "alice", "balance": "30 GLD", "base_units": 30 }])
+        );
+        let (status, _) = request(&node, "GET", "/tokens/XYZ/holders").await;
+        assert_eq!(status, 404);
+    }
+
+    #[tokio::test]
+    async fn serves_balances_at_heights_and_ledgers() {
+        let (node, _p2p_rx) = testing::node(&[("alice", 100)]);
+        for amount in [30, 20, 10] {
+            let mut chain = node.chain();
+            assert!(chain
+                .new_transaction("alice".into(), "bob".into(), units(amount))
+                .is_some());
+            assert!(chain.generate_new_block());
+        }
+
+        let (status, balance) = request(&node, "GET", "/accounts/bob/balance?height=2").await;
+        assert_eq!(status, 200);
+        assert_eq!(balance["base_units"], 50);
+        assert_eq!(balance["balance"], "50 TST");
+        assert_eq!(balance["token"], Value::Null);
+        let (_, balance) = request(&node, "GET", "/accounts/alice/balance?height=0").await;
+        assert_eq!(balance["base_units"], 100);
+
+        let (status, ledger) = request(&node, "GET", "/accounts/bob/ledger?offset=1&limit=1").await;
+        assert_eq!(status, 200);
+        assert_eq!(ledger["total"], 3);
+        assert_eq!(ledger["items"][0]["block_height"], 2);
+        assert_eq!(ledger["items"][0]["balance"], 50);
+
+        for (path, status) in [
+            ("/accounts/bob/balance", 400),
+            ("/accounts/bob/balance?height=4", 404),
+            ("/accounts/bob/balance?height=1&token=XYZ", 404),
+            ("/accounts/bob/ledger?token=XYZ", 404),
+        ] {
+            assert_eq!(request(&node, "GET", path).await.0, status, "{}", path);
+        }
+    }
+
+    #[tokio::test]
+    async fn pages_through_scheduled_transfers() {
+        let (node, _p2p_rx) = testing::node(&[("alice", 100)]);
+        assert!(node
+            .chain()
+            .schedule_vesting("alice".into(), "bob".into(), units(9), 3, 1, 3));
+        assert!(node.chain().generate_new_block());
+
+        let (status, scheduled) = request(&node, "GET", "/accounts/bob/scheduled?limit=2").await;
+        assert_eq!(status, 200);
+        assert_eq!(scheduled["total"], 3);
+        let unlocks: Vec<&Value> = scheduled["items"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|tx| &tx["transaction"]["lock_height"])
+            .collect();
+        assert_eq!(unlocks, [3, 4]);
+
+        // Each installment leaves the schedule once it is mined.
+        assert!(node.chain().generate_new_block());
+        assert!(node.chain().generate_new_block());
+        let (_, scheduled) = request(&node, "GET", "/accounts/bob/scheduled").await;
+        assert_eq!(scheduled["total"], 2);
+        assert_eq!(scheduled["items"][0]["transaction"]["lock_height"], 4);
+    }
+
+    #[tokio::test]
+    async fn serves_balance_proofs_for_the_tip() {
+        let (node, _p2p_rx) = testing::node(&[("alice", 100)]);
+        assert!(node.chain().generate_new_block());
+
+        let (status, answer) = request(&node, "GET", "/accounts/alice/proof").await;
+        assert_eq!(status, 200);
+        assert_eq!(answer["height"], 1);
+        let (_, block) = request(
+            &node,
+            "GET",
+            &format!("/blocks/{}", answer["block_hash"].as_str().unwrap()),
+        )
+        .await;
+        let proof: AccountProof = serde_json::from_value(answer["proof"].clone()).unwrap();
+        assert_eq!(proof.balance, Some(units(100)));
+        assert!(proof.verify(block["header"]["state_root"].as_str().unwrap()));
+    }
+
+    #[tokio::test]
+    async fn errors_share_one_body() {
+        let (node, _p2p_rx) = testing::node(&[]);
+        for (method, path, status, code) in [
+            ("GET", "/blocks?limit=0", 400, "bad_request"),
+            ("GET", "/blocks?limit=101", 400, "bad_request"),
+            ("GET", "/blocks?offset=x", 400, "bad_request"),
+            ("GET", "/blocks/99", 404, "not_found"),
+            ("GET", "/accounts/nobody", 404, "not_found"),
+            ("GET", "/supply/2", 404, "not_found"),
+            ("GET", "/supply/tip", 400, "bad_request"),
+            ("GET", "/nowhere", 404, "not_found"),
+            ("POST", "/blocks", 405, "method_not_allowed"),
+            ("DELETE", "/mempool", 405, "method_not_allowed"),
+        ] {
+            let (found, body) = request(&node, method, path).await;
+            assert_eq!(found, status, "{} {}", method, path);
+            assert_eq!(body["error"]["code"], code, "{} {}", method, path);
+            assert!(body["error"]["message"].is_string());
+        }
+    }
+
+    #[test]
+    fn documents_the_error_body_that_is_sent() {
+        let doc = serde_json::to_value(ApiDoc::openapi()).unwrap();
+        let schema = &doc["components"]["schemas"]["ErrorResponse"];
+        assert_eq!(
+            schema["properties"]["error"]["$ref"],
+            "#/components/schemas/ErrorBody"
+        );
+        let not_found = &doc["paths"]["/blocks/{id}"]["get"]["responses"]["404"];
+        assert_eq!(
+            not_found["content"]["application/json"]["schema"]["$ref"],
+            "#/components/schemas/ErrorResponse"
+        );
+    }
+}
@@ -0,0 +1,1126 @@
+use crate::amount::Amount;
+use crate::blockchain::{Block, Chain, Transaction};
+use crate::mining;
+use crate::node::Node;
+use crate::vm::{self, Op};
+use axum::extract::State;
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use axum::routing::post;
+use axum::{Json, Router};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::error::Error;
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+pub const PARSE_ERROR: i64 = -32700;
+pub const INVALID_REQUEST: i64 = -32600;
+pub const METHOD_NOT_FOUND: i64 = -32601;
+pub const INVALID_PARAMS: i64 = -32602;
+pub const INTERNAL_ERROR: i64 = -32603;
+/// Something asked for does not exist: an account, block, transaction...
+pub const NOT_FOUND: i64 = -32001;
+/// The chain refused the request, e.g. an invalid transaction. The node's
+/// log has the reason.
+pub const REJECTED: i64 = -32002;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RpcError {
+    pub code: i64,
+    pub message: String,
+}
+
+impl RpcError {
+    pub fn new(code: i64, message: impl Into<String>) -> RpcError {
+        RpcError {
+            code,
+            message: message.into(),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RpcResponse {
+    pub jsonrpc: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub result: Option<Value>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<RpcError>,
+    pub id: Value,
+}
+
+impl RpcResponse {
+    pub fn new(id: Value, outcome: Result<Value, RpcError>) -> RpcResponse {
+        let (result, error) = match outcome {
+            Ok(result) => (Some(result), None),
+            Err(error) => (None, Some(error)),
+        };
+        RpcResponse {
+            jsonrpc: String::from("2.0"),
+            result,
+            error,
+            id,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct RpcRequest {
+    jsonrpc: String,
+    method: String,
+    #[serde(default)]
+    params: Value,
+}
+
+/// JSON-RPC 2.0 over HTTP: `POST /rpc` with a request or a batch of them.
+pub fn router(node: Node) -> Router {
+    Router::new().route("/rpc", post(handle)).with_state(node)
+}
+
+async fn handle(State(node): State<Node>, body: String) -> Response {
+    let request: Value = match serde_json::from_str(&body) {
+        Ok(request) => request,
+        Err(e) => {
+            let error = RpcError::new(PARSE_ERROR, e.to_string());
+            return Json(RpcResponse::new(Value::Null, Err(error))).into_response();
+        }
+    };
+    // Mining and chain access block, so keep them off the async workers.
+    let responses = tokio::task::spawn_blocking(move || match request {
+        Value::Array(batch) if !batch.is_empty() => {
+            let responses: Vec<RpcResponse> = batch
+                .into_iter()
+                .filter_map(|request| dispatch(&node, request))
+                .collect();
+            (!responses.is_empty()).then(|| json!(responses))
+        }
+        request => dispatch(&node, request).map(|response| json!(response)),
+    })
+    .await;
+    match responses {
+        Ok(Some(responses)) => Json(responses).into_response(),
+        // Only notifications: nothing to answer.
+        Ok(None) => StatusCode::NO_CONTENT.into_response(),
+        Err(e) => {
+            let error = RpcError::new(INTERNAL_ERROR, e.to_string());
+            Json(RpcResponse::new(Value::Null, Err(error))).into_response()
+        }
+    }
+}
+
+/// Runs one request. Notifications (requests without an id) get no
+/// response.
+fn dispatch(node: &Node, request: Value) -> Option<RpcResponse> {
+    let id = request.get("id").cloned();
+    let request = match serde_json::from_value::<RpcRequest>(request) {
+        Ok(request) if request.jsonrpc == "2.0" => request,
+        _ => {
+            let error = RpcError::new(INVALID_REQUEST, "invalid JSON-RPC 2.0 request");
+            return Some(RpcResponse::new(id.unwrap_or(Value::Null), Err(error)));
+        }
+    };
+    let outcome = call(node, &request.method, request.params);
+    id.map(|id| RpcResponse::new(id, outcome))
+}
+
+/// How long `call_remote` waits for a node to answer.
+const REMOTE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Calls `method` on the node serving JSON-RPC at `addr`, one HTTP
+/// request per call. Anything but a `200 OK` with a JSON-RPC response is
+/// an error.
+pub async fn call_remote(
+    addr: SocketAddr,
+    method: &str,
+    params: Value,
+) -> Result<Value, Box<dyn Error>> {
+    let body = json!({ "jsonrpc": "2.0", "id": 1, "method": method, "params": params });
+    let body = serde_json::to_vec(&body)?;
+    let head = format!(
+        "POST /rpc HTTP/1.1\r\nHost: {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        addr,
+        body.len()
+    );
+    let exchange = async {
+        let mut stream = TcpStream::connect(addr)
+            .await
+            .map_err(|e| format!("cannot reach {}: {}", addr, e))?;
+        stream.write_all(head.as_bytes()).await?;
+        stream.write_all(&body).await?;
+        let mut response = Vec::new();
+        stream.read_to_end(&mut response).await?;
+        Ok::<_, Box<dyn Error>>(response)
+    };
+    let response = tokio::time::timeout(REMOTE_TIMEOUT, exchange)
+        .await
+        .map_err(|_| format!("{} did not answer {} in time", addr, method))??;
+    let body = http_body(&response).map_err(|e| format!("{} from {}: {}", method, addr, e))?;
+    let response: RpcResponse = serde_json::from_slice(&body)
+        .map_err(|e| format!("{} from {}: malformed response: {}", method, addr, e))?;
+    match (response.result, response.error) {
+        (_, Some(error)) => Err(format!("{} on {}: {}", method, addr, error.message).into()),
+        (Some(result), None) => Ok(result),
+        (None, None) => Ok(Value::Null),
+    }
+}
+
+/// The body of an HTTP/1.1 `response`, which must be a `200 OK` with
+/// something in it. Chunked bodies are decoded.
+fn http_body(response: &[u8]) -> Result<Vec<u8>, String> {
+    let end = find(response, b"\r\n\r\n").ok_or("malformed HTTP response")?;
+    let head = std::str::from_utf8(&response[..end]).map_err(|e| e.to_string())?;
+    let mut lines = head.split("\r\n");
+    // E.g. `HTTP/1.1 200 OK`.
+    let status = lines.next().unwrap_or_default();
+    let status = status.split_once(' ').map_or(status, |(_, status)| status);
+    if status.split(' ').next() != Some("200") {
+        return Err(format!("HTTP {}", status));
+    }
+    let chunked = lines.any(|line| {
+        line.split_once(':').is_some_and(|(name, value)| {
+            name.trim().eq_ignore_ascii_case("transfer-encoding")
+                && value.to_ascii_lowercase().contains("chunked")
+        })
+    });
+    let body = &response[end + 4..];
+    let body = if chunked {
+        dechunk(body)?
+    } else {
+        body.to_vec()
+    };
+    if body.is_empty() {
+        return Err(String::from("empty response"));
+    }
+    Ok(body)
+}
+
+/// Decodes a `Transfer-Encoding: chunked` body.
+fn dechunk(mut body: &[u8]) -> Result<Vec<u8>, String> {
+    let truncated = || String::from("truncated chunked body");
+    let mut decoded = Vec::new();
+    loop {
+        let end = find(body, b"\r\n").ok_or_else(truncated)?;
+        let size = std::str::from_utf8(&body[..end]).map_err(|e| e.to_string())?;
+        let size = size.split(';').next().unwrap_or_default().trim();
+        let size = usize::from_str_radix(size, 16).map_err(|e| format!("bad chunk size: {}", e))?;
+        body = &body[end + 2..];
+        if size == 0 {
+            return Ok(decoded);
+        }
+        decoded.extend_from_slice(body.get(..size).ok_or_else(truncated)?);
+        body = body.get(size + 2..).ok_or_else(truncated)?;
+    }
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack
+        .windows(needle.len())
+        .position(|window| window == needle)
+}
+
+fn params<T: DeserializeOwned>(params: Value) -> Result<T, RpcError> {
+    serde_json::from_value(params).map_err(|e| RpcError::new(INVALID_PARAMS, e.to_string()))
+}
+
+fn to_value<T: Serialize>(value: &T) -> Result<Value, RpcError> {
+    serde_json::to_value(value).map_err(|e| RpcError::new(INTERNAL_ERROR, e.to_string()))
+}
+
+fn parse_amount(chain: &Chain, amount: &str) -> Result<Amount, RpcError> {
+    chain
+        .parse_amount(amount)
+        .map_err(|e| RpcError::new(INVALID_PARAMS, e.to_string()))
+}
+
+fn unknown_token(symbol: &str) -> RpcError {
+    RpcError::new(NOT_FOUND, format!("unknown token {}", symbol))
+}
+
+fn rejected(what: &str) -> RpcError {
+    RpcError::new(REJECTED, format!("{} was rejected", what))
+}
+
+/// A block with its hash and height added, the way API clients see it.
+pub fn block_json(block: &Block, height: u64) -> Result<Value, RpcError> {
+    let mut value = to_value(block)?;
+    let hash =
+        Chain::hash(block.header()).map_err(|e| RpcError::new(INTERNAL_ERROR, e.to_string()))?;
+    value["hash"] = json!(hash);
+    value["height"] = json!(height);
+    Ok(value)
+}
+
+#[derive(Deserialize)]
+struct AccountParams {
+    account: String,
+}
+
+#[derive(Deserialize)]
+struct BalanceAtParams {
+    account: String,
+    height: u64,
+    token: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct LedgerParams {
+    account: String,
+    token: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct HeightParams {
+    height: u64,
+}
+
+#[derive(Deserialize)]
+struct HashParams {
+    hash: String,
+}
+
+#[derive(Deserialize)]
+struct TxIdParams {
+    tx_id: String,
+}
+
+#[derive(Deserialize)]
+struct TokenParams {
+    symbol: String,
+}
+
+#[derive(Deserialize)]
+struct SendParams {
+    sender: String,
+    receiver: String,
+    amount: String,
+}
+
+#[derive(Deserialize)]
+struct DeployParams {
+    sender: String,
+    code: Vec<Op>,
+}
+
+#[derive(Deserialize)]
+struct CallContractParams {
+    sender: String,
+    contract: String,
+    #[serde(default)]
+    amount: Option<String>,
+    #[serde(default)]
+    args: Vec<ContractArg>,
+    gas_limit: u64,
+}
+
+/// A call argument: a word, or an address passed as its `vm::address_word`.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum ContractArg {
+    Word(u64),
+    Address(String),
+}
+
+impl ContractArg {
+    fn word(&self) -> u64 {
+        match self {
+            ContractArg::Word(word) => *word,
+            ContractArg::Address(address) => vm::address_word(address),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct SubmitParams {
+    transaction: Transaction,
+}
+
+#[derive(Deserialize)]
+struct LockHtlcParams {
+    sender: String,
+    receiver: String,
+    amount: String,
+    hash_lock: String,
+    timeout_height: u64,
+}
+
+#[derive(Deserialize)]
+struct ClaimHtlcParams {
+    htlc_id: String,
+    preimage: String,
+}
+
+#[derive(Deserialize)]
+struct HtlcParams {
+    htlc_id: String,
+}
+
+fn call(node: &Node, method: &str, params_value: Value) -> Result<Value, RpcError> {
+    match method {
+        "get_chain_info" => {
+            let chain = node.chain();
+            let spec = chain.get_spec();
+            Ok(json!({
+                "network_id": spec.network_id,
+                "token_name": spec.token_name,
+                "token_symbol": spec.token_symbol,
+                "decimals": spec.decimals,
+                "ledger": spec.ledger,
+                "height": chain.get_chain().len() - 1,
+                "tip_hash": chain.last_hash(),
+                "genesis_hash": chain.genesis_hash(),
+                "difficulty": chain.difficulty(),
+                "pending_transactions": chain.pending_transactions().len(),
+            }))
+        }
+        "get_balance" => {
+            let AccountParams { account } = params(params_value)?;
+            let chain = node.chain();
+            let balance = chain
+                .get_balance(&account)
+                .ok_or_else(|| RpcError::new(NOT_FOUND, format!("unknown account {}", account)))?;
+            Ok(json!({
+                "account": account,
+                "balance": chain.format_amount(balance),
+                "base_units": balance,
+                "nonce": chain.next_nonce(&account),
+            }))
+        }
+        "get_balance_proof" => {
+            let AccountParams { account } = params(params_value)?;
+            let chain = node.chain();
+            Ok(json!({
+                "height": chain.get_chain().len() - 1,
+                "block_hash": chain.last_hash(),
+                "proof": chain.prove_balance(&account),
+            }))
+        }
+        "get_balance_at" => {
+            let BalanceAtParams {
+                account,
+                height,
+                token,
+            } = params(params_value)?;
+            let chain = node.chain();
+            if height >= chain.get_chain().len() as u64 {
+                return Err(RpcError::new(
+                    NOT_FOUND,
+                    format!("no block at height {}", height),
+                ));
+            }
+            // Accounts the ledger has not seen by then held nothing.
+            let balance = match &token {
+                Some(symbol) => {
+                    chain
+                        .get_token(symbol)
+                        .ok_or_else(|| unknown_token(symbol))?;
+                    chain.token_balance_at(&account, symbol, height)
+                }
+                None => chain.balance_at(&account, height),
+            }
+            .unwrap_or_default();
+            Ok(json!({
+                "account": account,
+                "height": height,
+                "token": token,
+                "balance": chain.format_amount_in(token.as_deref(), balance),
+                "base_units": balance,
+            }))
+        }
+        "get_account_ledger" => {
+            let LedgerParams { account, token } = params(params_value)?;
+            let chain = node.chain();
+            if let Some(symbol) = &token {
+                chain
+                    .get_token(symbol)
+                    .ok_or_else(|| unknown_token(symbol))?;
+            }
+            to_value(&chain.account_ledger(&account, token.as_deref()))
+        }
+        "get_scheduled_transfers" => {
+            let AccountParams { account } = params(params_value)?;
+            let chain = node.chain();
+            let scheduled: Vec<Value> = chain
+                .scheduled_transfers(&account)
+                .into_iter()
+                .map(|tx| json!({ "id": tx.id(), "transaction": tx }))
+                .collect();
+            Ok(json!(scheduled))
+        }
+        "get_block_by_height" => {
+            let HeightParams { height } = params(params_value)?;
+            let chain = node.chain();
+            let block = chain.get_block(height).ok_or_else(|| {
+                RpcError::new(NOT_FOUND, format!("no block at height {}", height))
+            })?;
+            block_json(block, height)
+        }
+        "get_block_by_hash" => {
+            let HashParams { hash } = params(params_value)?;
+            let chain = node.chain();
+            let height = chain
+                .block_height(&hash)
+                .ok_or_else(|| RpcError::new(NOT_FOUND, format!("no block with hash {}", hash)))?;
+            let block = chain.get_block(height).expect("Indexed block exists");
+            block_json(block, height)
+        }
+        "get_supply" => {
+            let HeightParams { height } = params(params_value)?;
+            let chain = node.chain();
+            let supply = chain.circulating_supply(height as usize).ok_or_else(|| {
+                RpcError::new(NOT_FOUND, format!("no block at height {}", height))
+            })?;
+            Ok(json!({
+                "height": height,
+                "supply": chain.format_amount(supply),
+                "base_units": supply,
+            }))
+        }
+        "get_transaction" => {
+            let TxIdParams { tx_id } = params(params_value)?;
+            let chain = node.chain();
+            let tx = chain.get_transaction(&tx_id).ok_or_else(|| {
+                RpcError::new(NOT_FOUND, format!("unknown transaction {}", tx_id))
+            })?;
+            Ok(json!({
+                "transaction": tx,
+                "status": chain.transaction_status(&tx_id),
+                "receipt": chain.get_receipt(&tx_id),
+                "contract_receipt": chain.contract_receipt(&tx_id),
+            }))
+        }
+        "send_transaction" => {
+            let SendParams {
+                sender,
+                receiver,
+                amount,
+            } = params(params_value)?;
+            let mut chain = node.chain();
+            let amount = parse_amount(&chain, &amount)?;
+            let id = chain
+                .new_transaction(sender, receiver, amount)
+                .ok_or_else(|| rejected("transaction"))?;
+            Ok(json!(id))
+        }
+        "submit_transaction" => {
+            let SubmitParams { transaction } = params(params_value)?;
+            let id = transaction.id();
+            if !node.chain().submit_transaction(transaction) {
+                return Err(rejected("transaction"));
+            }
+            Ok(json!(id))
+        }
+        "deploy_contract" => {
+            let DeployParams { sender, code } = params(params_value)?;
+            let address = node
+                .chain()
+                .deploy_contract(sender, code)
+                .ok_or_else(|| rejected("deployment"))?;
+            Ok(json!(address))
+        }
+        "call_contract" => {
+            let CallContractParams {
+                sender,
+                contract,
+                amount,
+                args,
+                gas_limit,
+            } = params(params_value)?;
+            let mut chain = node.chain();
+            let amount = match amount {
+                Some(amount) => parse_amount(&chain, &amount)?,
+                None => Amount::ZERO,
+            };
+            let args = args.iter().map(ContractArg::word).collect();
+            let id = chain
+                .call_contract(sender, contract, amount, args, gas_limit)
+                .ok_or_else(|| rejected("contract call"))?;
+            Ok(json!(id))
+        }
+        "mine_block" => {
+            let height = mining::mine_block(node).ok_or_else(|| rejected("mined block"))?;
+            let chain = node.chain();
+            let block = chain
+                .get_block(height)
+                .ok_or_else(|| rejected("mined block"))?;
+            let hash = Chain::hash(block.header())
+                .map_err(|e| RpcError::new(INTERNAL_ERROR, e.to_string()))?;
+            Ok(json!({ "height": height, "hash": hash }))
+        }
+        "create_account" => {
+            let AccountParams { account } = params(params_value)?;
+            if !node.chain().create_account(account.clone()) {
+                return Err(RpcError::new(
+                    REJECTED,
+                    format!("account {} already exists", account),
+                ));
+            }
+            Ok(json!(account))
+        }
+        "list_peers" => {
+            let peers = node.peers().lock().expect("Peer list lock poisoned");
+            let mut peers: Vec<_> = peers.values().cloned().collect();
+            peers.sort_by_key(|peer| peer.addr);
+            to_value(&peers)
+        }
+        "list_tokens" => to_value(&node.chain().list_tokens()),
+        "get_token_holders" => {
+            let TokenParams { symbol } = params(params_value)?;
+            let chain = node.chain();
+            let token = chain
+                .get_token(&symbol)
+                .ok_or_else(|| unknown_token(&symbol))?;
+            let holders: Vec<Value> = chain
+                .token_holders(&symbol)
+                .into_iter()
+                .map(|(account, balance)| {
+                    json!({
+                        "account": account,
+                        "balance": token.format_amount(balance),
+                        "base_units": balance,
+                    })
+                })
+                .collect();
+            Ok(json!(holders))
+        }
+        "lock_htlc" => {
+            let LockHtlcParams {
+                sender,
+                receiver,
+                amount,
+                hash_lock,
+                timeout_height,
+            } = params(params_value)?;
+            let mut chain = node.chain();
+            let amount = parse_amount(&chain, &amount)?;
+            let id = chain
+                .lock_htlc(sender, receiver, amount, hash_lock, timeout_height)
+                .ok_or_else(|| rejected("HTLC"))?;
+            Ok(json!(id))
+        }
+        "claim_htlc" => {
+            let ClaimHtlcParams { htlc_id, preimage } = params(params_value)?;
+            if !node.chain().claim_htlc(htlc_id, preimage) {
+                return Err(rejected("claim"));
+            }
+            Ok(json!(true))
+        }
+        "refund_htlc" => {
+            let HtlcParams { htlc_id } = params(params_value)?;
+            if !node.chain().refund_htlc(htlc_id) {
+                return Err(rejected("refund"));
+            }
+            Ok(json!(true))
+        }
+        "get_htlc" => {
+            let HtlcParams { htlc_id } = params(params_value)?;
+            let chain = node.chain();
+            Ok(json!({
+                "htlc": chain.get_htlc(&htlc_id),
+                "preimage": chain.htlc_preimage(&htlc_id),
+            }))
+        }
+        _ => Err(RpcError::new(
+            METHOD_NOT_FOUND,
+            format!("unknown method {}", method),
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::emission::RewardSchedule;
+    use crate::smt::AccountProof;
+    use crate::spec::LedgerModel;
+    use crate::testing::{self, units};
+    use std::collections::HashMap;
+
+    fn request(id: Option<u64>, method: &str, params: Value) -> Value {
+        let mut request = json!({ "jsonrpc": "2.0", "method": method, "params": params });
+        if let Some(id) = id {
+            request["id"] = json!(id);
+        }
+        request
+    }
+
+    fn call_ok(node: &Node, method: &str, params: Value) -> Value {
+        let response = dispatch(node, request(Some(1), method, params)).unwrap();
+        assert_eq!(response.error, None, "{}", method);
+        response.result.unwrap()
+    }
+
+    fn error_code(node: &Node, request: Value) -> i64 {
+        dispatch(node, request).unwrap().error.unwrap().code
+    }
+
+    async fn handled(node: &Node, body: &str) -> (StatusCode, Value) {
+        let response = handle(State(node.clone()), body.to_string()).await;
+        let status = response.status();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        (status, serde_json::from_slice(&body).unwrap_or(Value::Null))
+    }
+
+    #[test]
+    fn answers_requests_with_their_id() {
+        let (node, _p2p_rx) = testing::node(&[("alice", 100)]);
+        let response = dispatch(&node, request(Some(7), "get_chain_info", Value::Null)).unwrap();
+        assert_eq!(response.id, json!(7));
+        assert_eq!(response.jsonrpc, "2.0");
+        assert_eq!(response.result.unwrap()["height"], json!(0));
+
+        let balance = call_ok(&node, "get_balance", json!({ "account": "alice" }));
+        assert_eq!(balance["base_units"], json!(100));
+        assert_eq!(balance["nonce"], json!(0));
+    }
+
+    #[test]
+    fn reports_errors_with_their_codes() {
+        let (node, _p2p_rx) = testing::node(&[("alice", 100)]);
+        let mut wrong_version = request(Some(1), "get_chain_info", Value::Null);
+        wrong_version["jsonrpc"] = json!("1.0");
+        assert_eq!(error_code(&node, wrong_version), INVALID_REQUEST);
+        assert_eq!(error_code(&node, json!([1, 2])), INVALID_REQUEST);
+        assert_eq!(
+            error_code(&node, request(Some(1), "no_such_method", Value::Null)),
+            METHOD_NOT_FOUND
+        );
+        assert_eq!(
+            error_code(
+                &node,
+                request(Some(1), "get_balance", json!({ "who": "alice" }))
+            ),
+            INVALID_PARAMS
+        );
+        assert_eq!(
+            error_code(
+                &node,
+                request(Some(1), "get_balance", json!({ "account": "bob" }))
+            ),
+            NOT_FOUND
+        );
+        let overdraft = json!({ "sender": "alice", "receiver": "bob", "amount": "101" });
+        assert_eq!(
+            error_code(&node, request(Some(1), "send_transaction", overdraft)),
+            REJECTED
+        );
+        let malformed = json!({ "sender": "alice", "receiver": "bob", "amount": "1.5" });
+        assert_eq!(
+            error_code(&node, request(Some(1), "send_transaction", malformed)),
+            INVALID_PARAMS
+        );
+    }
+
+    #[test]
+    fn notifications_get_no_response() {
+        let (node, _p2p_rx) = testing::node(&[("alice", 100)]);
+        assert!(dispatch(&node, request(None, "get_chain_info", Value::Null)).is_none());
+        assert!(dispatch(&node, request(None, "no_such_method", Value::Null)).is_none());
+        // Notifications still take effect.
+        let params = json!({ "account": "bob" });
+        assert!(dispatch(&node, request(None, "create_account", params.clone())).is_none());
+        assert_eq!(
+            call_ok(&node, "get_balance", params)["base_units"],
+            json!(0)
+        );
+    }
+
+    #[tokio::test]
+    async fn sends_and_mines_transactions() {
+        let (node, _p2p_rx) = testing::node(&[("alice", 100)]);
+        let params = json!({ "sender": "alice", "receiver": "bob", "amount": "30" });
+        let id = call_ok(&node, "send_transaction", params);
+        let tx = call_ok(&node, "get_transaction", json!({ "tx_id": id }));
+        assert_eq!(tx["status"], json!("Pending"));
+
+        let mined = call_ok(&node, "mine_block", Value::Null);
+        assert_eq!(mined["height"], json!(1));
+        let tx = call_ok(&node, "get_transaction", json!({ "tx_id": id }));
+        assert_eq!(tx["status"]["Confirmed"]["block_height"], json!(1));
+        let block = call_ok(&node, "get_block_by_hash", json!({ "hash": mined["hash"] }));
+        assert_eq!(block["height"], json!(1));
+        let balance = call_ok(&node, "get_balance", json!({ "account": "bob" }));
+        assert_eq!(balance["base_units"], json!(30));
+    }
+
+    #[tokio::test]
+    async fn supply_follows_halvings_and_burned_gas() {
+        let mut spec = testing::spec(LedgerModel::Account, &[("alice", 100)]);
+        spec.reward = RewardSchedule {
+            initial_reward: units(8),
+            halving_interval: 2,
+            tail_emission: Amount::ZERO,
+            max_supply: units(1000),
+        };
+        let (node, _p2p_rx) = testing::node_with(spec);
+        let code = vec![Op::Push(1), Op::Pop, Op::Stop];
+        let contract = node.chain().deploy_contract("alice".into(), code).unwrap();
+        for _ in 0..3 {
+            call_ok(&node, "mine_block", Value::Null);
+        }
+        let call = node
+            .chain()
+            .call_contract("alice".into(), contract.clone(), Amount::ZERO, vec![], 100)
+            .unwrap();
+        call_ok(&node, "mine_block", Value::Null);
+
+        let supply: Vec<u64> = (0..=4)
+            .map(|height| {
+                let supply = call_ok(&node, "get_supply", json!({ "height": height }));
+                assert_eq!(supply["height"], json!(height));
+                supply["base_units"].as_u64().unwrap()
+            })
+            .collect();
+        assert_eq!(supply[0], 100);
+        assert_eq!(supply[2] - supply[1], 8);
+        assert_eq!(supply[3] - supply[2], 4);
+        let burned = node.chain().contract_receipt(&call).unwrap().gas_used;
+        assert!(burned > 0);
+        assert_eq!(supply[4], supply[3] + 4 - burned);
+
+        // Whatever circulates is held by someone.
+        let chain = node.chain();
+        let held: u64 = ["alice", "miner", &contract]
+            .iter()
+            .map(|account| chain.get_balance(account).unwrap_or_default().base_units())
+            .sum();
+        assert_eq!(supply[4], held);
+        drop(chain);
+        assert_eq!(
+            error_code(
+                &node,
+                request(Some(1), "get_supply", json!({ "height": 5 }))
+            ),
+            NOT_FOUND
+        );
+    }
+
+    #[tokio::test]
+    async fn lists_tokens_and_their_holders() {
+        let (node, _p2p_rx) = testing::node(&[("alice", 100)]);
+        {
+            let mut chain = node.chain();
+            assert!(chain.issue_token("alice".into(), "SLV".into(), "Silver".into(), 0, units(50)));
+            assert!(chain.issue_token("alice".into(), "GLD".into(), "Gold".into(), 2, units(1000)));
+            assert!(chain.generate_new_block());
+            assert!(chain.new_token_transaction(
+                "alice".into(),
+                "bob".into(),
+                "GLD".into(),
+                units(250)
+            ));
+            assert!(chain.generate_new_block());
+        }
+
+        let tokens = call_ok(&node, "list_tokens", Value::Null);
+        let symbols: Vec<&Value> = tokens
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|t| &t["symbol"])
+            .collect();
+        assert_eq!(symbols, ["GLD", "SLV"]);
+        assert_eq!(tokens[0]["issuer"], json!("alice"));
+
+        let holders = call_ok(&node, "get_token_holders", json!({ "symbol": "GLD" }));
+        assert_eq!(
+            holders,
+            json!([
+                { "account": "alice", "balance": "7.5 GLD", "base_units": 750 },
+                { "account": "bob", "balance": "2.5 GLD", "base_units": 250 },
+            ])
+        );
+        assert_eq!(
+            error_code(
+                &node,
+                request(Some(1), "get_token_holders", json!({ "symbol": "XYZ" }))
+            ),
+            NOT_FOUND
+        );
+    }
+
+    #[tokio::test]
+    async fn reads_balances_back_in_time() {
+        let (node, _p2p_rx) = testing::node(&[("alice", 100)]);
+        for amount in [30, 20] {
+            let params =
+                json!({ "sender": "alice", "receiver": "bob", "amount": amount.to_string() });
+            call_ok(&node, "send_transaction", params);
+            call_ok(&node, "mine_block", Value::Null);
+        }
+        assert!(node
+            .chain()
+            .issue_token("bob".into(), "GLD".into(), "Gold".into(), 0, units(7)));
+        call_ok(&node, "mine_block", Value::Null);
+
+        let balance_at = |height: u64, token: Option<&str>| {
+            let params = json!({ "account": "bob", "height": height, "token": token });
+            call_ok(&node, "get_balance_at", params)
+        };
+        assert_eq!(balance_at(0, None)["base_units"], json!(0));
+        assert_eq!(balance_at(1, None)["balance"], json!("30 TST"));
+        assert_eq!(balance_at(3, None)["base_units"], json!(50));
+        assert_eq!(balance_at(2, Some("GLD"))["base_units"], json!(0));
+        assert_eq!(balance_at(3, Some("GLD"))["balance"], json!("7 GLD"));
+        for params in [
+            json!({ "account": "bob", "height": 4 }),
+            json!({ "account": "bob", "height": 3, "token": "XYZ" }),
+        ] {
+            let request = request(Some(1), "get_balance_at", params);
+            assert_eq!(error_code(&node, request), NOT_FOUND);
+        }
+
+        let ledger = call_ok(&node, "get_account_ledger", json!({ "account": "bob" }));
+        let entries: Vec<(&Value, &Value, &Value)> = ledger
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|entry| (&entry["block_height"], &entry["credit"], &entry["balance"]))
+            .collect();
+        assert_eq!(
+            entries,
+            [
+                (&json!(1), &json!(30), &json!(30)),
+                (&json!(2), &json!(20), &json!(50))
+            ]
+        );
+        let ledger = call_ok(
+            &node,
+            "get_account_ledger",
+            json!({ "account": "bob", "token": "GLD" }),
+        );
+        assert_eq!(ledger[0]["balance"], json!(7));
+    }
+
+    #[tokio::test]
+    async fn lists_scheduled_transfers_in_unlock_order() {
+        let (node, _p2p_rx) = testing::node(&[("alice", 100)]);
+        {
+            let mut chain = node.chain();
+            assert!(chain.schedule_vesting("alice".into(), "bob".into(), units(10), 5, 2, 3));
+            assert!(chain.new_locked_transaction(
+                "alice".into(),
+                "carol".into(),
+                units(5),
+                Some(6),
+                None
+            ));
+            assert!(chain
+                .new_transaction("alice".into(), "bob".into(), units(1))
+                .is_some());
+        }
+
+        let scheduled = call_ok(
+            &node,
+            "get_scheduled_transfers",
+            json!({ "account": "bob" }),
+        );
+        let unlocks: Vec<(&Value, &Value)> = scheduled
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|tx| {
+                (
+                    &tx["transaction"]["lock_height"],
+                    &tx["transaction"]["amount"],
+                )
+            })
+            .collect();
+        assert_eq!(
+            unlocks,
+            [
+                (&json!(5), &json!(3)),
+                (&json!(7), &json!(3)),
+                (&json!(9), &json!(4))
+            ]
+        );
+        let id = scheduled[0]["id"].as_str().unwrap();
+        assert!(node.chain().get_transaction(id).is_some());
+
+        let scheduled = call_ok(
+            &node,
+            "get_scheduled_transfers",
+            json!({ "account": "alice" }),
+        );
+        assert_eq!(scheduled.as_array().unwrap().len(), 4);
+        assert_eq!(scheduled[1]["transaction"]["receiver"], json!("carol"));
+    }
+
+    #[tokio::test]
+    async fn balance_proofs_verify_against_the_tip_header() {
+        let (node, _p2p_rx) = testing::node(&[("alice", 100)]);
+        let params = json!({ "sender": "alice", "receiver": "bob", "amount": "30" });
+        call_ok(&node, "send_transaction", params);
+        call_ok(&node, "mine_block", Value::Null);
+
+        for (account, balance) in [("bob", Some(units(30))), ("nobody", None)] {
+            let answer = call_ok(&node, "get_balance_proof", json!({ "account": account }));
+            let block = call_ok(
+                &node,
+                "get_block_by_hash",
+                json!({ "hash": answer["block_hash"] }),
+            );
+            assert_eq!(block["height"], answer["height"]);
+            let state_root = block["header"]["state_root"].as_str().unwrap();
+            let mut proof: AccountProof = serde_json::from_value(answer["proof"].clone()).unwrap();
+            assert_eq!(proof.balance, balance);
+            assert!(proof.verify(state_root));
+            proof.balance = Some(units(1000));
+            assert!(!proof.verify(state_root));
+        }
+    }
+
+    #[tokio::test]
+    async fn runs_the_example_escrow() {
+        let (node, _p2p_rx) = testing::node(&[("alice", 1000), ("bob", 50), ("carol", 50)]);
+        let code: Value = serde_json::from_str(include_str!("../contracts/escrow.json")).unwrap();
+        let deploy_fee = code.as_array().unwrap().len() as u64 * vm::DEPLOY_GAS_PER_OP;
+        let escrow = call_ok(
+            &node,
+            "deploy_contract",
+            json!({ "sender": "alice", "code": code }),
+        );
+        call_ok(&node, "mine_block", Value::Null);
+
+        let mut gas = HashMap::new();
+        for (sender, params) in [
+            (
+                "alice",
+                json!({ "amount": "100", "args": [0, "bob", "carol"] }),
+            ),
+            ("carol", json!({ "args": [1] })),
+            ("bob", json!({ "args": [2] })),
+        ] {
+            let mut params = params;
+            params["sender"] = json!(sender);
+            params["contract"] = escrow.clone();
+            params["gas_limit"] = json!(500);
+            let id = call_ok(&node, "call_contract", params);
+            call_ok(&node, "mine_block", Value::Null);
+            let tx = call_ok(&node, "get_transaction", json!({ "tx_id": id }));
+            let receipt = &tx["contract_receipt"];
+            assert_eq!(receipt["error"], Value::Null, "{}", sender);
+            gas.insert(sender, receipt["gas_used"].as_u64().unwrap());
+        }
+
+        let balance = |account: &str| {
+            call_ok(&node, "get_balance", json!({ "account": account }))["base_units"]
+                .as_u64()
+                .unwrap()
+        };
+        assert_eq!(balance("alice"), 1000 - deploy_fee - 100 - gas["alice"]);
+        assert_eq!(balance("bob"), 50 + 100 - gas["bob"]);
+        assert_eq!(balance("carol"), 50 - gas["carol"]);
+
+        let broke = json!({ "sender": "dave", "code": [{ "Push": 1 }, "Stop"] });
+        assert_eq!(
+            error_code(&node, request(Some(1), "deploy_contract", broke)),
+            REJECTED
+        );
+    }
+
+    #[tokio::test]
+    async fn handles_batches_and_parse_errors() {
+        let (node, _p2p_rx) = testing::node(&[("alice", 100)]);
+        let batch = json!([
+            request(Some(1), "get_chain_info", Value::Null),
+            request(None, "get_chain_info", Value::Null),
+            request(Some(2), "no_such_method", Value::Null),
+        ]);
+        let (status, body) = handled(&node, &batch.to_string()).await;
+        assert_eq!(status, StatusCode::OK);
+        let responses = body.as_array().unwrap();
+        assert_eq!(responses.len(), 2);
+        assert_eq!(responses[0]["id"], json!(1));
+        assert_eq!(responses[1]["error"]["code"], json!(METHOD_NOT_FOUND));
+
+        let (status, body) = handled(&node, "{not json").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["error"]["code"], json!(PARSE_ERROR));
+        assert_eq!(body["id"], Value::Null);
+
+        let (_, body) = handled(&node, "[]").await;
+        assert_eq!(body["error"]["code"], json!(INVALID_REQUEST));
+
+        let notification = request(None, "get_chain_info", Value::Null);
+        let (status, _) = handled(&node, &json!([notification]).to_string()).await;
+        assert_eq!(status, StatusCode::NO_CONTENT);
+    }
+
+    /// Accepts one request on a local port and answers it with `response`.
+    async fn answering(response: &'static str) -> SocketAddr {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut request = Vec::new();
+            let mut buffer = [0; 1024];
+            while !request.ends_with(b"}") {
+                let read = stream.read(&mut buffer).await.unwrap();
+                request.extend_from_slice(&buffer[..read]);
+            }
+            stream.write_all(response.as_bytes()).await.unwrap();
+        });
+        addr
+    }
+
+    #[tokio::test]
+    async fn remote_calls_need_a_200_with_a_body() {
+        for (response, expected) in [
+            (
+                "HTTP/1.1 500 Internal Server Error\r\nContent-Length: 0\r\n\r\n",
+                "HTTP 500 Internal Server Error",
+            ),
+            ("HTTP/1.1 204 No Content\r\n\r\n", "HTTP 204 No Content"),
+            (
+                "HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n",
+                "empty response",
+            ),
+            ("garbage", "malformed HTTP response"),
+        ] {
+            let addr = answering(response).await;
+            let error = call_remote(addr, "get_chain_info", Value::Null)
+                .await
+                .unwrap_err();
+            assert!(error.to_string().contains(expected), "{}", error);
+        }
+    }
+
+    #[tokio::test]
+    async fn remote_calls_decode_chunked_bodies() {
+        let addr = answering(concat!(
+            "HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n",
+            "11\r\n{\"jsonrpc\":\"2.0\",\r\n",
+            "15;ext=1\r\n\"result\":7,\"id\":1}   \r\n",
+            "0\r\n\r\n",
+        ))
+        .await;
+        let result = call_remote(addr, "get_chain_info", Value::Null)
+            .await
+            .unwrap();
+        assert_eq!(result, json!(7));
+    }
+
+    #[tokio::test]
+    async fn calls_remote_nodes() {
+        let (node, _p2p_rx) = testing::node(&[("alice", 100)]);
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let app = router(node);
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        let info = call_remote(addr, "get_chain_info", Value::Null)
+            .await
+            .unwrap();
+        assert_eq!(info["height"], json!(0));
+        let error = call_remote(addr, "get_balance", json!({ "account": "bob" }))
+            .await
+            .unwrap_err();
+        assert!(error.to_string().contains("unknown account bob"));
+    }
+}
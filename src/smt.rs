@@ -0,0 +1,223 @@
+use crate::amount::Amount;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+/// Depth of the tree: one level per bit of the account key.
+const DEPTH: usize = 256;
+
+type Hash = [u8; 32];
+
+/// Hash of an empty subtree at any level.
+const EMPTY: Hash = [0; 32];
+
+/// A sparse Merkle tree over the whole state. Every entry is a leaf whose
+/// key is a namespaced path, such as `account/<address>` for a native
+/// balance, placed at `sha256(key)`; see `State::leaves` for the rest.
+/// Empty entries, like zero balances, are left out, so the root only
+/// depends on what is actually held.
+pub struct StateTree {
+    leaves: Vec<(Hash, Hash)>,
+}
+
+/// Shows that `account` holds `balance` (or nothing, when `None`) under a
+/// given state root. Only siblings that are not empty subtrees are included,
+/// keyed by their depth.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AccountProof {
+    pub account: String,
+    pub balance: Option<Amount>,
+    siblings: Vec<(u16, String)>,
+}
+
+/// Key of the leaf holding the native balance of `account`.
+pub fn balance_key(account: &str) -> String {
+    format!("account/{}", account)
+}
+
+/// Leaf value of an amount, or `None` for zero, which is left out.
+pub fn amount_value(amount: Amount) -> Option<Vec<u8>> {
+    (!amount.is_zero()).then(|| amount.base_units().to_be_bytes().to_vec())
+}
+
+fn key_hash(key: &str) -> Hash {
+    Sha256::digest(key.as_bytes()).into()
+}
+
+fn leaf_hash(key: &Hash, value: &[u8]) -> Hash {
+    let mut hasher = Sha256::new();
+    hasher.update(key);
+    hasher.update(value);
+    hasher.finalize().into()
+}
+
+fn node_hash(left: &Hash, right: &Hash) -> Hash {
+    if left == &EMPTY && right == &EMPTY {
+        return EMPTY;
+    }
+    let mut hasher = Sha256::new();
+    hasher.update(left);
+    hasher.update(right);
+    hasher.finalize().into()
+}
+
+fn bit(key: &Hash, depth: usize) -> u8 {
+    (key[depth / 8] >> (7 - depth % 8)) & 1
+}
+
+impl StateTree {
+    /// A tree over `leaves`, keyed by path. Each key must appear once.
+    pub fn new<K, V, I>(leaves: I) -> StateTree
+    where
+        K: AsRef<str>,
+        V: AsRef<[u8]>,
+        I: IntoIterator<Item = (K, V)>,
+    {
+        let mut leaves: Vec<(Hash, Hash)> = leaves
+            .into_iter()
+            .map(|(key, value)| {
+                let key = key_hash(key.as_ref());
+                (key, leaf_hash(&key, value.as_ref()))
+            })
+            .collect();
+        leaves.sort();
+        StateTree { leaves }
+    }
+
+    pub fn root(&self) -> String {
+        hex::encode(StateTree::subtree(&self.leaves, 0))
+    }
+
+    /// Root of the subtree at `depth` holding `leaves`, which must all share
+    /// the same first `depth` key bits and be sorted by key.
+    fn subtree(leaves: &[(Hash, Hash)], depth: usize) -> Hash {
+        match leaves {
+            [] => EMPTY,
+            [(_, leaf)] if depth == DEPTH => *leaf,
+            _ => {
+                let split = leaves.partition_point(|(key, _)| bit(key, depth) == 0);
+                let left = StateTree::subtree(&leaves[..split], depth + 1);
+                let right = StateTree::subtree(&leaves[split..], depth + 1);
+                node_hash(&left, &right)
+            }
+        }
+    }
+
+    pub fn prove(&self, account: &str, balance: Option<Amount>) -> AccountProof {
+        let key = key_hash(&balance_key(account));
+        let mut siblings = Vec::new();
+        let mut leaves = &self.leaves[..];
+        for depth in 0..DEPTH {
+            let split = leaves.partition_point(|(k, _)| bit(k, depth) == 0);
+            let (ours, theirs) = if bit(&key, depth) == 0 {
+                (&leaves[..split], &leaves[split..])
+            } else {
+                (&leaves[split..], &leaves[..split])
+            };
+            let sibling = StateTree::subtree(theirs, depth + 1);
+            if sibling != EMPTY {
+                siblings.push((depth as u16, hex::encode(sibling)));
+            }
+            leaves = ours;
+        }
+        AccountProof {
+            account: account.to_string(),
+            balance: balance.filter(|b| !b.is_zero()),
+            siblings,
+        }
+    }
+}
+
+impl AccountProof {
+    /// Recomputes the root from the proof and compares it to `state_root`.
+    pub fn verify(&self, state_root: &str) -> bool {
+        let key = key_hash(&balance_key(&self.account));
+        let mut hash = match self.balance.and_then(amount_value) {
+            Some(value) => leaf_hash(&key, &value),
+            None => EMPTY,
+        };
+        let mut siblings = self.siblings.iter().rev().peekable();
+        for depth in (0..DEPTH).rev() {
+            let sibling = match siblings.next_if(|(d, _)| *d as usize == depth) {
+                Some((_, sibling)) => {
+                    match hex::decode(sibling).ok().and_then(|s| s.try_into().ok()) {
+                        Some(sibling) => sibling,
+                        None => return false,
+                    }
+                }
+                None => EMPTY,
+            };
+            hash = if bit(&key, depth) == 0 {
+                node_hash(&hash, &sibling)
+            } else {
+                node_hash(&sibling, &hash)
+            };
+        }
+        siblings.next().is_none() && hex::encode(hash) == state_root
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::units;
+
+    fn tree(balances: &[(&str, u64)]) -> StateTree {
+        StateTree::new(balances.iter().filter_map(|(account, amount)| {
+            Some((balance_key(account), amount_value(units(*amount))?))
+        }))
+    }
+
+    #[test]
+    fn empty_entries_do_not_change_the_root() {
+        assert_eq!(tree(&[]).root(), hex::encode(EMPTY));
+        assert_eq!(
+            tree(&[("alice", 5), ("bob", 0)]).root(),
+            tree(&[("alice", 5)]).root()
+        );
+        assert_eq!(
+            tree(&[("alice", 5), ("bob", 7)]).root(),
+            tree(&[("bob", 7), ("alice", 5)]).root()
+        );
+        assert_ne!(tree(&[("alice", 5)]).root(), tree(&[("alice", 6)]).root());
+    }
+
+    #[test]
+    fn proves_balances_and_absence() {
+        let tree = tree(&[("alice", 5), ("bob", 7), ("carol", 9)]);
+        let root = tree.root();
+        assert!(tree.prove("bob", Some(units(7))).verify(&root));
+        assert!(!tree.prove("bob", Some(units(8))).verify(&root));
+        assert!(!tree.prove("bob", None).verify(&root));
+        assert!(tree.prove("dave", None).verify(&root));
+        assert!(tree.prove("dave", Some(Amount::ZERO)).verify(&root));
+        assert!(!tree.prove("dave", Some(units(1))).verify(&root));
+        assert!(!tree
+            .prove("alice", Some(units(5)))
+            .verify(&hex::encode(EMPTY)));
+    }
+
+    #[test]
+    fn namespaces_keep_other_state_apart_from_balances() {
+        let tree = StateTree::new([
+            ("token/GLD/alice", amount_value(units(5)).unwrap()),
+            ("nonce/alice", 1u64.to_be_bytes().to_vec()),
+        ]);
+        let root = tree.root();
+        assert!(tree.prove("alice", None).verify(&root));
+        assert!(!tree.prove("alice", Some(units(5))).verify(&root));
+    }
+
+    #[test]
+    fn rejects_tampered_proofs() {
+        let tree = tree(&[("alice", 5), ("bob", 7)]);
+        let root = tree.root();
+        let mut proof = tree.prove("alice", Some(units(5)));
+        proof.siblings[0].1 = hex::encode([1; 32]);
+        assert!(!proof.verify(&root));
+        proof.siblings[0].1 = String::from("not hex");
+        assert!(!proof.verify(&root));
+        let mut proof = tree.prove("alice", Some(units(5)));
+        proof.siblings.clear();
+        assert!(!proof.verify(&root));
+    }
+}
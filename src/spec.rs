@@ -0,0 +1,170 @@
+use crate::amount::Amount;
+use crate::blockchain::MAX_DIFFICULTY;
+use crate::emission::RewardSchedule;
+use serde::{Deserialize, Serialize};
+use std::error::Error;
+use std::fs;
+use std::path::Path;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Allocation {
+    pub address: String,
+    pub amount: Amount,
+}
+
+/// How balances are recorded.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LedgerModel {
+    /// A balance per account, debited and credited by transfers.
+    #[default]
+    Account,
+    /// Transfers spend earlier outputs and create new ones.
+    Utxo,
+}
+
+/// Everything nodes must agree on to share a network. The genesis block is
+/// built from the spec alone, so every node loading the same file ends up
+/// with the same genesis hash.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ChainSpec {
+    pub network_id: String,
+    pub token_name: String,
+    pub token_symbol: String,
+    /// Decimal places of the token; amounts are stored in base units.
+    pub decimals: u8,
+    pub genesis_timestamp: i64,
+    pub allocations: Vec<Allocation>,
+    pub difficulty: u32,
+    /// Target seconds between blocks.
+    pub block_time: u64,
+    pub reward: RewardSchedule,
+    #[serde(default)]
+    pub ledger: LedgerModel,
+}
+
+impl ChainSpec {
+    /// A throwaway single-node spec, used when no spec file is given.
+    pub fn dev(token_name: String, token_symbol: String, difficulty: u32) -> ChainSpec {
+        ChainSpec {
+            network_id: String::from("dev"),
+            token_name,
+            token_symbol,
+            decimals: 0,
+            genesis_timestamp: 0,
+            allocations: Vec::new(),
+            difficulty,
+            block_time: 10,
+            reward: RewardSchedule::default(),
+            ledger: LedgerModel::Account,
+        }
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<ChainSpec, Box<dyn Error>> {
+        let contents = fs::read_to_string(path)?;
+        let spec: ChainSpec = serde_json::from_str(&contents)?;
+        spec.validate()?;
+        Ok(spec)
+    }
+
+    /// Decimal places beyond this cannot be represented in a `u64`.
+    pub const MAX_DECIMALS: u8 = 18;
+
+    /// Rejects specs that cannot be mined, or whose allocations alone
+    /// would break the supply cap.
+    pub fn validate(&self) -> Result<(), String> {
+        if !(1..=MAX_DIFFICULTY).contains(&self.difficulty) {
+            return Err(format!("difficulty must be from 1 to {}", MAX_DIFFICULTY));
+        }
+        if self.decimals > ChainSpec::MAX_DECIMALS {
+            return Err(format!(
+                "decimals must be at most {}",
+                ChainSpec::MAX_DECIMALS
+            ));
+        }
+        match self.allocated() {
+            Some(total) if total <= self.reward.max_supply => Ok(()),
+            _ => Err(String::from(
+                "genesis allocations exceed the maximum supply",
+            )),
+        }
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<(), Box<dyn Error>> {
+        fs::write(path, serde_json::to_string_pretty(self)?)?;
+        Ok(())
+    }
+
+    /// Supply minted by the genesis allocations, or `None` on overflow.
+    pub fn allocated(&self) -> Option<Amount> {
+        Amount::checked_sum(self.allocations.iter().map(|a| a.amount))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blockchain::Block;
+
+    fn spec() -> ChainSpec {
+        ChainSpec::dev(String::from("Test"), String::from("TST"), 1)
+    }
+
+    fn allocation(address: &str, amount: u64) -> Allocation {
+        Allocation {
+            address: address.to_string(),
+            amount: Amount::from_base_units(amount),
+        }
+    }
+
+    #[test]
+    fn validates_difficulty_decimals_and_supply() {
+        assert_eq!(spec().validate(), Ok(()));
+        for difficulty in [0, MAX_DIFFICULTY + 1] {
+            let spec = ChainSpec {
+                difficulty,
+                ..spec()
+            };
+            assert!(spec.validate().is_err(), "{}", difficulty);
+        }
+        let spec_with = |decimals| ChainSpec { decimals, ..spec() };
+        assert_eq!(spec_with(ChainSpec::MAX_DECIMALS).validate(), Ok(()));
+        assert!(spec_with(ChainSpec::MAX_DECIMALS + 1).validate().is_err());
+
+        let mut capped = spec();
+        capped.reward.max_supply = Amount::from_base_units(100);
+        capped.allocations = vec![allocation("alice", 60), allocation("bob", 40)];
+        assert_eq!(capped.validate(), Ok(()));
+        capped.allocations.push(allocation("carol", 1));
+        assert!(capped.validate().is_err());
+        capped.allocations = vec![allocation("alice", u64::MAX), allocation("bob", 1)];
+        assert_eq!(capped.allocated(), None);
+        assert!(capped.validate().is_err());
+    }
+
+    #[test]
+    fn genesis_depends_on_the_spec_alone() {
+        let hash = |spec: &ChainSpec| {
+            crate::blockchain::Chain::hash(Block::genesis(spec).header()).unwrap()
+        };
+        assert_eq!(hash(&spec()), hash(&spec()));
+        let allocated = ChainSpec {
+            allocations: vec![allocation("alice", 1)],
+            ..spec()
+        };
+        let later = ChainSpec {
+            genesis_timestamp: 1,
+            ..spec()
+        };
+        assert_ne!(hash(&spec()), hash(&allocated));
+        assert_ne!(hash(&spec()), hash(&later));
+    }
+
+    #[test]
+    fn ledger_defaults_to_accounts() {
+        let mut json = serde_json::to_value(spec()).unwrap();
+        json.as_object_mut().unwrap().remove("ledger");
+        let spec: ChainSpec = serde_json::from_value(json).unwrap();
+        assert_eq!(spec.ledger, LedgerModel::Account);
+    }
+}
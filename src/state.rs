@@ -0,0 +1,1116 @@
+use crate::amount::Amount;
+use crate::blockchain::{BlockError, Transaction, TxKind};
+use crate::htlc::{self, Htlc, HtlcAction};
+use crate::multisig::MultisigAccount;
+use crate::smt::{self, AccountProof, StateTree};
+use crate::spec::LedgerModel;
+use crate::token::{self, Token};
+use crate::utxo::{Lock, OutPoint, TxOutput};
+use crate::vm::{self, CallContext, Contract, ContractAction, ContractReceipt};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use utoipa::ToSchema;
+
+/// Account balances after the last connected block. In UTXO mode the
+/// unspent outputs are tracked as well, and each address's balance is the
+/// sum of the outputs locked to it.
+#[derive(Debug, Clone, Default)]
+pub struct State {
+    ledger: LedgerModel,
+    balances: HashMap<String, Amount>,
+    utxos: HashMap<OutPoint, TxOutput>,
+    /// Transactions authorized by each sender whose replays are ruled out
+    /// by nonce, which the next one must carry as its nonce.
+    nonces: HashMap<String, u64>,
+    /// Open hash time-locked contracts by id. Their funds are off every
+    /// balance until claimed or refunded.
+    htlcs: HashMap<String, Htlc>,
+    /// Deployed contracts by address. Their balances are ordinary balances.
+    contracts: HashMap<String, Contract>,
+    /// Receipts of contract transactions by transaction id.
+    receipts: HashMap<String, ContractReceipt>,
+    /// Issued tokens by symbol.
+    tokens: HashMap<String, Token>,
+    /// Balances of issued tokens by symbol, then account. Always kept per
+    /// account, whatever the ledger model.
+    token_balances: HashMap<String, HashMap<String, Amount>>,
+    /// Receipts of all confirmed transactions by transaction id.
+    tx_receipts: HashMap<String, TxReceipt>,
+}
+
+/// A balance a transaction changed. `token` is `None` for the native token.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+pub struct BalanceChange {
+    pub account: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub token: Option<String>,
+    pub before: Amount,
+    pub after: Amount,
+}
+
+/// Where a confirmed transaction was mined and what it changed.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+pub struct TxReceipt {
+    pub tx_id: String,
+    pub block_height: u64,
+    pub index: u32,
+    pub balance_changes: Vec<BalanceChange>,
+}
+
+/// What a block changed, so it can be disconnected again: the balance each
+/// touched account had before the block, `None` if it did not exist, plus
+/// the outputs it spent and created, the HTLCs it opened or closed, the
+/// contracts it deployed or changed and the tokens it issued or moved.
+#[derive(Debug, Clone, Default)]
+pub struct BlockUndo {
+    previous: Vec<(String, Option<Amount>)>,
+    spent: Vec<(OutPoint, TxOutput)>,
+    created: Vec<OutPoint>,
+    nonces: Vec<(String, Option<u64>)>,
+    htlcs: Vec<(String, Option<Htlc>)>,
+    contracts: Vec<(String, Option<Contract>)>,
+    receipts: Vec<String>,
+    tokens: Vec<String>,
+    token_balances: Vec<((String, String), Option<Amount>)>,
+    tx_receipts: Vec<(String, Option<TxReceipt>)>,
+}
+
+/// Pending changes on top of a `State`. Nothing reaches the state until
+/// `commit`, so dropping an overlay discards everything applied to it.
+pub struct StateOverlay<'a> {
+    base: &'a State,
+    /// Height of the block being applied, for HTLC timeouts.
+    height: u64,
+    changes: HashMap<String, Amount>,
+    /// Outputs spent so far, including ones created in the overlay.
+    spent: HashMap<OutPoint, TxOutput>,
+    /// Outputs created in the overlay.
+    created: HashMap<OutPoint, TxOutput>,
+    nonces: HashMap<String, u64>,
+    /// HTLCs opened (`Some`) or closed (`None`) in the overlay.
+    htlcs: HashMap<String, Option<Htlc>>,
+    /// Contracts deployed or with storage changed in the overlay.
+    contracts: HashMap<String, Contract>,
+    receipts: Vec<ContractReceipt>,
+    /// Tokens issued in the overlay.
+    tokens: HashMap<String, Token>,
+    /// Token balances changed in the overlay, by (symbol, account).
+    token_changes: HashMap<(String, String), Amount>,
+}
+
+impl State {
+    pub fn new(ledger: LedgerModel) -> State {
+        State {
+            ledger,
+            ..State::default()
+        }
+    }
+
+    pub fn balance(&self, account: &str) -> Option<Amount> {
+        self.balances.get(account).copied()
+    }
+
+    pub fn balances(&self) -> &HashMap<String, Amount> {
+        &self.balances
+    }
+
+    pub fn nonce(&self, account: &str) -> u64 {
+        self.nonces.get(account).copied().unwrap_or(0)
+    }
+
+    pub fn htlc(&self, id: &str) -> Option<&Htlc> {
+        self.htlcs.get(id)
+    }
+
+    pub fn contract(&self, address: &str) -> Option<&Contract> {
+        self.contracts.get(address)
+    }
+
+    pub fn receipt(&self, tx_id: &str) -> Option<&ContractReceipt> {
+        self.receipts.get(tx_id)
+    }
+
+    pub fn token(&self, symbol: &str) -> Option<&Token> {
+        self.tokens.get(symbol)
+    }
+
+    pub fn tokens(&self) -> &HashMap<String, Token> {
+        &self.tokens
+    }
+
+    pub fn token_balance(&self, symbol: &str, account: &str) -> Option<Amount> {
+        self.token_balances.get(symbol)?.get(account).copied()
+    }
+
+    /// Accounts holding a non-zero balance of `symbol`.
+    pub fn token_holders(&self, symbol: &str) -> Vec<(&String, Amount)> {
+        self.token_balances
+            .get(symbol)
+            .into_iter()
+            .flatten()
+            .filter(|(_, balance)| !balance.is_zero())
+            .map(|(account, balance)| (account, *balance))
+            .collect()
+    }
+
+    pub fn tx_receipt(&self, tx_id: &str) -> Option<&TxReceipt> {
+        self.tx_receipts.get(tx_id)
+    }
+
+    /// Unspent outputs owned by `address`, in a stable order.
+    pub fn utxos_of(&self, address: &str) -> Vec<(OutPoint, TxOutput)> {
+        let mut utxos: Vec<(OutPoint, TxOutput)> = self
+            .utxos
+            .iter()
+            .filter(|(_, output)| output.lock.owner() == address)
+            .map(|(outpoint, output)| (outpoint.clone(), output.clone()))
+            .collect();
+        utxos.sort_by(|a, b| a.0.cmp(&b.0));
+        utxos
+    }
+
+    /// Root of the sparse Merkle tree over everything in `leaves`.
+    pub fn state_root(&self) -> String {
+        StateTree::new(&self.leaves()).root()
+    }
+
+    pub fn prove(&self, account: &str) -> AccountProof {
+        StateTree::new(&self.leaves()).prove(account, self.balance(account))
+    }
+
+    /// The state as leaves of the state tree. Zero balances and nonces are
+    /// left out, so accounts that were merely created do not change it.
+    fn leaves(&self) -> Leaves {
+        let mut leaves = Leaves::new();
+        for (account, balance) in &self.balances {
+            set_amount(&mut leaves, smt::balance_key(account), *balance);
+        }
+        for (account, nonce) in &self.nonces {
+            set_nonce(&mut leaves, account, *nonce);
+        }
+        for (symbol, token) in &self.tokens {
+            leaves.insert(format!("token/{}", symbol), json_value(token));
+        }
+        for (symbol, balances) in &self.token_balances {
+            for (account, balance) in balances {
+                set_amount(&mut leaves, token_balance_key(symbol, account), *balance);
+            }
+        }
+        for (address, contract) in &self.contracts {
+            set_contract(&mut leaves, address, contract);
+        }
+        for (id, htlc) in &self.htlcs {
+            leaves.insert(format!("htlc/{}", id), json_value(htlc));
+        }
+        for (outpoint, output) in &self.utxos {
+            leaves.insert(format!("utxo/{}", outpoint), json_value(output));
+        }
+        leaves
+    }
+
+    pub fn create_account(&mut self, account: String) -> bool {
+        if self.balances.contains_key(&account) {
+            return false;
+        }
+        self.balances.insert(account, Amount::ZERO);
+        true
+    }
+
+    /// Overlay for applying transactions in a block at `height`.
+    pub fn overlay(&self, height: u64) -> StateOverlay<'_> {
+        StateOverlay {
+            base: self,
+            height,
+            changes: HashMap::new(),
+            spent: HashMap::new(),
+            created: HashMap::new(),
+            nonces: HashMap::new(),
+            htlcs: HashMap::new(),
+            contracts: HashMap::new(),
+            receipts: Vec::new(),
+            tokens: HashMap::new(),
+            token_changes: HashMap::new(),
+        }
+    }
+
+    /// Applies all `transactions` of the block at `height` or none of them.
+    /// Returns the undo record and a receipt per transaction, in order.
+    pub fn apply_block(
+        &mut self,
+        transactions: &[Transaction],
+        height: u64,
+    ) -> Result<(BlockUndo, Vec<TxReceipt>), BlockError> {
+        let mut overlay = self.overlay(height);
+        let mut tx_receipts = Vec::new();
+        for (index, tx) in transactions.iter().enumerate() {
+            let balance_changes = overlay.apply(tx)?;
+            tx_receipts.push(TxReceipt {
+                tx_id: tx.id(),
+                block_height: height,
+                index: index as u32,
+                balance_changes,
+            });
+        }
+        let StateOverlay {
+            changes,
+            spent,
+            created,
+            nonces,
+            htlcs,
+            contracts,
+            receipts,
+            tokens,
+            token_changes,
+            ..
+        } = overlay;
+        let mut undo = self.commit(changes, spent, created, nonces, htlcs);
+        for (address, contract) in contracts {
+            let previous = self.contracts.insert(address.clone(), contract);
+            undo.contracts.push((address, previous));
+        }
+        for receipt in receipts {
+            undo.receipts.push(receipt.tx_id.clone());
+            self.receipts.insert(receipt.tx_id.clone(), receipt);
+        }
+        for (symbol, token) in tokens {
+            self.tokens.insert(symbol.clone(), token);
+            undo.tokens.push(symbol);
+        }
+        for ((symbol, account), balance) in token_changes {
+            let previous = self
+                .token_balances
+                .entry(symbol.clone())
+                .or_default()
+                .insert(account.clone(), balance);
+            undo.token_balances.push(((symbol, account), previous));
+        }
+        for receipt in &tx_receipts {
+            let previous = self
+                .tx_receipts
+                .insert(receipt.tx_id.clone(), receipt.clone());
+            undo.tx_receipts.push((receipt.tx_id.clone(), previous));
+        }
+        Ok((undo, tx_receipts))
+    }
+
+    fn commit(
+        &mut self,
+        changes: HashMap<String, Amount>,
+        spent: HashMap<OutPoint, TxOutput>,
+        mut created: HashMap<OutPoint, TxOutput>,
+        nonces: HashMap<String, u64>,
+        htlcs: HashMap<String, Option<Htlc>>,
+    ) -> BlockUndo {
+        let mut undo = BlockUndo::default();
+        for (account, balance) in changes {
+            let previous = self.balances.insert(account.clone(), balance);
+            undo.previous.push((account, previous));
+        }
+        for (outpoint, output) in spent {
+            // Created and spent within the same block: never reaches the set.
+            if created.remove(&outpoint).is_some() {
+                continue;
+            }
+            self.utxos.remove(&outpoint);
+            undo.spent.push((outpoint, output));
+        }
+        for (outpoint, output) in created {
+            self.utxos.insert(outpoint.clone(), output);
+            undo.created.push(outpoint);
+        }
+        for (account, nonce) in nonces {
+            let previous = self.nonces.insert(account.clone(), nonce);
+            undo.nonces.push((account, previous));
+        }
+        for (id, htlc) in htlcs {
+            let previous = match htlc {
+                Some(htlc) => self.htlcs.insert(id.clone(), htlc),
+                None => self.htlcs.remove(&id),
+            };
+            undo.htlcs.push((id, previous));
+        }
+        undo
+    }
+
+    /// Reverts the changes recorded in `undo`.
+    pub fn undo(&mut self, undo: BlockUndo) {
+        for (account, previous) in undo.previous {
+            match previous {
+                Some(balance) => self.balances.insert(account, balance),
+                None => self.balances.remove(&account),
+            };
+        }
+        for outpoint in undo.created {
+            self.utxos.remove(&outpoint);
+        }
+        self.utxos.extend(undo.spent);
+        for (account, previous) in undo.nonces {
+            match previous {
+                Some(nonce) => self.nonces.insert(account, nonce),
+                None => self.nonces.remove(&account),
+            };
+        }
+        for (id, previous) in undo.htlcs {
+            match previous {
+                Some(htlc) => self.htlcs.insert(id, htlc),
+                None => self.htlcs.remove(&id),
+            };
+        }
+        for (address, previous) in undo.contracts {
+            match previous {
+                Some(contract) => self.contracts.insert(address, contract),
+                None => self.contracts.remove(&address),
+            };
+        }
+        for tx_id in undo.receipts {
+            self.receipts.remove(&tx_id);
+        }
+        for ((symbol, account), previous) in undo.token_balances {
+            let balances = self.token_balances.entry(symbol.clone()).or_default();
+            match previous {
+                Some(balance) => balances.insert(account, balance),
+                None => balances.remove(&account),
+            };
+            if balances.is_empty() {
+                self.token_balances.remove(&symbol);
+            }
+        }
+        for symbol in undo.tokens {
+            self.tokens.remove(&symbol);
+        }
+        for (tx_id, previous) in undo.tx_receipts.into_iter().rev() {
+            match previous {
+                Some(receipt) => self.tx_receipts.insert(tx_id, receipt),
+                None => self.tx_receipts.remove(&tx_id),
+            };
+        }
+    }
+}
+
+/// Balances `tx` may change, as (account, token) pairs in a stable order.
+fn touched_balances(tx: &Transaction) -> Vec<(String, Option<String>)> {
+    let mut touched: Vec<(String, Option<String>)> = Vec::new();
+    let owners = tx.outputs.iter().map(|output| output.lock.owner());
+    for account in [tx.sender.as_str(), tx.receiver.as_str()]
+        .into_iter()
+        .chain(owners)
+    {
+        let entry = (account.to_string(), tx.token.clone());
+        if !account.is_empty() && !touched.contains(&entry) {
+            touched.push(entry);
+        }
+    }
+    touched
+}
+
+/// A contract deployed or called by a transaction, its receipt, and its
+/// address.
+type ContractChange = (String, Contract, ContractReceipt);
+
+/// Leaves of the state tree by key. Next to the `account/<address>`
+/// balances of `smt`, the state commits to `nonce/<account>`,
+/// `token/<symbol>` and the holdings under `token/<symbol>/<account>`,
+/// contract code at `contract/<address>` and storage under
+/// `contract/<address>/<key>`, open HTLCs at `htlc/<id>` and unspent
+/// outputs at `utxo/<tx_id>:<index>`.
+type Leaves = BTreeMap<String, Vec<u8>>;
+
+fn json_value<T: Serialize>(item: &T) -> Vec<u8> {
+    serde_json::to_vec(item).expect("Failed to serialize state")
+}
+
+fn token_balance_key(symbol: &str, account: &str) -> String {
+    format!("token/{}/{}", symbol, account)
+}
+
+fn set_amount(leaves: &mut Leaves, key: String, amount: Amount) {
+    match smt::amount_value(amount) {
+        Some(value) => leaves.insert(key, value),
+        None => leaves.remove(&key),
+    };
+}
+
+fn set_nonce(leaves: &mut Leaves, account: &str, nonce: u64) {
+    let key = format!("nonce/{}", account);
+    if nonce == 0 {
+        leaves.remove(&key);
+    } else {
+        leaves.insert(key, nonce.to_be_bytes().to_vec());
+    }
+}
+
+/// Replaces the code and storage leaves of the contract at `address`.
+fn set_contract(leaves: &mut Leaves, address: &str, contract: &Contract) {
+    let prefix = format!("contract/{}/", address);
+    let stale: Vec<String> = leaves
+        .range(prefix.clone()..)
+        .map(|(key, _)| key)
+        .take_while(|key| key.starts_with(&prefix))
+        .cloned()
+        .collect();
+    for key in stale {
+        leaves.remove(&key);
+    }
+    leaves.insert(format!("contract/{}", address), json_value(&contract.code));
+    for (key, value) in &contract.storage {
+        leaves.insert(format!("{}{}", prefix, key), value.to_be_bytes().to_vec());
+    }
+}
+
+/// What a contract call moves besides its value: `payout` back from the
+/// contract to the caller, and the `fee` the caller burns for gas.
+#[derive(Debug, Default, Clone, Copy)]
+struct Charge {
+    payout: Amount,
+    fee: Amount,
+}
+
+/// What `gas` costs at `vm::GAS_PRICE`.
+fn gas_fee(gas: u64) -> Result<Amount, BlockError> {
+    gas.checked_mul(vm::GAS_PRICE)
+        .map(Amount::from_base_units)
+        .ok_or(BlockError::Overflow)
+}
+
+impl StateOverlay<'_> {
+    pub fn balance(&self, account: &str) -> Option<Amount> {
+        self.changes
+            .get(account)
+            .copied()
+            .or_else(|| self.base.balance(account))
+    }
+
+    /// State root as it would be after committing the overlay.
+    pub fn state_root(&self) -> String {
+        let mut leaves = self.base.leaves();
+        for (account, balance) in &self.changes {
+            set_amount(&mut leaves, smt::balance_key(account), *balance);
+        }
+        for (account, nonce) in &self.nonces {
+            set_nonce(&mut leaves, account, *nonce);
+        }
+        for (symbol, token) in &self.tokens {
+            leaves.insert(format!("token/{}", symbol), json_value(token));
+        }
+        for ((symbol, account), balance) in &self.token_changes {
+            set_amount(&mut leaves, token_balance_key(symbol, account), *balance);
+        }
+        for (address, contract) in &self.contracts {
+            set_contract(&mut leaves, address, contract);
+        }
+        for (id, htlc) in &self.htlcs {
+            let key = format!("htlc/{}", id);
+            match htlc {
+                Some(htlc) => leaves.insert(key, json_value(htlc)),
+                None => leaves.remove(&key),
+            };
+        }
+        // Outputs created and spent within the overlay end up spent.
+        for (outpoint, output) in &self.created {
+            leaves.insert(format!("utxo/{}", outpoint), json_value(output));
+        }
+        for outpoint in self.spent.keys() {
+            leaves.remove(&format!("utxo/{}", outpoint));
+        }
+        StateTree::new(&leaves).root()
+    }
+
+    fn utxo(&self, outpoint: &OutPoint) -> Result<TxOutput, BlockError> {
+        if self.spent.contains_key(outpoint) {
+            return Err(BlockError::DoubleSpend(outpoint.clone()));
+        }
+        self.created
+            .get(outpoint)
+            .or_else(|| self.base.utxos.get(outpoint))
+            .cloned()
+            .ok_or_else(|| BlockError::UnknownInput(outpoint.clone()))
+    }
+
+    pub fn nonce(&self, account: &str) -> u64 {
+        self.nonces
+            .get(account)
+            .copied()
+            .unwrap_or_else(|| self.base.nonce(account))
+    }
+
+    pub fn htlc(&self, id: &str) -> Option<Htlc> {
+        match self.htlcs.get(id) {
+            Some(htlc) => htlc.clone(),
+            None => self.base.htlc(id).cloned(),
+        }
+    }
+
+    /// Applies `tx` to the overlay and returns the balances it changed. On
+    /// error the overlay is left as it was.
+    pub fn apply(&mut self, tx: &Transaction) -> Result<Vec<BalanceChange>, BlockError> {
+        let touched = touched_balances(tx);
+        let before: Vec<Amount> = touched
+            .iter()
+            .map(|(account, token)| self.holding(account, token.as_deref()))
+            .collect();
+        self.apply_changes(tx)?;
+        Ok(touched
+            .into_iter()
+            .zip(before)
+            .filter_map(|((account, token), before)| {
+                let after = self.holding(&account, token.as_deref());
+                (after != before).then_some(BalanceChange {
+                    account,
+                    token,
+                    before,
+                    after,
+                })
+            })
+            .collect())
+    }
+
+    /// Balance of `account` in `token`, or in the native token for `None`.
+    fn holding(&self, account: &str, token: Option<&str>) -> Amount {
+        match token {
+            Some(symbol) => self.token_balance(symbol, account),
+            None => self.balance(account),
+        }
+        .unwrap_or_default()
+    }
+
+    fn apply_changes(&mut self, tx: &Transaction) -> Result<(), BlockError> {
+        self.authorize(tx)?;
+        let sequenced = tx.needs_authorization() && self.uses_nonce(tx);
+        if sequenced {
+            let expected = self.nonce(&tx.sender);
+            if tx.nonce != expected {
+                return Err(BlockError::InvalidNonce {
+                    expected,
+                    found: tx.nonce,
+                });
+            }
+        }
+        let htlc_change = self.check_htlc(tx)?;
+        let (contract_change, charge) = self.run_contract(tx)?;
+        if tx.token.is_some() || tx.issue.is_some() {
+            self.apply_token(tx)?;
+        } else {
+            match self.base.ledger {
+                LedgerModel::Account => self.apply_account(tx, charge)?,
+                LedgerModel::Utxo => self.apply_utxo(tx)?,
+            }
+        }
+        if sequenced {
+            self.nonces.insert(tx.sender.clone(), tx.nonce + 1);
+        }
+        if let Some((id, htlc)) = htlc_change {
+            self.htlcs.insert(id, htlc);
+        }
+        if let Some((address, contract, receipt)) = contract_change {
+            self.contracts.insert(address, contract);
+            self.receipts.push(receipt);
+        }
+        Ok(())
+    }
+
+    /// Whether replays of `tx` are ruled out by its nonce rather than by the
+    /// outputs it spends. Every such transaction the sender authorizes
+    /// must carry the sender's next nonce, which also keeps ids unique.
+    fn uses_nonce(&self, tx: &Transaction) -> bool {
+        self.base.ledger == LedgerModel::Account || tx.token.is_some()
+    }
+
+    pub fn token(&self, symbol: &str) -> Option<Token> {
+        self.tokens
+            .get(symbol)
+            .or_else(|| self.base.token(symbol))
+            .cloned()
+    }
+
+    pub fn token_balance(&self, symbol: &str, account: &str) -> Option<Amount> {
+        self.token_changes
+            .get(&(symbol.to_string(), account.to_string()))
+            .copied()
+            .or_else(|| self.base.token_balance(symbol, account))
+    }
+
+    /// Issues a token to its issuer, or moves a token between accounts.
+    fn apply_token(&mut self, tx: &Transaction) -> Result<(), BlockError> {
+        if !tx.inputs.is_empty() || !tx.outputs.is_empty() {
+            return Err(BlockError::WrongLedgerModel);
+        }
+        let symbol = tx.token.clone().ok_or(BlockError::InvalidToken)?;
+        match (tx.kind, &tx.issue) {
+            (TxKind::IssueToken, Some(info)) => {
+                if !token::is_valid_symbol(&symbol)
+                    || !info.is_valid()
+                    || tx.sender != tx.receiver
+                    || tx.amount.is_zero()
+                    || self.token(&symbol).is_some()
+                {
+                    return Err(BlockError::InvalidToken);
+                }
+                let token = Token {
+                    symbol: symbol.clone(),
+                    name: info.name.clone(),
+                    decimals: info.decimals,
+                    supply: tx.amount,
+                    issuer: tx.sender.clone(),
+                };
+                self.tokens.insert(symbol.clone(), token);
+                self.token_changes
+                    .insert((symbol, tx.sender.clone()), tx.amount);
+                Ok(())
+            }
+            (TxKind::Transfer, None) => {
+                if self.token(&symbol).is_none() {
+                    return Err(BlockError::UnknownToken(symbol));
+                }
+                let sender_after = self
+                    .token_balance(&symbol, &tx.sender)
+                    .and_then(|balance| balance.checked_sub(tx.amount))
+                    .ok_or_else(|| BlockError::InsufficientFunds {
+                        account: tx.sender.clone(),
+                    })?;
+                let receiver_before = if tx.sender == tx.receiver {
+                    sender_after
+                } else {
+                    self.token_balance(&symbol, &tx.receiver)
+                        .unwrap_or_default()
+                };
+                let receiver_after = receiver_before
+                    .checked_add(tx.amount)
+                    .ok_or(BlockError::Overflow)?;
+                self.token_changes
+                    .insert((symbol.clone(), tx.sender.clone()), sender_after);
+                self.token_changes
+                    .insert((symbol, tx.receiver.clone()), receiver_after);
+                Ok(())
+            }
+            _ => Err(BlockError::InvalidToken),
+        }
+    }
+
+    /// Checks an HTLC transaction against the contract it opens or closes
+    /// and returns the change to record once its funds have moved.
+    fn check_htlc(&self, tx: &Transaction) -> Result<Option<(String, Option<Htlc>)>, BlockError> {
+        if (tx.kind == TxKind::Htlc) != tx.htlc.is_some() {
+            return Err(BlockError::InvalidHtlc);
+        }
+        let Some(action) = &tx.htlc else {
+            return Ok(None);
+        };
+        match action {
+            HtlcAction::Lock {
+                hash_lock,
+                timeout_height,
+            } => {
+                if *timeout_height <= self.height
+                    || hex::decode(hash_lock).map(|h| h.len()) != Ok(32)
+                {
+                    return Err(BlockError::InvalidHtlc);
+                }
+                let id = tx.id();
+                if self.htlc(&id).is_some() {
+                    return Err(BlockError::InvalidHtlc);
+                }
+                let htlc = Htlc {
+                    sender: tx.sender.clone(),
+                    receiver: tx.receiver.clone(),
+                    amount: tx.amount,
+                    hash_lock: hash_lock.clone(),
+                    timeout_height: *timeout_height,
+                };
+                Ok(Some((id, Some(htlc))))
+            }
+            HtlcAction::Claim { htlc_id, preimage } => {
+                let htlc = self.open_htlc(htlc_id)?;
+                if self.height >= htlc.timeout_height {
+                    return Err(BlockError::HtlcExpired);
+                }
+                if htlc::hash_secret(preimage).as_ref() != Some(&htlc.hash_lock) {
+                    return Err(BlockError::InvalidPreimage);
+                }
+                if tx.receiver != htlc.receiver || tx.amount != htlc.amount {
+                    return Err(BlockError::InvalidHtlc);
+                }
+                Ok(Some((htlc_id.clone(), None)))
+            }
+            HtlcAction::Refund { htlc_id } => {
+                let htlc = self.open_htlc(htlc_id)?;
+                if self.height < htlc.timeout_height {
+                    return Err(BlockError::HtlcNotExpired);
+                }
+                if tx.receiver != htlc.sender || tx.amount != htlc.amount {
+                    return Err(BlockError::InvalidHtlc);
+                }
+                Ok(Some((htlc_id.clone(), None)))
+            }
+        }
+    }
+
+    fn open_htlc(&self, id: &str) -> Result<Htlc, BlockError> {
+        self.htlc(id)
+            .ok_or_else(|| BlockError::UnknownHtlc(id.to_string()))
+    }
+
+    pub fn contract(&self, address: &str) -> Option<Contract> {
+        self.contracts
+            .get(address)
+            .or_else(|| self.base.contract(address))
+            .cloned()
+    }
+
+    /// Deploys or runs the contract of a contract transaction without
+    /// touching the overlay. Returns the contract and receipt to record and
+    /// what the contract pays out to the caller.
+    fn run_contract(
+        &self,
+        tx: &Transaction,
+    ) -> Result<(Option<ContractChange>, Charge), BlockError> {
+        if (tx.kind == TxKind::Contract) != tx.contract.is_some() {
+            return Err(BlockError::InvalidContract);
+        }
+        let Some(action) = &tx.contract else {
+            return Ok((None, Charge::default()));
+        };
+        if self.base.ledger != LedgerModel::Account {
+            return Err(BlockError::WrongLedgerModel);
+        }
+        let tx_id = tx.id();
+        match action {
+            ContractAction::Deploy { code } => {
+                let address = vm::contract_address(&tx_id);
+                if !tx.amount.is_zero()
+                    || !tx.receiver.is_empty()
+                    || !vm::validate(code)
+                    || self.contract(&address).is_some()
+                {
+                    return Err(BlockError::InvalidContract);
+                }
+                let contract = Contract {
+                    code: code.clone(),
+                    storage: Default::default(),
+                };
+                let gas_used = vm::deploy_gas(code);
+                let receipt = ContractReceipt {
+                    tx_id,
+                    contract: address.clone(),
+                    gas_used,
+                    logs: Vec::new(),
+                    error: None,
+                };
+                let charge = Charge {
+                    fee: gas_fee(gas_used)?,
+                    ..Charge::default()
+                };
+                Ok((Some((address, contract, receipt)), charge))
+            }
+            ContractAction::Call { args, gas_limit } => {
+                let mut contract = self
+                    .contract(&tx.receiver)
+                    .ok_or_else(|| BlockError::UnknownContract(tx.receiver.clone()))?;
+                if *gas_limit > vm::MAX_GAS || tx.sender == tx.receiver {
+                    return Err(BlockError::InvalidContract);
+                }
+                let balance = self
+                    .balance(&tx.receiver)
+                    .unwrap_or_default()
+                    .checked_add(tx.amount)
+                    .ok_or(BlockError::Overflow)?;
+                let ctx = CallContext {
+                    contract: &tx.receiver,
+                    caller: &tx.sender,
+                    value: tx.amount,
+                    balance,
+                    args,
+                    gas_limit: *gas_limit,
+                };
+                // A failed call is still mined: the contract hands the value
+                // back and the caller pays for the gas it burned.
+                let (receipt, payout, gas_used) =
+                    match vm::execute(&contract.code, &mut contract.storage, &ctx) {
+                        Ok(outcome) => (
+                            ContractReceipt {
+                                tx_id,
+                                contract: tx.receiver.clone(),
+                                gas_used: outcome.gas_used,
+                                logs: outcome.logs,
+                                error: None,
+                            },
+                            outcome.paid,
+                            outcome.gas_used,
+                        ),
+                        Err(failure) => (
+                            ContractReceipt {
+                                tx_id,
+                                contract: tx.receiver.clone(),
+                                gas_used: failure.gas_used,
+                                logs: Vec::new(),
+                                error: Some(failure.error.to_string()),
+                            },
+                            tx.amount,
+                            failure.gas_used,
+                        ),
+                    };
+                Ok((
+                    Some((tx.receiver.clone(), contract, receipt)),
+                    Charge {
+                        payout,
+                        fee: gas_fee(gas_used)?,
+                    },
+                ))
+            }
+        }
+    }
+
+    /// Checks that nothing is sent from a contract, and that a transfer from
+    /// a multisig account carries the account's keys and enough valid
+    /// signatures.
+    fn authorize(&self, tx: &Transaction) -> Result<(), BlockError> {
+        // Nobody holds a contract's keys; funds only leave one through its
+        // own code paying out to a caller.
+        if tx.needs_authorization() && vm::is_contract_address(&tx.sender) {
+            return Err(BlockError::ContractSender);
+        }
+        let Some(account) = &tx.multisig else {
+            if tx.needs_authorization() && MultisigAccount::is_multisig_address(&tx.sender) {
+                return Err(BlockError::MissingSignatures);
+            }
+            return Ok(());
+        };
+        if !tx.needs_authorization() || account.address() != tx.sender {
+            return Err(BlockError::InvalidSignatures);
+        }
+        if !account.verify(tx.signing_hash().as_bytes(), &tx.signatures) {
+            return Err(BlockError::InvalidSignatures);
+        }
+        Ok(())
+    }
+
+    /// Moves `amount` from sender to receiver, and for contract calls the
+    /// payout back the other way, burning the sender's gas fee.
+    fn apply_account(&mut self, tx: &Transaction, charge: Charge) -> Result<(), BlockError> {
+        let Charge { payout, fee } = charge;
+        if !tx.inputs.is_empty() || !tx.outputs.is_empty() {
+            return Err(BlockError::WrongLedgerModel);
+        }
+
+        let mut sender_after = None;
+        if tx.debits_sender() {
+            let balance = self
+                .balance(&tx.sender)
+                .ok_or_else(|| BlockError::UnknownAccount {
+                    account: tx.sender.clone(),
+                })?;
+            let insufficient = || BlockError::InsufficientFunds {
+                account: tx.sender.clone(),
+            };
+            let after = balance
+                .checked_sub(tx.amount)
+                .ok_or_else(insufficient)?
+                .checked_add(payout)
+                .ok_or(BlockError::Overflow)?
+                .checked_sub(fee)
+                .ok_or_else(insufficient)?;
+            sender_after = Some(after);
+        }
+
+        let mut receiver_after = None;
+        if tx.credits_receiver() {
+            let before = match sender_after {
+                Some(after) if tx.sender == tx.receiver => after,
+                _ => self.balance(&tx.receiver).unwrap_or_default(),
+            };
+            let after = before
+                .checked_add(tx.amount)
+                .and_then(|after| after.checked_sub(payout))
+                .ok_or(BlockError::Overflow)?;
+            receiver_after = Some(after);
+        }
+
+        if let Some(after) = sender_after {
+            self.changes.insert(tx.sender.clone(), after);
+        }
+        if let Some(after) = receiver_after {
+            self.changes.insert(tx.receiver.clone(), after);
+        }
+        Ok(())
+    }
+
+    /// Mints and HTLC payouts create a single output locked to the receiver.
+    /// Transfers must spend existing outputs locked to the sender, and their
+    /// inputs must add up to their outputs plus whatever an HTLC lock escrows.
+    fn apply_utxo(&mut self, tx: &Transaction) -> Result<(), BlockError> {
+        let outputs = if !tx.debits_sender() {
+            if !tx.inputs.is_empty() || !tx.outputs.is_empty() {
+                return Err(BlockError::WrongLedgerModel);
+            }
+            vec![TxOutput {
+                amount: tx.amount,
+                lock: Lock::Address(tx.receiver.clone()),
+            }]
+        } else {
+            if tx.inputs.is_empty() {
+                return Err(BlockError::WrongLedgerModel);
+            }
+            tx.outputs.clone()
+        };
+
+        let mut spending = Vec::new();
+        let mut seen = HashSet::new();
+        for outpoint in &tx.inputs {
+            if !seen.insert(outpoint) {
+                return Err(BlockError::DoubleSpend(outpoint.clone()));
+            }
+            let output = self.utxo(outpoint)?;
+            if output.lock.owner() != tx.sender {
+                return Err(BlockError::InputLocked(outpoint.clone()));
+            }
+            spending.push((outpoint.clone(), output));
+        }
+
+        let total_out =
+            Amount::checked_sum(outputs.iter().map(|o| o.amount)).ok_or(BlockError::Overflow)?;
+        if tx.debits_sender() {
+            let total_in = Amount::checked_sum(spending.iter().map(|(_, o)| o.amount))
+                .ok_or(BlockError::Overflow)?;
+            let escrowed = if tx.credits_receiver() {
+                Amount::ZERO
+            } else {
+                tx.amount
+            };
+            if Some(total_in) != total_out.checked_add(escrowed) {
+                return Err(BlockError::ValueMismatch);
+            }
+        }
+
+        // Work out every balance change before touching the overlay.
+        let mut balances: HashMap<String, Amount> = HashMap::new();
+        for (_, output) in &spending {
+            let owner = output.lock.owner();
+            let before = match balances.get(owner) {
+                Some(balance) => *balance,
+                None => self.balance(owner).unwrap_or_default(),
+            };
+            let after = before
+                .checked_sub(output.amount)
+                .ok_or(BlockError::Overflow)?;
+            balances.insert(owner.to_string(), after);
+        }
+        for output in &outputs {
+            let owner = output.lock.owner();
+            let before = match balances.get(owner) {
+                Some(balance) => *balance,
+                None => self.balance(owner).unwrap_or_default(),
+            };
+            let after = before
+                .checked_add(output.amount)
+                .ok_or(BlockError::Overflow)?;
+            balances.insert(owner.to_string(), after);
+        }
+
+        let tx_id = tx.id();
+        self.spent.extend(spending);
+        for (index, output) in outputs.into_iter().enumerate() {
+            let outpoint = OutPoint {
+                tx_id: tx_id.clone(),
+                index: index as u32,
+            };
+            self.created.insert(outpoint, output);
+        }
+        self.changes.extend(balances);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::units;
+
+    /// An account state with `alice` holding 100.
+    fn funded() -> State {
+        let mut state = State::new(LedgerModel::Account);
+        let allocation = Transaction::allocation("alice".into(), units(100), 0);
+        state.apply_block(&[allocation], 0).unwrap();
+        state
+    }
+
+    fn transfer(receiver: &str, amount: u64, nonce: u64) -> Transaction {
+        Transaction {
+            nonce,
+            ..Transaction::transfer("alice".into(), receiver.into(), units(amount))
+        }
+    }
+
+    #[test]
+    fn applies_blocks_all_or_nothing() {
+        let mut state = funded();
+        let root = state.state_root();
+        let block = [transfer("bob", 30, 0), transfer("carol", 80, 1)];
+        assert!(matches!(
+            state.apply_block(&block, 1),
+            Err(BlockError::InsufficientFunds { .. })
+        ));
+        assert_eq!(state.balance("alice"), Some(units(100)));
+        assert_eq!(state.balance("bob"), None);
+        assert_eq!(state.nonce("alice"), 0);
+        assert_eq!(state.state_root(), root);
+    }
+
+    #[test]
+    fn receipts_list_the_balances_changed() {
+        let mut state = funded();
+        let (_, receipts) = state.apply_block(&[transfer("bob", 30, 0)], 1).unwrap();
+        let changes: Vec<_> = receipts[0]
+            .balance_changes
+            .iter()
+            .map(|change| (change.account.as_str(), change.before, change.after))
+            .collect();
+        assert_eq!(
+            changes,
+            [
+                ("alice", units(100), units(70)),
+                ("bob", Amount::ZERO, units(30))
+            ]
+        );
+    }
+
+    #[test]
+    fn transfers_carry_the_senders_next_nonce() {
+        let mut state = funded();
+        let replay = [transfer("bob", 10, 0), transfer("bob", 10, 0)];
+        assert!(matches!(
+            state.apply_block(&replay, 1),
+            Err(BlockError::InvalidNonce {
+                expected: 1,
+                found: 0
+            })
+        ));
+        state
+            .apply_block(&[transfer("bob", 10, 0), transfer("bob", 10, 1)], 1)
+            .unwrap();
+        assert_eq!(state.nonce("alice"), 2);
+        assert_eq!(state.balance("bob"), Some(units(20)));
+    }
+
+    #[test]
+    fn overlay_roots_match_the_committed_state() {
+        let mut state = funded();
+        let block = [transfer("bob", 30, 0)];
+        let mut overlay = state.overlay(1);
+        overlay.apply(&block[0]).unwrap();
+        let predicted = overlay.state_root();
+        state.apply_block(&block, 1).unwrap();
+        assert_eq!(state.state_root(), predicted);
+    }
+
+    #[test]
+    fn undo_restores_the_previous_state() {
+        let mut state = funded();
+        let root = state.state_root();
+        let (undo, _) = state.apply_block(&[transfer("bob", 30, 0)], 1).unwrap();
+        assert_ne!(state.state_root(), root);
+        state.undo(undo);
+        assert_eq!(state.balance("alice"), Some(units(100)));
+        assert_eq!(state.balance("bob"), None);
+        assert_eq!(state.nonce("alice"), 0);
+        assert_eq!(state.state_root(), root);
+    }
+}
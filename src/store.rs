@@ -0,0 +1,216 @@
+use crate::blockchain::{Block, Chain, Transaction};
+use crate::spec::ChainSpec;
+use log::info;
+use serde::{Deserialize, Serialize};
+use std::error::Error;
+use std::fs::{self, OpenOptions};
+use std::path::{Path, PathBuf};
+
+pub const SPEC_FILE: &str = "spec.json";
+pub const CHAIN_FILE: &str = "chain.json";
+pub const LOCK_FILE: &str = "LOCK";
+
+/// Everything a node keeps between runs besides its spec: the blocks after
+/// genesis, the pending pool and the accounts, which may have been created
+/// without a transaction. State is rebuilt by replaying the blocks.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct Snapshot {
+    pub blocks: Vec<Block>,
+    pub pending: Vec<Transaction>,
+    #[serde(default)]
+    pub accounts: Vec<String>,
+}
+
+impl Snapshot {
+    pub fn of(chain: &Chain) -> Snapshot {
+        Snapshot {
+            blocks: chain.get_chain()[1..].to_vec(),
+            pending: chain.pending_transactions().to_vec(),
+            accounts: chain.accounts(),
+        }
+    }
+
+    /// Connects the blocks on top of `chain`, then restores the pool.
+    pub fn restore(mut self, chain: &mut Chain) -> Result<(), Box<dyn Error>> {
+        for block in std::mem::take(&mut self.blocks) {
+            let height = chain.get_chain().len();
+            chain
+                .connect_block(block)
+                .map_err(|e| format!("stored block {} does not connect: {}", height, e))?;
+        }
+        self.restore_pool(chain);
+        Ok(())
+    }
+
+    /// Recreates the accounts and queues the pending transactions that
+    /// still apply.
+    pub fn restore_pool(self, chain: &mut Chain) {
+        for account in self.accounts {
+            chain.create_account(account);
+        }
+        for tx in self.pending {
+            chain.submit_transaction(tx);
+        }
+    }
+}
+
+/// A chain exported to a single file, spec included, so it can be imported
+/// into an empty data directory.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Archive {
+    pub spec: ChainSpec,
+    #[serde(flatten)]
+    pub snapshot: Snapshot,
+}
+
+/// Held while a process uses a data directory; removes the lock file when
+/// dropped.
+pub struct StoreLock {
+    path: PathBuf,
+}
+
+impl Drop for StoreLock {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+/// A node's data directory.
+pub struct Store {
+    dir: PathBuf,
+}
+
+impl Store {
+    pub fn open<P: AsRef<Path>>(dir: P) -> Result<Store, Box<dyn Error>> {
+        fs::create_dir_all(&dir)?;
+        Ok(Store {
+            dir: dir.as_ref().to_path_buf(),
+        })
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Claims the directory for this process, so a command and a running
+    /// node cannot overwrite each other's chain.
+    pub fn lock(&self) -> Result<StoreLock, Box<dyn Error>> {
+        let path = self.dir.join(LOCK_FILE);
+        if let Err(e) = OpenOptions::new().write(true).create_new(true).open(&path) {
+            return Err(format!(
+                "{} is in use by another node or command ({}); remove {} if none is running",
+                self.dir.display(),
+                e,
+                path.display()
+            )
+            .into());
+        }
+        Ok(StoreLock { path })
+    }
+
+    /// The spec saved by an earlier run, if any.
+    pub fn load_spec(&self) -> Result<Option<ChainSpec>, Box<dyn Error>> {
+        let path = self.dir.join(SPEC_FILE);
+        if !path.exists() {
+            return Ok(None);
+        }
+        ChainSpec::load(path).map(Some)
+    }
+
+    pub fn save_spec(&self, spec: &ChainSpec) -> Result<(), Box<dyn Error>> {
+        spec.save(self.dir.join(SPEC_FILE))
+    }
+
+    /// Replays the saved chain onto a freshly created `chain`.
+    pub fn load(&self, chain: &mut Chain) -> Result<(), Box<dyn Error>> {
+        let path = self.dir.join(CHAIN_FILE);
+        if !path.exists() {
+            return Ok(());
+        }
+        let snapshot: Snapshot = serde_json::from_str(&fs::read_to_string(path)?)?;
+        snapshot.restore(chain)?;
+        info!(
+            "Loaded {} blocks from {}",
+            chain.get_chain().len(),
+            self.dir.display()
+        );
+        Ok(())
+    }
+
+    /// Writes the chain to disk. The file is replaced in one step, so a
+    /// crash mid-write leaves the previous copy intact.
+    pub fn save(&self, chain: &Chain) -> Result<(), Box<dyn Error>> {
+        let path = self.dir.join(CHAIN_FILE);
+        let temp = path.with_extension("json.tmp");
+        fs::write(&temp, serde_json::to_vec(&Snapshot::of(chain))?)?;
+        fs::rename(temp, path)?;
+        info!(
+            "Saved {} blocks to {}",
+            chain.get_chain().len(),
+            self.dir.display()
+        );
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::spec::LedgerModel;
+    use crate::testing::{self, units};
+
+    /// A fresh data directory under the system temp dir.
+    fn temp_store(name: &str) -> Store {
+        let dir = std::env::temp_dir().join(format!("{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        Store::open(dir).unwrap()
+    }
+
+    #[tokio::test]
+    async fn saves_and_restores_the_chain_and_pool() {
+        let store = temp_store("store-roundtrip");
+        let (mut chain, _p2p_rx) = testing::chain(LedgerModel::Account, &[("alice", 100)]);
+        chain.create_account(String::from("carol"));
+        assert!(chain
+            .new_transaction("alice".into(), "bob".into(), units(10))
+            .is_some());
+        assert!(chain.generate_new_block());
+        let pending = chain
+            .new_transaction("alice".into(), "bob".into(), units(5))
+            .unwrap();
+        store.save(&chain).unwrap();
+
+        let (mut restored, _restored_rx) = testing::chain(LedgerModel::Account, &[("alice", 100)]);
+        store.load(&mut restored).unwrap();
+        assert_eq!(restored.last_hash(), chain.last_hash());
+        assert_eq!(restored.get_balance("bob"), Some(units(10)));
+        assert_eq!(restored.get_balance("carol"), Some(units(0)));
+        assert_eq!(restored.pending_transactions()[0].id(), pending);
+        fs::remove_dir_all(store.dir()).unwrap();
+    }
+
+    #[tokio::test]
+    async fn refuses_blocks_that_do_not_connect() {
+        let store = temp_store("store-foreign");
+        let (mut chain, _p2p_rx) = testing::chain(LedgerModel::Account, &[("alice", 100)]);
+        assert!(chain.generate_new_block());
+        store.save(&chain).unwrap();
+
+        let (mut other, _other_rx) = testing::chain(LedgerModel::Account, &[("bob", 100)]);
+        let error = store.load(&mut other).unwrap_err();
+        assert!(error
+            .to_string()
+            .contains("stored block 1 does not connect"));
+        fs::remove_dir_all(store.dir()).unwrap();
+    }
+
+    #[test]
+    fn locks_the_directory_for_one_user() {
+        let store = temp_store("store-lock");
+        let lock = store.lock().unwrap();
+        assert!(store.lock().is_err());
+        drop(lock);
+        assert!(store.lock().is_ok());
+        fs::remove_dir_all(store.dir()).unwrap();
+    }
+}
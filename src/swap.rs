@@ -0,0 +1,261 @@
+use crate::htlc;
+use crate::rpc::call_remote;
+use serde_json::{json, Value};
+use std::error::Error;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+/// How often the chains are polled while waiting for a step to be mined.
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How long a refund waits on a chain that mines no blocks before giving
+/// up.
+const STALL_TIMEOUT: Duration = Duration::from_secs(300);
+
+/// Blocks past the timeout height a refund may take to be mined.
+const REFUND_BLOCKS: u64 = 20;
+
+/// One side of a swap: the node serving a chain, and who pays what on it.
+#[derive(Debug, Clone)]
+pub struct Leg {
+    pub node: SocketAddr,
+    pub sender: String,
+    pub receiver: String,
+    /// Amount in the chain's tokens, as `send` takes it.
+    pub amount: String,
+    /// Blocks until the lock on this chain can be refunded.
+    pub timeout: u64,
+}
+
+/// Swaps `first.amount` on the first chain for `second.amount` on the
+/// second through two running nodes. The first sender picks the secret
+/// and locks, the second sender locks under the same hash, the first
+/// claims on the second chain and the second reads the secret from there
+/// to claim on the first. The second lock must expire before the first,
+/// so the first sender cannot claim after the second has been refunded.
+///
+/// Both nodes have to be mining. If a step is not mined before its lock
+/// expires, the open locks are refunded once they may be and an error is
+/// returned. Progress goes to stdout, one step per line.
+pub async fn run(first: Leg, second: Leg) -> Result<(), Box<dyn Error>> {
+    if second.timeout >= first.timeout {
+        return Err("the second lock must time out before the first".into());
+    }
+    let (preimage, hash_lock) = htlc::new_secret();
+
+    let (first_id, first_timeout) = lock(&first, &hash_lock).await?;
+    println!(
+        "locked {} on {} until {}",
+        first_id, first.node, first_timeout
+    );
+    if !wait(first.node, &first_id, first_timeout, is_open).await? {
+        return Err("the first lock was not mined in time".into());
+    }
+
+    let (second_id, second_timeout) = match lock(&second, &hash_lock).await {
+        Ok(lock) => lock,
+        Err(e) => {
+            refund(first.node, &first_id, first_timeout, STALL_TIMEOUT).await?;
+            return Err(e);
+        }
+    };
+    println!(
+        "locked {} on {} until {}",
+        second_id, second.node, second_timeout
+    );
+    if !wait(second.node, &second_id, second_timeout, is_open).await? {
+        refund(first.node, &first_id, first_timeout, STALL_TIMEOUT).await?;
+        return Err("the second lock was not mined in time".into());
+    }
+
+    let claim = json!({ "htlc_id": second_id, "preimage": preimage });
+    let claimed = call_remote(second.node, "claim_htlc", claim).await.is_ok()
+        && wait(second.node, &second_id, second_timeout, is_claimed).await?;
+    if !claimed {
+        refund(second.node, &second_id, second_timeout, STALL_TIMEOUT).await?;
+        refund(first.node, &first_id, first_timeout, STALL_TIMEOUT).await?;
+        return Err("the claim on the second chain was not mined in time".into());
+    }
+    println!("claimed {} on {}", second_id, second.node);
+
+    // The way the second sender learns the secret: from the second chain.
+    let revealed = htlc_state(second.node, &second_id).await?["preimage"]
+        .as_str()
+        .ok_or("the claim on the second chain revealed no secret")?
+        .to_string();
+    let claim = json!({ "htlc_id": first_id, "preimage": revealed });
+    let claimed = call_remote(first.node, "claim_htlc", claim).await.is_ok()
+        && wait(first.node, &first_id, first_timeout, is_claimed).await?;
+    if !claimed {
+        refund(first.node, &first_id, first_timeout, STALL_TIMEOUT).await?;
+        return Err("the claim on the first chain was not mined in time".into());
+    }
+    println!("claimed {} on {}", first_id, first.node);
+    Ok(())
+}
+
+/// Locks `leg.amount` under `hash_lock`, returning the HTLC id and the
+/// height it can be refunded from.
+async fn lock(leg: &Leg, hash_lock: &str) -> Result<(String, u64), Box<dyn Error>> {
+    let timeout_height = height(leg.node).await? + leg.timeout;
+    let params = json!({
+        "sender": leg.sender,
+        "receiver": leg.receiver,
+        "amount": leg.amount,
+        "hash_lock": hash_lock,
+        "timeout_height": timeout_height,
+    });
+    let id = call_remote(leg.node, "lock_htlc", params).await?;
+    let id = id.as_str().ok_or("lock_htlc returned no id")?.to_string();
+    Ok((id, timeout_height))
+}
+
+/// Refunds HTLC `htlc_id` once the chain reaches `timeout_height`, and
+/// waits for the refund to be mined. An HTLC claimed in the meantime is
+/// left alone. Gives up if the chain mines nothing for `stall_timeout`, or
+/// the refund is still not mined `REFUND_BLOCKS` after the timeout height.
+async fn refund(
+    node: SocketAddr,
+    htlc_id: &str,
+    timeout_height: u64,
+    stall_timeout: Duration,
+) -> Result<(), Box<dyn Error>> {
+    println!("refunding {} on {} at {}", htlc_id, node, timeout_height);
+    let mut last_height = None;
+    let mut last_progress = Instant::now();
+    loop {
+        let state = htlc_state(node, htlc_id).await?;
+        if is_claimed(&state) {
+            println!("{} on {} was claimed", htlc_id, node);
+            return Ok(());
+        }
+        if state["htlc"].is_null() {
+            println!("refunded {} on {}", htlc_id, node);
+            return Ok(());
+        }
+        let height = height(node).await?;
+        if last_height != Some(height) {
+            last_height = Some(height);
+            last_progress = Instant::now();
+        } else if last_progress.elapsed() >= stall_timeout {
+            return Err(format!(
+                "{} stopped mining at {}; refund {} once it resumes",
+                node, height, htlc_id
+            )
+            .into());
+        }
+        if height >= timeout_height.saturating_add(REFUND_BLOCKS) {
+            return Err(format!(
+                "the refund of {} on {} was not mined by {}",
+                htlc_id, node, height
+            )
+            .into());
+        }
+        // Rejected until the timeout height, and while a refund is pending.
+        if height >= timeout_height {
+            let _ = call_remote(node, "refund_htlc", json!({ "htlc_id": htlc_id })).await;
+        }
+        tokio::time::sleep(POLL_INTERVAL).await;
+    }
+}
+
+/// Polls HTLC `htlc_id` until `done` holds, or the chain reaches
+/// `timeout_height` first.
+async fn wait(
+    node: SocketAddr,
+    htlc_id: &str,
+    timeout_height: u64,
+    done: fn(&Value) -> bool,
+) -> Result<bool, Box<dyn Error>> {
+    loop {
+        if done(&htlc_state(node, htlc_id).await?) {
+            return Ok(true);
+        }
+        if height(node).await? >= timeout_height {
+            return Ok(false);
+        }
+        tokio::time::sleep(POLL_INTERVAL).await;
+    }
+}
+
+fn is_open(state: &Value) -> bool {
+    !state["htlc"].is_null()
+}
+
+fn is_claimed(state: &Value) -> bool {
+    state["preimage"].is_string()
+}
+
+async fn htlc_state(node: SocketAddr, htlc_id: &str) -> Result<Value, Box<dyn Error>> {
+    call_remote(node, "get_htlc", json!({ "htlc_id": htlc_id })).await
+}
+
+async fn height(node: SocketAddr) -> Result<u64, Box<dyn Error>> {
+    let info = call_remote(node, "get_chain_info", Value::Null).await?;
+    info["height"]
+        .as_u64()
+        .ok_or_else(|| format!("{} reported no height", node).into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::node::Node;
+    use crate::rpc;
+    use crate::testing::{self, units};
+
+    /// Serves `node`'s JSON-RPC with alice's 10 locked to bob until
+    /// `timeout_height`, the lock mined. Returns the address and lock id.
+    async fn serve_lock(node: &Node, timeout_height: u64) -> (SocketAddr, String) {
+        let (_, hash_lock) = htlc::new_secret();
+        let id = {
+            let mut chain = node.chain();
+            let id = chain
+                .lock_htlc(
+                    "alice".into(),
+                    "bob".into(),
+                    units(10),
+                    hash_lock,
+                    timeout_height,
+                )
+                .unwrap();
+            assert!(chain.generate_new_block());
+            id
+        };
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let app = rpc::router(node.clone());
+        tokio::spawn(async move { axum::serve(listener, app).await });
+        (addr, id)
+    }
+
+    #[tokio::test]
+    async fn refunds_once_the_lock_times_out() {
+        let (node, _p2p_rx) = testing::node(&[("alice", 100)]);
+        let (addr, id) = serve_lock(&node, 2).await;
+        assert!(node.chain().generate_new_block());
+        let miner = node.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(POLL_INTERVAL * 2).await;
+            assert!(miner.chain().generate_new_block());
+        });
+
+        refund(addr, &id, 2, STALL_TIMEOUT).await.unwrap();
+        assert!(node.chain().get_htlc(&id).is_none());
+        assert_eq!(node.chain().get_balance("alice"), Some(units(100)));
+    }
+
+    #[tokio::test]
+    async fn gives_up_on_a_chain_that_stopped_mining() {
+        let (node, _p2p_rx) = testing::node(&[("alice", 100)]);
+        let (addr, id) = serve_lock(&node, 5).await;
+
+        let error = refund(addr, &id, 5, POLL_INTERVAL * 2).await.unwrap_err();
+        assert!(
+            error.to_string().contains("stopped mining at 1"),
+            "{}",
+            error
+        );
+        assert!(node.chain().get_htlc(&id).is_some());
+    }
+}
@@ -0,0 +1,50 @@
+//! Fixtures shared by the unit tests.
+
+use crate::amount::Amount;
+use crate::blockchain::Chain;
+use crate::node::Node;
+use crate::p2p::{P2pMessage, PeerList};
+use crate::spec::{Allocation, ChainSpec, LedgerModel};
+use tokio::sync::mpsc;
+
+/// A difficulty-1 spec with zero decimals and the given base-unit
+/// allocations.
+pub fn spec(ledger: LedgerModel, allocations: &[(&str, u64)]) -> ChainSpec {
+    let mut spec = ChainSpec::dev(String::from("Test"), String::from("TST"), 1);
+    spec.ledger = ledger;
+    spec.allocations = allocations
+        .iter()
+        .map(|(address, amount)| Allocation {
+            address: address.to_string(),
+            amount: Amount::from_base_units(*amount),
+        })
+        .collect();
+    spec
+}
+
+/// A chain mining to `miner`. Keep the receiver alive: mined blocks are
+/// sent to it from a tokio task, so tests that mine run on a runtime.
+pub fn chain(
+    ledger: LedgerModel,
+    allocations: &[(&str, u64)],
+) -> (Chain, mpsc::Receiver<P2pMessage>) {
+    let (p2p_tx, p2p_rx) = mpsc::channel(64);
+    let chain = Chain::new(spec(ledger, allocations), String::from("miner"), p2p_tx);
+    (chain, p2p_rx)
+}
+
+/// An account-mode node; see `chain`.
+pub fn node(allocations: &[(&str, u64)]) -> (Node, mpsc::Receiver<P2pMessage>) {
+    node_with(spec(LedgerModel::Account, allocations))
+}
+
+/// A node on `spec`, mining to `miner`; see `chain`.
+pub fn node_with(spec: ChainSpec) -> (Node, mpsc::Receiver<P2pMessage>) {
+    let (p2p_tx, p2p_rx) = mpsc::channel(64);
+    let chain = Chain::new(spec, String::from("miner"), p2p_tx);
+    (Node::new(chain, PeerList::default()), p2p_rx)
+}
+
+pub fn units(amount: u64) -> Amount {
+    Amount::from_base_units(amount)
+}
@@ -0,0 +1,116 @@
+use crate::amount::Amount;
+use crate::spec::ChainSpec;
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+/// Longest symbol a token may have.
+pub const MAX_SYMBOL_LEN: usize = 10;
+
+/// A token issued on chain alongside the native one. Its whole supply is
+/// credited to the issuer when it is created.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+pub struct Token {
+    pub symbol: String,
+    pub name: String,
+    pub decimals: u8,
+    pub supply: Amount,
+    pub issuer: String,
+}
+
+impl Token {
+    /// Formats `amount` with the token's decimals and symbol.
+    pub fn format_amount(&self, amount: Amount) -> String {
+        format!("{} {}", amount.format(self.decimals), self.symbol)
+    }
+}
+
+/// What an `IssueToken` transaction declares besides its symbol (the
+/// transaction's `token`) and supply (its `amount`).
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+pub struct TokenInfo {
+    pub name: String,
+    pub decimals: u8,
+}
+
+impl TokenInfo {
+    pub fn is_valid(&self) -> bool {
+        !self.name.is_empty() && self.decimals <= ChainSpec::MAX_DECIMALS
+    }
+}
+
+/// Symbols are short and made of uppercase letters and digits, so they are
+/// easy to tell apart.
+pub fn is_valid_symbol(symbol: &str) -> bool {
+    !symbol.is_empty()
+        && symbol.len() <= MAX_SYMBOL_LEN
+        && symbol
+            .chars()
+            .all(|c| c.is_ascii_uppercase() || c.is_ascii_digit())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blockchain::{Transaction, TxKind};
+    use crate::spec::LedgerModel;
+    use crate::state::State;
+    use crate::testing::units;
+
+    fn issue(issuer: &str, symbol: &str, supply: u64) -> Transaction {
+        Transaction {
+            kind: TxKind::IssueToken,
+            token: Some(symbol.to_string()),
+            issue: Some(TokenInfo {
+                name: String::from("Token"),
+                decimals: 0,
+            }),
+            ..Transaction::transfer(issuer.to_string(), issuer.to_string(), units(supply))
+        }
+    }
+
+    #[test]
+    fn validates_symbols() {
+        assert!(is_valid_symbol("TOK1"));
+        assert!(!is_valid_symbol(""));
+        assert!(!is_valid_symbol("tok"));
+        assert!(!is_valid_symbol("TOKEN/BOB"));
+        assert!(!is_valid_symbol("ABCDEFGHIJK"));
+    }
+
+    #[test]
+    fn token_issues_and_transfers_move_the_state_root() {
+        let mut state = State::new(LedgerModel::Account);
+        state
+            .apply_block(&[Transaction::allocation("alice".into(), units(100), 0)], 0)
+            .unwrap();
+        let genesis = state.state_root();
+
+        state.apply_block(&[issue("alice", "TOK", 10)], 1).unwrap();
+        let issued = state.state_root();
+        assert_ne!(issued, genesis);
+
+        let transfer = Transaction {
+            token: Some(String::from("TOK")),
+            nonce: 1,
+            ..Transaction::transfer("alice".into(), "bob".into(), units(4))
+        };
+        state.apply_block(&[transfer], 2).unwrap();
+        assert_ne!(state.state_root(), issued);
+        assert_eq!(state.token_balance("TOK", "bob"), Some(units(4)));
+        assert_eq!(state.balance("alice"), Some(units(100)));
+    }
+
+    #[test]
+    fn symbols_can_only_be_issued_once() {
+        let mut state = State::new(LedgerModel::Account);
+        state
+            .apply_block(&[Transaction::allocation("alice".into(), units(1), 0)], 0)
+            .unwrap();
+        state.apply_block(&[issue("alice", "TOK", 10)], 1).unwrap();
+        let again = Transaction {
+            nonce: 1,
+            ..issue("alice", "TOK", 10)
+        };
+        assert!(state.apply_block(&[again], 2).is_err());
+    }
+}
@@ -0,0 +1,39 @@
+use crate::amount::Amount;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use utoipa::ToSchema;
+
+/// Points at output `index` of the transaction with id `tx_id`.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize, ToSchema)]
+pub struct OutPoint {
+    pub tx_id: String,
+    pub index: u32,
+}
+
+impl fmt::Display for OutPoint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.tx_id, self.index)
+    }
+}
+
+/// Condition that must be met to spend an output.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+pub enum Lock {
+    /// Spendable by a transaction whose `sender` is this address.
+    Address(String),
+}
+
+impl Lock {
+    /// The address whose balance this output counts towards.
+    pub fn owner(&self) -> &str {
+        match self {
+            Lock::Address(address) => address,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+pub struct TxOutput {
+    pub amount: Amount,
+    pub lock: Lock,
+}
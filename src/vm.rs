@@ -0,0 +1,502 @@
+use crate::amount::Amount;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::fmt;
+use utoipa::ToSchema;
+
+/// Addresses of deployed contracts start with this, followed by a hash of
+/// the deploying transaction.
+pub const CONTRACT_PREFIX: &str = "contract:";
+
+/// Most instructions a contract may consist of.
+pub const MAX_CODE_LEN: usize = 1024;
+
+/// Most gas a single call may use.
+pub const MAX_GAS: u64 = 100_000;
+
+/// Base units the caller pays, and that are burned, per unit of gas a call
+/// uses, whether it succeeds or not.
+pub const GAS_PRICE: u64 = 1;
+
+/// Gas a deployment uses per instruction deployed, paid like a call's gas.
+/// Code stays in the state for good, so storing an instruction costs more
+/// than running it.
+pub const DEPLOY_GAS_PER_OP: u64 = 10;
+
+/// Deepest the stack may grow.
+pub const MAX_STACK: usize = 1024;
+
+/// An instruction of the contract VM. Every value is a `u64` word; storage
+/// maps words to words and addresses are reduced to words by `address_word`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+pub enum Op {
+    Push(u64),
+    Pop,
+    /// Pushes a copy of the word `n` places below the top (0 is the top).
+    Dup(u8),
+    Swap,
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Mod,
+    Eq,
+    Lt,
+    Gt,
+    /// Pushes 1 if the top word is 0, else 0.
+    Not,
+    And,
+    Or,
+    Jump(u32),
+    /// Pops a condition and jumps if it is not 0.
+    JumpIf(u32),
+    /// Pops a key and pushes the stored word, 0 if unset.
+    Load,
+    /// Pops a key, then a value, and stores it. Storing 0 clears the key.
+    Store,
+    /// Pushes `address_word` of the calling account.
+    Caller,
+    /// Pushes the value sent with the call, in base units.
+    Value,
+    /// Pushes call argument `n`.
+    Arg(u8),
+    /// Pushes the contract's balance, including the value sent with the call.
+    Balance,
+    /// Pops an amount and pays it from the contract to the caller.
+    Pay,
+    /// Pops `n` words and emits them as a log entry.
+    Log(u8),
+    /// Ends the call successfully.
+    Stop,
+    /// Aborts the call; nothing it did takes effect.
+    Revert,
+}
+
+impl Op {
+    pub fn gas(&self) -> u64 {
+        match self {
+            Op::Load => 5,
+            Op::Store | Op::Pay => 20,
+            Op::Log(n) => 10 + *n as u64,
+            _ => 1,
+        }
+    }
+}
+
+/// A deployed contract: its code and everything it has stored.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Contract {
+    pub code: Vec<Op>,
+    pub storage: BTreeMap<u64, u64>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+pub enum ContractAction {
+    /// Deploys `code` as a new contract whose address is derived from the
+    /// transaction id.
+    Deploy { code: Vec<Op> },
+    /// Runs the contract at the transaction's receiver, sending it the
+    /// transaction's amount.
+    Call { args: Vec<u64>, gas_limit: u64 },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Log {
+    pub contract: String,
+    pub data: Vec<u64>,
+}
+
+/// What a contract transaction did. Deployments use `deploy_gas` and log
+/// nothing.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ContractReceipt {
+    pub tx_id: String,
+    pub contract: String,
+    pub gas_used: u64,
+    pub logs: Vec<Log>,
+    /// Why the call failed, if it did. A failed call changes nothing but
+    /// the caller's nonce and the gas it paid for.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// Everything a call can see besides the contract's own storage.
+pub struct CallContext<'a> {
+    pub contract: &'a str,
+    pub caller: &'a str,
+    pub value: Amount,
+    pub balance: Amount,
+    pub args: &'a [u64],
+    pub gas_limit: u64,
+}
+
+/// Result of a successful call.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Outcome {
+    pub gas_used: u64,
+    pub logs: Vec<Log>,
+    /// Total paid out to the caller.
+    pub paid: Amount,
+}
+
+/// Result of a failed call: why, and the gas used until then.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Failure {
+    pub error: VmError,
+    pub gas_used: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum VmError {
+    OutOfGas,
+    StackUnderflow,
+    StackOverflow,
+    InvalidJump(u32),
+    MissingArgument(u8),
+    Overflow,
+    DivisionByZero,
+    InsufficientBalance,
+    Reverted,
+}
+
+impl fmt::Display for VmError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            VmError::OutOfGas => write!(f, "out of gas"),
+            VmError::StackUnderflow => write!(f, "stack underflow"),
+            VmError::StackOverflow => write!(f, "stack overflow"),
+            VmError::InvalidJump(target) => write!(f, "jump to invalid target {}", target),
+            VmError::MissingArgument(n) => write!(f, "missing call argument {}", n),
+            VmError::Overflow => write!(f, "arithmetic overflow"),
+            VmError::DivisionByZero => write!(f, "division by zero"),
+            VmError::InsufficientBalance => write!(f, "contract balance too low to pay"),
+            VmError::Reverted => write!(f, "reverted"),
+        }
+    }
+}
+
+impl std::error::Error for VmError {}
+
+/// Reduces an address to a word, so contracts can compare and store it.
+pub fn address_word(address: &str) -> u64 {
+    let hash = Sha256::digest(address.as_bytes());
+    u64::from_be_bytes(hash[..8].try_into().expect("Hash is 32 bytes"))
+}
+
+/// Gas deploying `code` uses.
+pub fn deploy_gas(code: &[Op]) -> u64 {
+    (code.len() as u64).saturating_mul(DEPLOY_GAS_PER_OP)
+}
+
+pub fn contract_address(deploy_tx_id: &str) -> String {
+    format!("{}{}", CONTRACT_PREFIX, &deploy_tx_id[..40])
+}
+
+pub fn is_contract_address(address: &str) -> bool {
+    address.starts_with(CONTRACT_PREFIX)
+}
+
+/// Whether `code` can be deployed: not empty, not too long, and every jump
+/// lands inside it.
+pub fn validate(code: &[Op]) -> bool {
+    !code.is_empty()
+        && code.len() <= MAX_CODE_LEN
+        && code.iter().all(|op| match op {
+            Op::Jump(target) | Op::JumpIf(target) => (*target as usize) < code.len(),
+            _ => true,
+        })
+}
+
+struct Stack(Vec<u64>);
+
+impl Stack {
+    fn push(&mut self, word: u64) -> Result<(), VmError> {
+        if self.0.len() >= MAX_STACK {
+            return Err(VmError::StackOverflow);
+        }
+        self.0.push(word);
+        Ok(())
+    }
+
+    fn pop(&mut self) -> Result<u64, VmError> {
+        self.0.pop().ok_or(VmError::StackUnderflow)
+    }
+
+    fn binary(&mut self, f: impl Fn(u64, u64) -> Result<u64, VmError>) -> Result<(), VmError> {
+        let b = self.pop()?;
+        let a = self.pop()?;
+        self.push(f(a, b)?)
+    }
+}
+
+/// Runs `code` against `storage`. Storage is only written back if the call
+/// succeeds; on error nothing has changed. A call that runs out of gas has
+/// used all of its limit.
+pub fn execute(
+    code: &[Op],
+    storage: &mut BTreeMap<u64, u64>,
+    ctx: &CallContext,
+) -> Result<Outcome, Failure> {
+    let mut gas_used = 0;
+    run(code, storage, ctx, &mut gas_used).map_err(|error| Failure {
+        error,
+        gas_used: gas_used.min(ctx.gas_limit),
+    })
+}
+
+fn run(
+    code: &[Op],
+    storage: &mut BTreeMap<u64, u64>,
+    ctx: &CallContext,
+    gas_used: &mut u64,
+) -> Result<Outcome, VmError> {
+    let mut scratch = storage.clone();
+    let mut stack = Stack(Vec::new());
+    let mut logs = Vec::new();
+    let mut paid = Amount::ZERO;
+    let mut pc = 0;
+
+    while let Some(op) = code.get(pc) {
+        *gas_used += op.gas();
+        if *gas_used > ctx.gas_limit {
+            return Err(VmError::OutOfGas);
+        }
+        pc += 1;
+        match *op {
+            Op::Push(word) => stack.push(word)?,
+            Op::Pop => {
+                stack.pop()?;
+            }
+            Op::Dup(n) => {
+                let index = stack
+                    .0
+                    .len()
+                    .checked_sub(n as usize + 1)
+                    .ok_or(VmError::StackUnderflow)?;
+                stack.push(stack.0[index])?;
+            }
+            Op::Swap => {
+                let b = stack.pop()?;
+                let a = stack.pop()?;
+                stack.push(b)?;
+                stack.push(a)?;
+            }
+            Op::Add => stack.binary(|a, b| a.checked_add(b).ok_or(VmError::Overflow))?,
+            Op::Sub => stack.binary(|a, b| a.checked_sub(b).ok_or(VmError::Overflow))?,
+            Op::Mul => stack.binary(|a, b| a.checked_mul(b).ok_or(VmError::Overflow))?,
+            Op::Div => stack.binary(|a, b| a.checked_div(b).ok_or(VmError::DivisionByZero))?,
+            Op::Mod => stack.binary(|a, b| a.checked_rem(b).ok_or(VmError::DivisionByZero))?,
+            Op::Eq => stack.binary(|a, b| Ok((a == b) as u64))?,
+            Op::Lt => stack.binary(|a, b| Ok((a < b) as u64))?,
+            Op::Gt => stack.binary(|a, b| Ok((a > b) as u64))?,
+            Op::And => stack.binary(|a, b| Ok((a != 0 && b != 0) as u64))?,
+            Op::Or => stack.binary(|a, b| Ok((a != 0 || b != 0) as u64))?,
+            Op::Not => {
+                let a = stack.pop()?;
+                stack.push((a == 0) as u64)?;
+            }
+            Op::Jump(target) => pc = jump(code, target)?,
+            Op::JumpIf(target) => {
+                if stack.pop()? != 0 {
+                    pc = jump(code, target)?;
+                }
+            }
+            Op::Load => {
+                let key = stack.pop()?;
+                stack.push(scratch.get(&key).copied().unwrap_or(0))?;
+            }
+            Op::Store => {
+                let key = stack.pop()?;
+                let value = stack.pop()?;
+                if value == 0 {
+                    scratch.remove(&key);
+                } else {
+                    scratch.insert(key, value);
+                }
+            }
+            Op::Caller => stack.push(address_word(ctx.caller))?,
+            Op::Value => stack.push(ctx.value.base_units())?,
+            Op::Arg(n) => {
+                let arg = ctx
+                    .args
+                    .get(n as usize)
+                    .ok_or(VmError::MissingArgument(n))?;
+                stack.push(*arg)?;
+            }
+            Op::Balance => {
+                let left = ctx.balance.checked_sub(paid).ok_or(VmError::Overflow)?;
+                stack.push(left.base_units())?;
+            }
+            Op::Pay => {
+                let amount = Amount::from_base_units(stack.pop()?);
+                paid = paid.checked_add(amount).ok_or(VmError::Overflow)?;
+                if paid > ctx.balance {
+                    return Err(VmError::InsufficientBalance);
+                }
+            }
+            Op::Log(n) => {
+                let mut data = Vec::with_capacity(n as usize);
+                for _ in 0..n {
+                    data.push(stack.pop()?);
+                }
+                data.reverse();
+                logs.push(Log {
+                    contract: ctx.contract.to_string(),
+                    data,
+                });
+            }
+            Op::Stop => break,
+            Op::Revert => return Err(VmError::Reverted),
+        }
+    }
+
+    *storage = scratch;
+    Ok(Outcome {
+        gas_used: *gas_used,
+        logs,
+        paid,
+    })
+}
+
+fn jump(code: &[Op], target: u32) -> Result<usize, VmError> {
+    if target as usize >= code.len() {
+        return Err(VmError::InvalidJump(target));
+    }
+    Ok(target as usize)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::spec::LedgerModel;
+    use crate::testing::{self, units};
+
+    fn call(
+        code: &[Op],
+        storage: &mut BTreeMap<u64, u64>,
+        balance: u64,
+    ) -> Result<Outcome, Failure> {
+        let ctx = CallContext {
+            contract: "contract:test",
+            caller: "alice",
+            value: units(0),
+            balance: units(balance),
+            args: &[7, 9],
+            gas_limit: 100,
+        };
+        execute(code, storage, &ctx)
+    }
+
+    #[test]
+    fn runs_arithmetic_and_storage() {
+        let mut storage = BTreeMap::new();
+        let code = [
+            Op::Arg(0),
+            Op::Arg(1),
+            Op::Mul,
+            Op::Push(1),
+            Op::Store,
+            Op::Stop,
+        ];
+        let outcome = call(&code, &mut storage, 0).unwrap();
+        assert_eq!(storage.get(&1), Some(&63));
+        assert_eq!(outcome.gas_used, 5 + Op::Store.gas());
+    }
+
+    #[test]
+    fn failed_calls_leave_storage_alone_and_report_gas() {
+        let mut storage = BTreeMap::from([(1, 1)]);
+        let code = [Op::Push(2), Op::Push(1), Op::Store, Op::Revert];
+        let failure = call(&code, &mut storage, 0).unwrap_err();
+        assert_eq!(failure.error, VmError::Reverted);
+        assert_eq!(failure.gas_used, 2 + Op::Store.gas() + 1);
+        assert_eq!(storage.get(&1), Some(&1));
+
+        let failure = call(&[Op::Jump(0)], &mut storage, 0).unwrap_err();
+        assert_eq!(failure.error, VmError::OutOfGas);
+        assert_eq!(failure.gas_used, 100);
+    }
+
+    #[test]
+    fn pays_at_most_the_balance() {
+        let mut storage = BTreeMap::new();
+        let outcome = call(
+            &[Op::Push(30), Op::Pay, Op::Push(20), Op::Pay],
+            &mut storage,
+            50,
+        )
+        .unwrap();
+        assert_eq!(outcome.paid, units(50));
+        let failure = call(&[Op::Push(51), Op::Pay], &mut storage, 50).unwrap_err();
+        assert_eq!(failure.error, VmError::InsufficientBalance);
+    }
+
+    #[test]
+    fn validates_jumps() {
+        assert!(validate(&[Op::Jump(1), Op::Stop]));
+        assert!(!validate(&[Op::Jump(2), Op::Stop]));
+        assert!(!validate(&[]));
+    }
+
+    #[tokio::test]
+    async fn contracts_cannot_send_transactions() {
+        let (mut chain, _p2p_rx) = testing::chain(LedgerModel::Account, &[("alice", 100)]);
+        let escrow = chain
+            .deploy_contract("alice".into(), vec![Op::Stop])
+            .unwrap();
+        assert!(chain.generate_new_block());
+        chain
+            .call_contract("alice".into(), escrow.clone(), units(50), Vec::new(), 100)
+            .unwrap();
+        assert!(chain.generate_new_block());
+        assert_eq!(chain.get_balance(&escrow), Some(units(50)));
+
+        assert!(chain
+            .new_transaction(escrow.clone(), "mallory".into(), units(50))
+            .is_none());
+        assert!(!chain.new_token_transaction(
+            escrow.clone(),
+            "mallory".into(),
+            "TOK".into(),
+            units(1)
+        ));
+        assert!(!chain.issue_token(escrow.clone(), "TOK".into(), "Token".into(), 0, units(10)));
+        assert!(chain.generate_new_block());
+        assert_eq!(chain.get_balance(&escrow), Some(units(50)));
+        assert_eq!(chain.get_balance("mallory"), None);
+    }
+
+    #[tokio::test]
+    async fn deployments_pay_gas_per_instruction() {
+        let (mut chain, _p2p_rx) = testing::chain(LedgerModel::Account, &[("alice", 25)]);
+        let code = vec![Op::Push(1), Op::Pop, Op::Stop];
+        assert_eq!(deploy_gas(&code), 30);
+        assert!(chain.deploy_contract("alice".into(), code).is_none());
+
+        let code = vec![Op::Push(1), Op::Stop];
+        let contract = chain.deploy_contract("alice".into(), code).unwrap();
+        assert!(chain.generate_new_block());
+        assert!(chain.get_contract(&contract).is_some());
+        assert_eq!(chain.get_balance("alice"), Some(units(5)));
+    }
+
+    #[tokio::test]
+    async fn failed_calls_are_mined_and_pay_for_gas() {
+        let (mut chain, _p2p_rx) = testing::chain(LedgerModel::Account, &[("alice", 100)]);
+        let code = vec![Op::Push(1), Op::Pop, Op::Revert];
+        let deployed = deploy_gas(&code);
+        let contract = chain.deploy_contract("alice".into(), code).unwrap();
+        assert!(chain.generate_new_block());
+        let id = chain
+            .call_contract("alice".into(), contract.clone(), units(40), Vec::new(), 100)
+            .unwrap();
+        assert!(chain.generate_new_block());
+
+        let receipt = chain.contract_receipt(&id).unwrap();
+        assert_eq!(receipt.error.as_deref(), Some("reverted"));
+        assert_eq!(receipt.gas_used, 3);
+        assert_eq!(chain.get_balance("alice"), Some(units(100 - deployed - 3)));
+        assert_eq!(chain.get_balance(&contract).unwrap_or_default(), units(0));
+    }
+}
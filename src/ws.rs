@@ -0,0 +1,265 @@
+use crate::events::ChainEvent;
+use crate::node::Node;
+use crate::rpc::{
+    RpcError, RpcResponse, INVALID_PARAMS, INVALID_REQUEST, METHOD_NOT_FOUND, NOT_FOUND,
+    PARSE_ERROR,
+};
+use axum::extract::ws::{Message, WebSocket, WebSocketUpgrade};
+use axum::extract::State;
+use axum::response::Response;
+use axum::routing::get;
+use axum::Router;
+use log::warn;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use tokio::sync::broadcast::error::RecvError;
+
+/// What a subscription receives.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "topic", rename_all = "snake_case")]
+pub enum Topic {
+    NewHeads,
+    PendingTransactions,
+    Reorgs,
+    AddressActivity { address: String },
+}
+
+impl Topic {
+    fn matches(&self, event: &ChainEvent) -> bool {
+        match (self, event) {
+            (Topic::NewHeads, ChainEvent::NewHead { .. }) => true,
+            (Topic::PendingTransactions, ChainEvent::PendingTransaction { .. }) => true,
+            (Topic::Reorgs, ChainEvent::Reorg { .. }) => true,
+            (
+                Topic::AddressActivity { address },
+                ChainEvent::AddressActivity { address: a, .. },
+            ) => address == a,
+            _ => false,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct WsRequest {
+    jsonrpc: String,
+    method: String,
+    #[serde(default)]
+    params: Value,
+}
+
+#[derive(Deserialize)]
+struct UnsubscribeParams {
+    subscription: u64,
+}
+
+/// JSON-RPC 2.0 subscriptions over a WebSocket at `/ws`. `subscribe` takes
+/// a topic (e.g. `{"topic": "address_activity", "address": "alice"}`) and
+/// answers with a subscription id; events then arrive as `subscription`
+/// notifications carrying that id. `unsubscribe` takes the id.
+pub fn router(node: Node) -> Router {
+    Router::new().route("/ws", get(upgrade)).with_state(node)
+}
+
+async fn upgrade(State(node): State<Node>, ws: WebSocketUpgrade) -> Response {
+    ws.on_upgrade(move |socket| session(socket, node))
+}
+
+async fn session(mut socket: WebSocket, node: Node) {
+    let mut events = node.subscribe();
+    let mut subscriptions: HashMap<u64, Topic> = HashMap::new();
+    let mut next_id = 1;
+    loop {
+        tokio::select! {
+            message = socket.recv() => {
+                let text = match message {
+                    Some(Ok(Message::Text(text))) => text,
+                    Some(Ok(Message::Close(_))) | None | Some(Err(_)) => return,
+                    Some(Ok(_)) => continue,
+                };
+                let response = handle(&text, &mut subscriptions, &mut next_id);
+                if !send(&mut socket, &response).await {
+                    return;
+                }
+            }
+            event = events.recv() => {
+                let event = match event {
+                    Ok(event) => event,
+                    Err(RecvError::Lagged(missed)) => {
+                        warn!("WebSocket subscriber missed {} events", missed);
+                        continue;
+                    }
+                    Err(RecvError::Closed) => return,
+                };
+                for (id, topic) in &subscriptions {
+                    if !topic.matches(&event) {
+                        continue;
+                    }
+                    let notification = json!({
+                        "jsonrpc": "2.0",
+                        "method": "subscription",
+                        "params": { "subscription": id, "result": event },
+                    });
+                    if !send(&mut socket, &notification).await {
+                        return;
+                    }
+                }
+            }
+        }
+    }
+}
+
+async fn send<T: Serialize>(socket: &mut WebSocket, message: &T) -> bool {
+    let text = serde_json::to_string(message).expect("Message serializes");
+    socket.send(Message::Text(text.into())).await.is_ok()
+}
+
+fn handle(text: &str, subscriptions: &mut HashMap<u64, Topic>, next_id: &mut u64) -> RpcResponse {
+    let request: Value = match serde_json::from_str(text) {
+        Ok(request) => request,
+        Err(e) => {
+            return RpcResponse::new(Value::Null, Err(RpcError::new(PARSE_ERROR, e.to_string())))
+        }
+    };
+    let id = request.get("id").cloned().unwrap_or(Value::Null);
+    let request = match serde_json::from_value::<WsRequest>(request) {
+        Ok(request) if request.jsonrpc == "2.0" => request,
+        _ => {
+            let error = RpcError::new(INVALID_REQUEST, "invalid JSON-RPC 2.0 request");
+            return RpcResponse::new(id, Err(error));
+        }
+    };
+    let outcome = match request.method.as_str() {
+        "subscribe" => serde_json::from_value::<Topic>(request.params)
+            .map_err(|e| RpcError::new(INVALID_PARAMS, e.to_string()))
+            .map(|topic| {
+                let subscription = *next_id;
+                *next_id += 1;
+                subscriptions.insert(subscription, topic);
+                json!(subscription)
+            }),
+        "unsubscribe" => serde_json::from_value::<UnsubscribeParams>(request.params)
+            .map_err(|e| RpcError::new(INVALID_PARAMS, e.to_string()))
+            .and_then(|UnsubscribeParams { subscription }| {
+                subscriptions
+                    .remove(&subscription)
+                    .map(|_| json!(true))
+                    .ok_or_else(|| {
+                        RpcError::new(NOT_FOUND, format!("unknown subscription {}", subscription))
+                    })
+            }),
+        method => Err(RpcError::new(
+            METHOD_NOT_FOUND,
+            format!("unknown method {}", method),
+        )),
+    };
+    RpcResponse::new(id, outcome)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::spec::LedgerModel;
+    use crate::testing;
+    use tokio::sync::broadcast;
+
+    fn drain(events: &mut broadcast::Receiver<ChainEvent>, topic: &Topic) -> Vec<ChainEvent> {
+        let mut matched = Vec::new();
+        while let Ok(event) = events.try_recv() {
+            if topic.matches(&event) {
+                matched.push(event);
+            }
+        }
+        matched
+    }
+
+    #[test]
+    fn subscribes_and_unsubscribes() {
+        let mut subscriptions = HashMap::new();
+        let mut next_id = 1;
+        let mut call = |text: &str| {
+            let response = handle(text, &mut subscriptions, &mut next_id);
+            (response.result, response.error.map(|e| e.code))
+        };
+        let subscribe = r#"{"jsonrpc":"2.0","id":1,"method":"subscribe","params":{"topic":"address_activity","address":"alice"}}"#;
+        assert_eq!(call(subscribe), (Some(json!(1)), None));
+        assert_eq!(call(subscribe), (Some(json!(2)), None));
+        let unsubscribe =
+            r#"{"jsonrpc":"2.0","id":2,"method":"unsubscribe","params":{"subscription":1}}"#;
+        assert_eq!(call(unsubscribe), (Some(json!(true)), None));
+        assert_eq!(call(unsubscribe), (None, Some(NOT_FOUND)));
+
+        let unknown_topic =
+            r#"{"jsonrpc":"2.0","id":3,"method":"subscribe","params":{"topic":"gossip"}}"#;
+        assert_eq!(call(unknown_topic), (None, Some(INVALID_PARAMS)));
+        let unknown_method = r#"{"jsonrpc":"2.0","id":4,"method":"eth_subscribe"}"#;
+        assert_eq!(call(unknown_method), (None, Some(METHOD_NOT_FOUND)));
+        assert_eq!(
+            call(r#"{"id":5,"method":"subscribe"}"#),
+            (None, Some(INVALID_REQUEST))
+        );
+        assert_eq!(call("{"), (None, Some(PARSE_ERROR)));
+        assert_eq!(
+            subscriptions.into_values().collect::<Vec<_>>(),
+            [Topic::AddressActivity {
+                address: String::from("alice")
+            }]
+        );
+    }
+
+    #[tokio::test]
+    async fn topics_pick_their_events() {
+        let (node, _p2p_rx) = testing::node(&[("alice", 100)]);
+        let mut events = node.subscribe();
+        let id = node
+            .chain()
+            .new_transaction("alice".into(), "bob".into(), testing::units(5))
+            .unwrap();
+        let bob = Topic::AddressActivity {
+            address: String::from("bob"),
+        };
+        let pending = drain(&mut events, &bob);
+        assert!(matches!(
+            &pending[..],
+            [ChainEvent::AddressActivity { tx_id, block_height: None, .. }] if *tx_id == id
+        ));
+
+        assert!(node.chain().generate_new_block());
+        let mut heads = node.subscribe();
+        assert!(node.chain().generate_new_block());
+        assert_eq!(drain(&mut events, &bob).len(), 1);
+        assert!(matches!(
+            &drain(&mut heads, &Topic::NewHeads)[..],
+            [ChainEvent::NewHead { height: 2, .. }]
+        ));
+    }
+
+    #[tokio::test]
+    async fn reorgs_name_the_blocks_swapped() {
+        let (node, _p2p_rx) = testing::node(&[("alice", 100)]);
+        let (mut longer, _longer_rx) = testing::chain(LedgerModel::Account, &[("alice", 100)]);
+        assert!(node.chain().generate_new_block());
+        let replaced = node.chain().last_hash();
+        assert!(longer.generate_new_block());
+        assert!(longer.generate_new_block());
+
+        let mut events = node.subscribe();
+        assert!(node.chain().resolve_conflict(longer.get_chain()));
+        let connected: Vec<_> = longer.get_chain()[1..]
+            .iter()
+            .map(|block| crate::blockchain::Chain::hash(block.header()).unwrap())
+            .collect();
+        match &drain(&mut events, &Topic::Reorgs)[..] {
+            [ChainEvent::Reorg {
+                fork_height,
+                disconnected,
+                connected: swapped_in,
+            }] => {
+                assert_eq!(*fork_height, 0);
+                assert_eq!(disconnected, &[replaced]);
+                assert_eq!(swapped_in, &connected);
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+}